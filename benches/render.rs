@@ -0,0 +1,59 @@
+//! Throughput of rendering the same template many times
+//!
+//! ```sh
+//! cargo bench --bench render
+//! ```
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libmll::{CompiledTemplate, Mll};
+
+const TEMPLATE: &str = r#"{"id": {{id}}, "name": "{{ name | upper }}", "tags": [{{#tags}}"{{.}}",{{/tags}}], "even": {{#if id % 2 == 0}}true{{else}}false{{/if}}}"#;
+
+const ITERATIONS: usize = 10_000;
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{:<40} {:>10.2?} {:>12.0} renders/s",
+        name,
+        elapsed,
+        ITERATIONS as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let contexts = (0..ITERATIONS)
+        .map(|i| {
+            let mut table = HashMap::new();
+            table.insert("id", i.to_string());
+            table.insert("name", format!("name{}", i));
+            table
+        })
+        .collect::<Vec<_>>();
+
+    // parse and create a Lua state on every render
+    let start = Instant::now();
+    for context in &contexts {
+        let mut mll = Mll::new();
+        mll.set_template(TEMPLATE.to_string());
+        let _ = mll.render(context);
+    }
+    report("Mll::render (new Mll per render)", start.elapsed());
+
+    // reuse the parsed template and the Lua state
+    let mut mll = Mll::new();
+    mll.set_template(TEMPLATE.to_string());
+    let start = Instant::now();
+    for context in &contexts {
+        let _ = mll.render(context);
+    }
+    report("Mll::render (same Mll)", start.elapsed());
+
+    let compiled = CompiledTemplate::compile(TEMPLATE).unwrap();
+    let start = Instant::now();
+    for context in &contexts {
+        let _ = compiled.render(context);
+    }
+    report("CompiledTemplate::render", start.elapsed());
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::panic;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+use mlua::Table;
+use serde_json::Value as JsonValue;
+
+use crate::limits::{LimitGuard, call_limited};
+use crate::utils::do_blocking;
+use crate::{
+    BuiltinRegistry, Capabilities, Context, Deterministic, Limits, MissingVariablePolicy, Mll,
+    MllError, ValueFormat,
+};
+
+/// Context of an item of a batch
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use serde_json::json;
+/// use libmll::BatchContext;
+///
+/// let json = BatchContext::from(json!({"name": "hoge"}));
+/// let map = BatchContext::from(HashMap::from([("name".to_string(), "fuga".to_string())]));
+/// let script = BatchContext::Script("name = 'piyo'".to_string());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchContext {
+    /// JSON value, whose fields are the variables
+    Json(JsonValue),
+    /// Variables and their values
+    Map(HashMap<String, String>),
+    /// Lua script, whose globals are the variables
+    ///
+    /// The globals set by the script are visible only to the render of the item. The globals of
+    /// the session (e.g. set by the pre-process script) are shared with the other items of the
+    /// worker, so changes to their tables (e.g. `table.insert(items, 1)`) are visible to the
+    /// items rendered after it.
+    Script(String),
+}
+
+impl From<JsonValue> for BatchContext {
+    fn from(value: JsonValue) -> Self {
+        BatchContext::Json(value)
+    }
+}
+
+impl From<Context> for BatchContext {
+    fn from(context: Context) -> Self {
+        BatchContext::Json(context.into())
+    }
+}
+
+impl From<HashMap<String, String>> for BatchContext {
+    fn from(value: HashMap<String, String>) -> Self {
+        BatchContext::Map(value)
+    }
+}
+
+/// Settings of `Mll` copied into each worker
+#[derive(Clone)]
+struct Settings {
+    template: String,
+    template_name: String,
+    pre_process_script: String,
+    pre_process_script_name: String,
+    post_process_script: String,
+    post_process_script_name: String,
+    missing_variable_policy: MissingVariablePolicy,
+    value_format: ValueFormat,
+    capabilities: Capabilities,
+    limits: Limits,
+    builtins: BuiltinRegistry,
+    deterministic: Option<Deterministic>,
+}
+
+impl Settings {
+    fn of(mll: &Mll) -> Self {
+        Self {
+            template: mll.template.clone(),
+            template_name: mll.template_name.clone(),
+            pre_process_script: mll.pre_process_script.clone(),
+            pre_process_script_name: mll.pre_process_script_name.clone(),
+            post_process_script: mll.post_process_script.clone(),
+            post_process_script_name: mll.post_process_script_name.clone(),
+            missing_variable_policy: mll.missing_variable_policy.clone(),
+            value_format: mll.value_format,
+            capabilities: mll.capabilities.clone(),
+            limits: mll.limits,
+            builtins: mll.builtins.clone(),
+            deterministic: mll.deterministic,
+        }
+    }
+
+    fn into_mll(self) -> Mll {
+        let mut mll = Mll::new();
+        mll.set_template(self.template);
+        mll.set_template_name(self.template_name);
+        mll.set_pre_process_script(self.pre_process_script);
+        mll.set_pre_process_script_name(self.pre_process_script_name);
+        mll.set_post_process_script(self.post_process_script);
+        mll.set_post_process_script_name(self.post_process_script_name);
+        mll.set_missing_variable_policy(self.missing_variable_policy);
+        mll.set_value_format(self.value_format);
+        mll.set_capabilities(self.capabilities);
+        mll.set_limits(self.limits);
+        mll.set_builtins(self.builtins);
+        mll.set_deterministic(self.deterministic);
+
+        mll
+    }
+}
+
+/// Get number of workers used by default, which is the available parallelism
+pub(crate) fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Render the template of `mll` with each context on `workers` threads
+///
+/// Each worker has its own Lua state, where the pre-process script is run once before rendering
+/// the items. If the script fails, the item fails with its error, and the script is run again
+/// in a new state for the next item.
+pub(crate) fn render_batch<I>(
+    mll: &Mll,
+    contexts: I,
+    workers: usize,
+) -> Vec<Result<String, MllError>>
+where
+    I: IntoIterator,
+    I::IntoIter: Send,
+    I::Item: Into<BatchContext>,
+{
+    let settings = Settings::of(mll);
+    let contexts = Mutex::new(contexts.into_iter().enumerate());
+
+    let mut results = thread::scope(|scope| {
+        let handles = (0..workers.max(1))
+            .map(|_| {
+                let settings = settings.clone();
+                let contexts = &contexts;
+                scope.spawn(move || work(settings, contexts))
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Render items taken from `contexts` until it is exhausted
+fn work<I, C>(settings: Settings, contexts: &Mutex<I>) -> Vec<(usize, Result<String, MllError>)>
+where
+    I: Iterator<Item = (usize, C)>,
+    C: Into<BatchContext>,
+{
+    let mut mll = settings.into_mll();
+    let mut ready = false;
+
+    let mut results = Vec::new();
+    loop {
+        let next = contexts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .next();
+        let Some((index, context)) = next else {
+            break;
+        };
+
+        if !ready {
+            let _guard = LimitGuard::new(mll.engine().lua(), mll.limits());
+            match do_blocking(mll.run_pre_process_async()).and_then(|result| result) {
+                Ok(()) => ready = true,
+                Err(e) => {
+                    results.push((index, Err(e)));
+                    mll.reset();
+                    continue;
+                }
+            }
+        }
+
+        let result =
+            do_blocking(render_item(&mut mll, index, context.into())).and_then(|result| result);
+        results.push((index, result));
+    }
+
+    results
+}
+
+/// Render an item in the session of the worker
+async fn render_item(
+    mll: &mut Mll,
+    index: usize,
+    context: BatchContext,
+) -> Result<String, MllError> {
+    // the tags of the items rendered before are not kept by the session
+    mll.tags.clear();
+    mll.processed_tags.clear();
+
+    // in the deterministic mode, items do not depend on the items rendered before them, nor on
+    // the worker which renders them
+    if let Some(deterministic) = mll.deterministic() {
+        deterministic.for_item(index).reseed(mll.engine().lua())?;
+    }
+
+    match context {
+        BatchContext::Json(json) => mll.render_async(&json).await,
+        BatchContext::Map(map) => mll.render_async(&map).await,
+        BatchContext::Script(script) => {
+            let environment = run_isolated(mll, &script).await?;
+            mll.render_async(&environment).await
+        }
+    }
+}
+
+/// Run Lua script with its own globals, which fall back to the globals of the session
+async fn run_isolated(mll: &Mll, script: &str) -> Result<Table, MllError> {
+    let lua = mll.engine().lua().clone();
+
+    let metatable = lua.create_table()?;
+    metatable.set("__index", lua.globals())?;
+    let environment = lua.create_table()?;
+    environment.set_metatable(Some(metatable));
+
+    let _guard = LimitGuard::new(&lua, mll.limits());
+    let function = lua
+        .load(script)
+        .set_environment(environment.clone())
+        .into_function()?;
+    call_limited::<()>(&lua, function, ()).await?;
+
+    Ok(environment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_render_batch() {
+        let mut mll = Mll::new();
+        mll.set_template("{{greeting}}, {{name}}!".to_string());
+        mll.set_pre_process_script("greeting = 'Hello'".to_string());
+
+        let contexts = (0..100).map(|i| match i % 3 {
+            0 => BatchContext::from(json!({"name": format!("json{}", i)})),
+            1 => BatchContext::from(HashMap::from([("name".to_string(), format!("map{}", i))])),
+            _ => BatchContext::Script(format!("greeting = 'Hi'; name = 'lua{}'", i)),
+        });
+        let results = render_batch(&mll, contexts, 4);
+
+        assert_eq!(100, results.len());
+        assert_eq!("Hello, json0!", results[0].as_ref().unwrap());
+        assert_eq!("Hello, map1!", results[1].as_ref().unwrap());
+        assert_eq!("Hi, lua2!", results[2].as_ref().unwrap());
+        // globals of a script are not visible to the other items
+        assert_eq!("Hello, json99!", results[99].as_ref().unwrap());
+
+        let contexts = vec![json!({"name": "hoge"}), json!({}), json!({"name": "fuga"})];
+        let results = render_batch(&mll, contexts, 2);
+
+        assert_eq!("Hello, hoge!", results[0].as_ref().unwrap());
+        assert!(matches!(results[1], Err(MllError::MissingVariables { .. })));
+        assert_eq!("Hello, fuga!", results[2].as_ref().unwrap());
+
+        // each item fails with the error of the pre-process script
+        mll.set_pre_process_script("greeting = 'Hello'\nerror('hoge')".to_string());
+        let results = render_batch(&mll, vec![json!({"name": "hoge"}); 3], 2);
+
+        assert_eq!(3, results.len());
+        assert!(results.iter().all(|result| {
+            let e = result.as_ref().unwrap_err();
+            e.location().is_some_and(|location| location.line() == 2)
+        }));
+    }
+
+    #[test]
+    fn test_render_batch_deterministic() {
+        let mut mll = Mll::new();
+        mll.set_template("{{= mll.random.int(1, 1000000) }}".to_string());
+        mll.set_deterministic(Some(Deterministic::new(1, UNIX_EPOCH)));
+
+        let contexts = || (0..20).map(|_| json!({}));
+        let results = render_batch(&mll, contexts(), 1)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        // each item has its own seed
+        assert!(results.iter().any(|result| *result != results[0]));
+        assert_eq!(
+            results,
+            render_batch(&mll, contexts(), 1)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            results,
+            render_batch(&mll, contexts(), 3)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,30 +1,71 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
 use crate::builtins::{
-    builtin::BuiltinFunction,
+    builtin::{BuiltinFunction, set_function_in},
     exec::Exec,
+    filter::RegisterFilter,
     include::Include,
     lua_utils::{JsonToTable, TableToJson},
-    random::{RandomInt, RandomString},
+    print::Print,
+    random::{RandomInt, RandomString, RandomUuid},
     render::Render,
     s::ShiftJis,
 };
 
-use mlua::Lua;
+use mlua::{Lua, Table};
+
+use crate::Capabilities;
+
+/// Module in the `mll` table and name in the module of each standard builtin, by its legacy
+/// global name (e.g. `random_int` is `mll.random.int`)
+const MODULES: &[(&str, &str, &str)] = &[
+    ("exec", "process", "exec"),
+    ("s", "encoding", "sjis"),
+    ("random_int", "random", "int"),
+    ("random_string", "random", "string"),
+    ("random_uuid", "random", "uuid"),
+    ("include", "template", "include"),
+    ("render", "template", "render"),
+    ("register_filter", "template", "register_filter"),
+    ("table_to_json", "json", "encode"),
+    ("json_to_table", "json", "decode"),
+    ("simple_http_get", "http", "get"),
+    ("simple_http_post", "http", "post"),
+    ("simple_http_put", "http", "put"),
+    ("simple_http_delete", "http", "delete"),
+    ("send_http_request", "http", "request"),
+    ("datetime_format", "datetime", "format"),
+    ("datetime_offset", "datetime", "offset"),
+    ("datetime_now", "datetime", "now"),
+];
 
 pub struct Builtins;
 
 impl Builtins {
-    pub fn init(lua: &Lua) -> mlua::Result<()> {
-        let _ = Exec {}.set_function(lua);
-        let _ = ShiftJis {}.set_function(lua);
+    /// Set all builtins into the `mll` table, which check `capabilities` when they are called
+    ///
+    /// `print` replaces the global of Lua, and the others are set by their modules (e.g.
+    /// `mll.http.get`), so that globals of scripts cannot break them.
+    pub fn init(lua: &Lua, capabilities: &Capabilities) -> mlua::Result<()> {
+        lua.set_app_data(capabilities.clone());
+
+        let _ = Print {}.set_function(lua);
+        let _ = set_builtin(lua, Exec {});
+        let _ = set_builtin(lua, ShiftJis {});
+
+        let _ = set_builtin(lua, RandomInt {});
+        let _ = set_builtin(lua, RandomString {});
+        let _ = set_builtin(lua, RandomUuid {});
 
-        let _ = RandomInt {}.set_function(lua);
-        let _ = RandomString {}.set_function(lua);
+        let _ = set_builtin(lua, Include {});
+        let _ = set_builtin(lua, Render {});
 
-        let _ = Include {}.set_function(lua);
-        let _ = Render {}.set_function(lua);
+        let _ = set_builtin(lua, TableToJson {});
+        let _ = set_builtin(lua, JsonToTable {});
 
-        let _ = TableToJson {}.set_function(lua);
-        let _ = JsonToTable {}.set_function(lua);
+        let _ = set_builtin(lua, RegisterFilter {});
 
         #[cfg(feature = "http")]
         {
@@ -32,26 +73,278 @@ impl Builtins {
                 SendHttpRequest, SimpleHttpDelete, SimpleHttpGet, SimpleHttpPost, SimpleHttpPut,
             };
 
-            let _ = SimpleHttpGet {}.set_function(lua);
-            let _ = SimpleHttpPost {}.set_function(lua);
-            let _ = SimpleHttpPut {}.set_function(lua);
-            let _ = SimpleHttpDelete {}.set_function(lua);
-            let _ = SendHttpRequest {}.set_function(lua);
+            let _ = set_builtin(lua, SimpleHttpGet {});
+            let _ = set_builtin(lua, SimpleHttpPost {});
+            let _ = set_builtin(lua, SimpleHttpPut {});
+            let _ = set_builtin(lua, SimpleHttpDelete {});
+            let _ = set_builtin(lua, SendHttpRequest {});
         }
 
         #[cfg(feature = "datetime")]
         {
             use crate::builtins::datetime::DateTimeFormat;
-            let _ = DateTimeFormat {}.set_function(lua);
+            let _ = set_builtin(lua, DateTimeFormat {});
 
             use crate::builtins::datetime::DateTimeOffset;
-            let _ = DateTimeOffset {}.set_function(lua);
+            let _ = set_builtin(lua, DateTimeOffset {});
+
+            use crate::builtins::datetime::DateTimeNow;
+            let _ = set_builtin(lua, DateTimeNow {});
         }
 
         Ok(())
     }
 }
 
+/// Set a standard builtin into its module of the `mll` table
+fn set_builtin<B>(lua: &Lua, builtin: B) -> mlua::Result<()>
+where
+    B: BuiltinFunction,
+{
+    let module = MODULES
+        .iter()
+        .find(|(global, _, _)| *global == builtin.get_name());
+    let Some((_, module, key)) = module else {
+        return builtin.set_function(lua);
+    };
+
+    let table = module_table(lua, &format!("mll.{}", module))?;
+    let name = format!("mll.{}.{}", module, key);
+    set_function_in(lua, &table, key, &name, &builtin)
+}
+
+/// Set or remove the legacy globals (e.g. `random_int`) of the standard builtins
+fn set_legacy_globals(lua: &Lua, enabled: bool) -> mlua::Result<()> {
+    let globals = lua.globals();
+
+    for (global, module, key) in MODULES {
+        let Some(table) = find_table(lua, &format!("mll.{}", module))? else {
+            continue;
+        };
+        let function = table.get::<mlua::Value>(*key)?;
+        if !matches!(function, mlua::Value::Function(_)) {
+            continue;
+        }
+
+        if enabled {
+            globals.set(*global, function)?;
+        } else if globals.get::<mlua::Value>(*global)? == function {
+            // remove only the aliases, not the globals set by scripts
+            globals.set(*global, mlua::Value::Nil)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Builtin registered to a registry
+type SharedBuiltin = Arc<dyn BuiltinFunction + Send + Sync>;
+
+/// Builtins added, replaced or removed on top of the standard builtins
+///
+/// A builtin is set as a global, or as a field of a table named by its module
+/// (e.g. `ids.next()` for the builtin `next` in the module `ids`). A module can be nested by
+/// dots, and a builtin in the module of a standard builtin replaces it (e.g. `int` in the
+/// module `mll.random` replaces `mll.random.int`).
+///
+/// The standard builtins can also be set as the globals of the previous versions
+/// (e.g. `random_int` for `mll.random.int`) by `set_legacy_globals`.
+///
+/// # Examples
+///
+/// ```
+/// use mlua::Lua;
+///
+/// use libmll::{BuiltinFunction, BuiltinRegistry, Mll};
+///
+/// struct Next;
+///
+/// impl BuiltinFunction for Next {
+///     fn get_name(&self) -> &str {
+///         "next_id"
+///     }
+///
+///     fn get_function(&self, lua: &Lua) -> mlua::Function {
+///         lua.create_function(|_, prefix: String| Ok(format!("{}-0001", prefix)))
+///             .unwrap()
+///     }
+/// }
+///
+/// let mut registry = BuiltinRegistry::new();
+/// registry.register_in("ids", Next);
+/// registry.remove_in("mll.process", "exec");
+///
+/// let mut mll = Mll::new();
+/// mll.set_builtins(registry);
+/// mll.set_template("{{= ids.next_id('user') }} {{= mll.process.exec == nil }}".to_string());
+///
+/// assert_eq!("user-0001 true", mll.render_with_lua("").unwrap());
+/// ```
+#[derive(Clone, Default)]
+pub struct BuiltinRegistry {
+    /// Builtins by module and name, `None` if the builtin is removed
+    builtins: BTreeMap<(Option<String>, String), Option<SharedBuiltin>>,
+    legacy_globals: bool,
+}
+
+impl BuiltinRegistry {
+    /// Create registry without any changes to the standard builtins
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a builtin as a global, or replace the builtin of the same name
+    ///
+    /// # Arguments
+    ///
+    /// `builtin: B` - Builtin
+    pub fn register<B>(&mut self, builtin: B)
+    where
+        B: BuiltinFunction + Send + Sync + 'static,
+    {
+        let name = builtin.get_name().to_string();
+        self.builtins.insert((None, name), Some(Arc::new(builtin)));
+    }
+
+    /// Add a builtin to a module, or replace the builtin of the same name in the module
+    ///
+    /// # Arguments
+    ///
+    /// `module: &str` - Name of the module (global table)
+    ///
+    /// `builtin: B` - Builtin
+    pub fn register_in<B>(&mut self, module: &str, builtin: B)
+    where
+        B: BuiltinFunction + Send + Sync + 'static,
+    {
+        let name = builtin.get_name().to_string();
+        let key = (Some(module.to_string()), name);
+        self.builtins.insert(key, Some(Arc::new(builtin)));
+    }
+
+    /// Remove a global builtin
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the builtin
+    pub fn remove(&mut self, name: &str) {
+        self.builtins.insert((None, name.to_string()), None);
+    }
+
+    /// Remove a builtin from a module, including the standard builtins (e.g. `exec` in
+    /// `mll.process`)
+    ///
+    /// # Arguments
+    ///
+    /// `module: &str` - Name of the module
+    ///
+    /// `name: &str` - Name of the builtin
+    pub fn remove_in(&mut self, module: &str, name: &str) {
+        let key = (Some(module.to_string()), name.to_string());
+        self.builtins.insert(key, None);
+    }
+
+    /// Whether the standard builtins are also set as the legacy globals
+    pub fn legacy_globals(&self) -> bool {
+        self.legacy_globals
+    }
+
+    /// Set the standard builtins also as the globals of the previous versions (e.g.
+    /// `random_int` for `mll.random.int`), for scripts written for them
+    ///
+    /// # Arguments
+    ///
+    /// `enabled: bool` - `true` to set the legacy globals
+    pub fn set_legacy_globals(&mut self, enabled: bool) {
+        self.legacy_globals = enabled;
+    }
+
+    /// Get names of the globals set by `apply` (e.g. `consts` of `consts.one`)
+    pub(crate) fn global_names(&self) -> impl Iterator<Item = &str> {
+        let builtins = self.builtins.keys().map(|(module, name)| match module {
+            Some(module) => module.split('.').next().unwrap_or(module),
+            None => name.as_str(),
+        });
+        let legacy_globals = MODULES
+            .iter()
+            .filter(|_| self.legacy_globals)
+            .map(|(global, _, _)| *global);
+
+        builtins.chain(legacy_globals)
+    }
+
+    /// Set the builtins into the Lua state, after the standard builtins
+    pub(crate) fn apply(&self, lua: &Lua) -> mlua::Result<()> {
+        let globals = lua.globals();
+        // aliases of the builtins which may be replaced or removed
+        set_legacy_globals(lua, false)?;
+
+        for ((module, name), builtin) in &self.builtins {
+            let (table, full_name) = match module {
+                Some(module) => (module_table(lua, module)?, format!("{}.{}", module, name)),
+                None => (globals.clone(), name.clone()),
+            };
+
+            match builtin {
+                Some(builtin) => set_function_in(lua, &table, name, &full_name, builtin.as_ref())?,
+                None => table.set(name.as_str(), mlua::Value::Nil)?,
+            }
+        }
+
+        // after the changes, so that the globals are the replaced builtins
+        set_legacy_globals(lua, self.legacy_globals)
+    }
+}
+
+/// Get table of the module (e.g. `mll.http`), create it if it does not exist
+fn module_table(lua: &Lua, module: &str) -> mlua::Result<Table> {
+    let mut table = lua.globals();
+
+    for name in module.split('.') {
+        table = match table.get::<mlua::Value>(name)? {
+            mlua::Value::Table(child) => child,
+            _ => {
+                let child = lua.create_table()?;
+                table.set(name, child.clone())?;
+                child
+            }
+        };
+    }
+
+    Ok(table)
+}
+
+/// Get table of the module, `None` if it does not exist
+fn find_table(lua: &Lua, module: &str) -> mlua::Result<Option<Table>> {
+    let mut table = lua.globals();
+
+    for name in module.split('.') {
+        table = match table.get::<mlua::Value>(name)? {
+            mlua::Value::Table(child) => child,
+            _ => return Ok(None),
+        };
+    }
+
+    Ok(Some(table))
+}
+
+impl fmt::Debug for BuiltinRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.builtins.iter().map(|((module, name), builtin)| {
+            let name = match module {
+                Some(module) => format!("{}.{}", module, name),
+                None => name.clone(),
+            };
+            (name, builtin.is_some())
+        });
+
+        f.debug_struct("BuiltinRegistry")
+            .field("builtins", &names.collect::<Vec<_>>())
+            .field("legacy_globals", &self.legacy_globals)
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -63,7 +356,7 @@ mod tests {
     fn test_simple_http_get() {
         let template = "{{value}}";
         let pre_process_script = r#"
-            response = simple_http_get("https://httpbin.org/get", '{"foo": "bar"}')
+            response = mll.http.get("https://httpbin.org/get", '{"foo": "bar"}')
             args = response['args']
             value = args['foo']
         "#;
@@ -72,9 +365,60 @@ mod tests {
         mll.set_template(template.to_string());
         mll.set_pre_process_script(pre_process_script.to_string());
 
-        // mll.get_variable("response").unwrap();
-
         assert_eq!("bar", mll.render_lua_globals().unwrap());
+
+        let response = mll.get_variable::<serde_json::Value>("response").unwrap();
+        assert_eq!("bar", response["args"]["foo"]);
+    }
+
+    struct Constant(&'static str, i64);
+
+    impl BuiltinFunction for Constant {
+        fn get_name(&self) -> &str {
+            self.0
+        }
+
+        fn get_function(&self, lua: &Lua) -> mlua::Function {
+            let value = self.1;
+            lua.create_function(move |_, ()| Ok(value)).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_builtin_registry() {
+        let lua = Lua::new();
+        Builtins::init(&lua, &Capabilities::default()).unwrap();
+
+        let mut registry = BuiltinRegistry::new();
+        registry.register_in("mll.random", Constant("int", 4));
+        registry.register_in("consts", Constant("one", 1));
+        registry.register_in("consts", Constant("two", 2));
+        registry.remove_in("consts", "two");
+        registry.remove_in("mll.process", "exec");
+        registry.set_legacy_globals(true);
+        registry.apply(&lua).unwrap();
+
+        let result = lua
+            .load("return mll.random.int(1, 10) + random_int(1, 10), consts.one(), consts.two")
+            .eval::<(i64, i64, Option<mlua::Function>)>()
+            .unwrap();
+        assert_eq!((8, 1, None), result);
+
+        let result = lua
+            .load("return mll.process.exec == nil, exec == nil, random_string ~= nil")
+            .eval::<(bool, bool, bool)>()
+            .unwrap();
+        assert_eq!((true, true, true), result);
+
+        lua.load("s = 'hoge'").exec().unwrap();
+        registry.set_legacy_globals(false);
+        registry.apply(&lua).unwrap();
+
+        let result = lua
+            .load("return random_int == nil, s, mll.encoding.sjis ~= nil")
+            .eval::<(bool, String, bool)>()
+            .unwrap();
+        assert_eq!((true, "hoge".to_string(), true), result);
     }
 
     #[test]
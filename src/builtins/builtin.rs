@@ -1,14 +1,25 @@
-use mlua::Lua;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Instant;
+
+use mlua::{Lua, MultiValue, Table};
+
+use crate::MllError;
+use crate::limits::{call_limited, check_deadline};
+use crate::report::record_builtin;
 
 /// A trait for defining a built-in function
 ///
+/// Builtins of downstream crates are registered by `Mll::register_builtin`. The function can be
+/// async (made by `Lua::create_async_function`), and it waits on the runtime of the render
+/// instead of blocking it.
+///
 /// # Example
 ///
 /// ```
 /// use mlua::Lua;
-/// use mlua::prelude::*;
 ///
-/// use mll::builtins::builtin::*;
+/// use libmll::{BuiltinFunction, Mll};
 ///
 /// pub struct MyFunction;
 ///
@@ -18,15 +29,15 @@ use mlua::Lua;
 ///     }
 ///
 ///     fn get_function(&self, lua: &Lua) -> mlua::Function {
-///         let lua_ref = lua.clone();
-///         lua_ref
-///             .clone()
-///             .create_function(|_, ()| {
-///                 Ok("Hello, World!")
-///        })
-///        .unwrap()
+///         lua.create_function(|_, ()| Ok("Hello, World!")).unwrap()
 ///     }
 /// }
+///
+/// let mut mll = Mll::new();
+/// mll.register_builtin(MyFunction);
+/// mll.set_template("{{= my_function() }}".to_string());
+///
+/// assert_eq!("Hello, World!", mll.render_with_lua("").unwrap());
 /// ```
 pub trait BuiltinFunction {
     /// Get the name of the function
@@ -47,11 +58,134 @@ pub trait BuiltinFunction {
     /// `mlua::Function` - The function
     fn get_function(&self, lua: &Lua) -> mlua::Function;
 
+    /// Set the function as a global, which records its calls and time for the render report
+    ///
+    /// # Arguments
+    ///
+    /// * `lua` - The Lua context
     fn set_function(&self, lua: &Lua) -> mlua::Result<()> {
-        let globals = lua.globals();
-        let name = self.get_name().to_owned();
-        let func = self.get_function(lua);
-        globals.set(name, func)?;
-        Ok(())
+        let name = self.get_name();
+        set_function_in(lua, &lua.globals(), name, name, self)
     }
 }
+
+/// Set the function of a builtin into a table by `key`, which records its calls and time by
+/// `name` (e.g. `mll.random.int`)
+pub(crate) fn set_function_in<B>(
+    lua: &Lua,
+    table: &Table,
+    key: &str,
+    name: &str,
+    builtin: &B,
+) -> mlua::Result<()>
+where
+    B: BuiltinFunction + ?Sized,
+{
+    let func = timed(lua, name, builtin.get_function(lua))?;
+    table.set(key, func)?;
+    Ok(())
+}
+
+/// Wrap a function to record its calls and time, and to check the wall time limit before and
+/// after the call (blocking builtins cannot be stopped by the Lua hook)
+///
+/// The wrapper is an async function, so that async builtins (e.g. `mll.http.get`) can wait on
+/// the runtime of the render. Synchronous builtins return without yielding.
+fn timed(lua: &Lua, name: &str, function: mlua::Function) -> mlua::Result<mlua::Function> {
+    let name = name.to_owned();
+
+    lua.create_async_function(move |lua, args: MultiValue| {
+        let name = name.clone();
+        let function = function.clone();
+
+        async move {
+            check_deadline(&lua)?;
+
+            let start = Instant::now();
+            let result = call_limited::<MultiValue>(&lua, function, args).await;
+            record_builtin(&lua, &name, start.elapsed());
+
+            check_deadline(&lua)?;
+
+            // raise the original error, not the one wrapped by the call
+            result.map_err(|e| match e {
+                mlua::Error::CallbackError { cause, .. } => cause.as_ref().clone(),
+                e => e,
+            })
+        }
+    })
+}
+
+/// Make an error raised by a builtin
+///
+/// The error can be caught by `pcall` in Lua scripts, and an uncaught one is returned from
+/// `Mll` as `MllError::Builtin`.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::builtin_error;
+///
+/// let e = builtin_error("my_function", "something wrong");
+/// assert_eq!("my_function: something wrong", e.to_string());
+/// ```
+///
+/// # Arguments
+///
+/// * `name` - The name of the builtin
+/// * `message` - The error message
+///
+/// # Returns
+///
+/// `mlua::Error` - The Lua error
+pub fn builtin_error(name: &str, message: impl ToString) -> mlua::Error {
+    mlua::Error::external(MllError::Builtin {
+        name: name.to_string(),
+        message: message.to_string(),
+        source: None,
+        output: None,
+        location: None,
+    })
+}
+
+/// Make an error raised by a builtin, caused by another error
+///
+/// Same as `builtin_error`, and the cause is returned by `Error::source` of the
+/// `MllError::Builtin`.
+///
+/// # Examples
+///
+/// ```
+/// use std::error::Error;
+/// use libmll::{MllError, builtin_error_with_source};
+///
+/// let cause = std::fs::read("not_found.txt").unwrap_err();
+/// let e = builtin_error_with_source("my_function", "cannot read not_found.txt", cause);
+/// assert_eq!("my_function: cannot read not_found.txt", e.to_string());
+///
+/// let e = MllError::from(e);
+/// assert!(e.source().unwrap().downcast_ref::<std::io::Error>().is_some());
+/// ```
+///
+/// # Arguments
+///
+/// * `name` - The name of the builtin
+/// * `message` - The error message
+/// * `source` - The underlying error
+///
+/// # Returns
+///
+/// `mlua::Error` - The Lua error
+pub fn builtin_error_with_source(
+    name: &str,
+    message: impl ToString,
+    source: impl Error + Send + Sync + 'static,
+) -> mlua::Error {
+    mlua::Error::external(MllError::Builtin {
+        name: name.to_string(),
+        message: message.to_string(),
+        source: Some(Arc::new(source)),
+        output: None,
+        location: None,
+    })
+}
@@ -11,20 +11,26 @@
 //!    sec = 56
 //! }
 //!
-//! local formatted = datetime_format(datetime, "%Y/%m/%d %H:%M:%S")
+//! local formatted = mll.datetime.format(datetime, "%Y/%m/%d %H:%M:%S")
 //! print(formatted)    -- 2020/01/02 12:34:56
 //!
-//! local new_datetime = datetime_offset(datetime, 1, 2, 3, 4, 5)
-//! local formatted = datetime_format(new_datetime, "%Y/%m/%d %H:%M:%S")
+//! local new_datetime = mll.datetime.offset(datetime, 1, 2, 3, 4, 5)
+//! local formatted = mll.datetime.format(new_datetime, "%Y/%m/%d %H:%M:%S")
 //! print(formatted)    -- 2020/01/03 15:38:01
+//!
+//! local now = mll.datetime.now()
+//! print(mll.datetime.format(now, "%Y/%m/%d"))    -- e.g. 2025/03/14
 //! ```
 
+use std::fmt::Write;
+
 use chrono::Duration;
 use chrono::prelude::*;
 use mlua::Lua;
 use mlua::Table;
 
 use super::builtin::*;
+use crate::deterministic;
 
 /// Format command
 ///
@@ -32,7 +38,7 @@ use super::builtin::*;
 ///
 /// ```lua
 /// local dt = os.date(*t)
-/// local formatted = datetime_format(dt)
+/// local formatted = mll.datetime.format(dt)
 ///
 /// year = formatted.year -- e.g. 2025
 /// month = formatted.month -- e.g. 3
@@ -53,9 +59,9 @@ impl BuiltinFunction for DateTimeFormat {
         lua_ref
             .clone()
             .create_function(move |_, (datetime, format): (Table, String)| {
-                let dt = lua_datetime_to_chrono(&datetime);
-                let formatted = dt.format(&format);
-                Ok(formatted.to_string())
+                let dt = lua_datetime_to_chrono(&datetime)
+                    .map_err(|e| builtin_error("datetime_format", e))?;
+                format_chrono(&dt, &format).map_err(|e| builtin_error("datetime_format", e))
             })
             .unwrap()
     }
@@ -67,7 +73,7 @@ impl BuiltinFunction for DateTimeFormat {
 ///
 /// ```lua
 /// local dt = os.date(*t)
-/// local new_datetime = datetime_offset(dt, 1, 2, 3, 4, 5)
+/// local new_datetime = mll.datetime.offset(dt, 1, 2, 3, 4, 5)
 /// ```
 pub struct DateTimeOffset;
 
@@ -90,90 +96,130 @@ impl BuiltinFunction for DateTimeOffset {
                     Option<i64>,
                     Option<i64>,
                 )| {
-                    let weeks = Duration::weeks(weeks.unwrap_or(0));
-                    let days = Duration::days(days.unwrap_or(0));
-                    let hours = Duration::hours(hours.unwrap_or(0));
-                    let minutes = Duration::minutes(minutes.unwrap_or(0));
-                    let seconds = Duration::seconds(seconds.unwrap_or(0));
+                    let offsets = [
+                        Duration::try_weeks(weeks.unwrap_or(0)),
+                        Duration::try_days(days.unwrap_or(0)),
+                        Duration::try_hours(hours.unwrap_or(0)),
+                        Duration::try_minutes(minutes.unwrap_or(0)),
+                        Duration::try_seconds(seconds.unwrap_or(0)),
+                    ];
 
                     let dt = lua_datetime_to_chrono(&datetime)
-                        + weeks
-                        + days
-                        + hours
-                        + minutes
-                        + seconds;
+                        .map_err(|e| builtin_error("datetime_offset", e))?;
+                    let dt = offsets
+                        .into_iter()
+                        .try_fold(dt, |dt, offset| dt.checked_add_signed(offset?))
+                        .ok_or_else(|| builtin_error("datetime_offset", "datetime out of range"))?;
 
-                    Ok(chrono_datetime_to_lua(&lua_ref, &dt))
+                    chrono_datetime_to_lua(&lua_ref, &dt)
                 },
             )
             .unwrap()
     }
 }
 
-fn lua_datetime_to_chrono(data: &mlua::Table) -> NaiveDateTime {
-    lua_date_to_chrono(data).and_time(lua_time_to_chrono(data))
+/// Now command, returns the local time (fixed and in UTC in the deterministic mode)
+///
+/// # Examples
+///
+/// ```lua
+/// local now = mll.datetime.now()
+/// local tomorrow = mll.datetime.offset(now, 0, 1)
+/// ```
+pub struct DateTimeNow;
+
+impl BuiltinFunction for DateTimeNow {
+    fn get_name(&self) -> &str {
+        "datetime_now"
+    }
+
+    fn get_function(&self, lua: &Lua) -> mlua::Function {
+        let lua_ref = lua.clone();
+        lua_ref
+            .clone()
+            .create_function(move |lua, ()| {
+                let now = deterministic::now(lua);
+                // in UTC in the deterministic mode, so that it does not depend on the machine
+                let now = if deterministic::is_deterministic(lua) {
+                    DateTime::<Utc>::from(now).naive_utc()
+                } else {
+                    DateTime::<Local>::from(now).naive_local()
+                };
+                chrono_datetime_to_lua(lua, &now)
+            })
+            .unwrap()
+    }
 }
 
-fn lua_date_to_chrono(data: &mlua::Table) -> NaiveDate {
+pub(crate) fn lua_datetime_to_chrono(data: &mlua::Table) -> Result<NaiveDateTime, String> {
+    Ok(lua_date_to_chrono(data)?.and_time(lua_time_to_chrono(data)?))
+}
+
+/// Format datetime, returns an error instead of panicking on a bad format string
+pub(crate) fn format_chrono(data: &NaiveDateTime, format: &str) -> Result<String, String> {
+    let mut formatted = String::new();
+    match write!(formatted, "{}", data.format(format)) {
+        Ok(_) => Ok(formatted),
+        Err(_) => Err(format!("invalid format: {}", format)),
+    }
+}
+
+fn lua_date_to_chrono(data: &mlua::Table) -> Result<NaiveDate, String> {
     let year = data.get::<i32>("year").unwrap_or(1970);
     let month = data.get::<u32>("month").unwrap_or(1);
     let day = data.get::<u32>("day").unwrap_or(1);
 
-    let result = NaiveDate::from_ymd_opt(year, month, day).unwrap();
-
-    result
+    NaiveDate::from_ymd_opt(year, month, day)
+        .ok_or_else(|| format!("invalid date: {}-{}-{}", year, month, day))
 }
 
-fn lua_time_to_chrono(data: &mlua::Table) -> NaiveTime {
+fn lua_time_to_chrono(data: &mlua::Table) -> Result<NaiveTime, String> {
     let hour = data.get::<u32>("hour").unwrap_or(0);
     let min = data.get::<u32>("min").unwrap_or(0);
     let sec = data.get::<u32>("sec").unwrap_or(0);
 
-    let result = NaiveTime::from_hms_opt(hour, min, sec).unwrap();
-
-    result
+    NaiveTime::from_hms_opt(hour, min, sec)
+        .ok_or_else(|| format!("invalid time: {}:{}:{}", hour, min, sec))
 }
 
-fn chrono_datetime_to_lua(lua: &mlua::Lua, data: &NaiveDateTime) -> mlua::Table {
-    let table = lua.create_table().unwrap();
-
-    let date = chrono_date_to_lua(lua, &data.date());
-    let time = chrono_time_to_lua(lua, &data.time());
+fn chrono_datetime_to_lua(lua: &mlua::Lua, data: &NaiveDateTime) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
 
-    let _ = table.set("year", date.get::<i32>("year").unwrap_or(1970));
-    let _ = table.set("month", date.get::<i32>("month").unwrap_or(1));
-    let _ = table.set("day", date.get::<i32>("day").unwrap_or(1));
+    table.set("year", data.year())?;
+    table.set("month", data.month())?;
+    table.set("day", data.day())?;
 
-    let _ = table.set("hour", time.get::<i32>("hour").unwrap_or(0));
-    let _ = table.set("min", time.get::<i32>("min").unwrap_or(0));
-    let _ = table.set("sec", time.get::<i32>("sec").unwrap_or(0));
+    table.set("hour", data.hour())?;
+    table.set("min", data.minute())?;
+    table.set("sec", data.second())?;
 
-    table
+    Ok(table)
 }
 
-fn chrono_date_to_lua(lua: &mlua::Lua, data: &NaiveDate) -> mlua::Table {
-    let table = lua.create_table().unwrap();
-
-    table.set("year", data.year()).unwrap();
-    table.set("month", data.month()).unwrap();
-    table.set("day", data.day()).unwrap();
-
-    table
-}
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
 
-fn chrono_time_to_lua(lua: &mlua::Lua, data: &NaiveTime) -> mlua::Table {
-    let table = lua.create_table().unwrap();
+    use crate::{Deterministic, Mll};
 
-    table.set("hour", data.hour()).unwrap();
-    table.set("min", data.minute()).unwrap();
-    table.set("sec", data.second()).unwrap();
+    #[test]
+    fn test_datetime_now() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
 
-    table
-}
+        let mut mll = Mll::new();
+        mll.set_deterministic(Some(Deterministic::new(0, now)));
+        mll.set_template(
+            "{{= mll.datetime.format(mll.datetime.now(), '%Y-%m-%d %H:%M:%S') }}, \
+             {{= os.date('%Y-%m-%d %H:%M:%S') }}, {{= os.time() }}"
+                .to_string(),
+        );
 
-#[cfg(test)]
-mod tests {
-    use crate::Mll;
+        // in UTC whatever the timezone of the machine is
+        assert_eq!(
+            "2023-11-14 22:13:20, 2023-11-14 22:13:20, 1700000000",
+            mll.render_with_lua("").unwrap()
+        );
+    }
 
     #[test]
     fn test_datetime_format() {
@@ -191,7 +237,7 @@ mod tests {
                 sec = 56
             };
 
-            formatted_datetime = datetime_format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
+            formatted_datetime = mll.datetime.format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
         "#
             .to_string(),
         );
@@ -217,9 +263,9 @@ mod tests {
                 sec = 56
             };
 
-            datetime = datetime_offset(datetime, 1, -2, 3, -4, 5);
+            datetime = mll.datetime.offset(datetime, 1, -2, 3, -4, 5);
 
-            formatted_datetime = datetime_format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
+            formatted_datetime = mll.datetime.format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
         "#
             .to_string(),
         );
@@ -229,6 +275,28 @@ mod tests {
         assert_eq!(expected, rendered.unwrap());
     }
 
+    #[test]
+    fn test_datetime_error() {
+        let template = "{{invalid_date}},{{invalid_format}}";
+        let mut mll = Mll::new();
+        mll.set_template(template.to_owned());
+        mll.set_pre_process_script(
+            r#"
+            local _, e = pcall(mll.datetime.format, {year = 2020, month = 13, day = 1}, "%Y")
+            invalid_date = tostring(e):match("[^\n]*")
+
+            _, e = pcall(mll.datetime.format, {year = 2020, month = 1, day = 1}, "%Q")
+            invalid_format = tostring(e):match("[^\n]*")
+        "#
+            .to_string(),
+        );
+
+        let rendered = mll.render_lua_globals();
+        let expected =
+            "datetime_format: invalid date: 2020-13-1,datetime_format: invalid format: %Q";
+        assert_eq!(expected, rendered.unwrap());
+    }
+
     #[test]
     fn test_datetime_offset_nil() {
         let template = "{{formatted_datetime}}";
@@ -245,9 +313,9 @@ mod tests {
                 sec = 56
             };
 
-            datetime = datetime_offset(datetime, nil, nil, nil, nil, nil);
+            datetime = mll.datetime.offset(datetime, nil, nil, nil, nil, nil);
 
-            formatted_datetime = datetime_format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
+            formatted_datetime = mll.datetime.format(datetime, "%Y年%m月%d日　%H時%M分%S秒");
         "#
             .to_string(),
         );
@@ -3,16 +3,23 @@
 //! # Examples
 //!
 //! ```lua
-//! local result = exec("rustc", {"--version"})
+//! local result = mll.process.exec("rustc", {"--version"})
 //! print(result.code)   -- e.g. 0
 //! print(result.stdout)   -- e.g. "rustc 1.85.0 (4d91de4e4 2025-02-17)" (depends on your environment)
 //! print(result.stderr)   -- e.g. ""
 
-use std::process::Command;
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use mlua::{FromLua, Function, IntoLua, Lua};
+use tokio::task;
 
-use super::builtin::BuiltinFunction;
+use super::builtin::{BuiltinFunction, builtin_error, builtin_error_with_source};
+use crate::Limit;
+use crate::capabilities::check_capability;
+use crate::limits::{deadline, limit_error};
 
 pub struct Exec;
 
@@ -22,8 +29,16 @@ impl BuiltinFunction for Exec {
     }
 
     fn get_function(&self, lua: &Lua) -> Function {
-        lua.create_function(|_, (param, args): (String, Vec<String>)| Ok(system(param, args)))
-            .unwrap()
+        lua.create_async_function(|lua, (param, args): (String, Vec<String>)| async move {
+            check_capability(&lua, "exec", |c| c.check_executable(&param))?;
+            let deadline = deadline(&lua);
+
+            // wait for the command on a blocking thread, not on the runtime of the render
+            task::spawn_blocking(move || system(param, args, deadline))
+                .await
+                .map_err(|e| builtin_error_with_source("exec", e.to_string(), e))?
+        })
+        .unwrap()
     }
 }
 
@@ -41,13 +56,19 @@ impl IntoLua for ExecResult {
         table.set("stdout", self.stdout)?;
         table.set("stderr", self.stderr)?;
 
-        Ok(table.into_lua(lua).unwrap())
+        Ok(mlua::Value::Table(table))
     }
 }
 
 impl FromLua for ExecResult {
     fn from_lua(value: mlua::Value, _: &Lua) -> mlua::Result<Self> {
-        let table = value.as_table().unwrap();
+        let table = value
+            .as_table()
+            .ok_or_else(|| mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "ExecResult".to_string(),
+                message: Some("expected a table".to_string()),
+            })?;
 
         Ok(ExecResult {
             code: table.get("code")?,
@@ -57,28 +78,105 @@ impl FromLua for ExecResult {
     }
 }
 
-fn system(program: String, args: Vec<String>) -> ExecResult {
+fn system(
+    program: String,
+    args: Vec<String>,
+    deadline: Option<Instant>,
+) -> mlua::Result<ExecResult> {
     let mut command = Command::new(program.as_str());
     let result = command.args(args);
-    let output = result.output().expect("failed to execute process");
-
-    ExecResult {
-        code: output.status.code().unwrap(),
-        stdout: String::from_utf8(output.stdout)
-            .unwrap()
-            .trim_end()
-            .to_string(),
-        stderr: String::from_utf8(output.stderr)
-            .unwrap()
-            .trim_end()
-            .to_string(),
-    }
+    let output = match deadline {
+        Some(deadline) => output_until(result, deadline),
+        None => result.output().map(Some),
+    };
+    let output = match output {
+        Ok(Some(output)) => output,
+        Ok(None) => return Err(limit_error(Limit::WallTime)),
+        Err(e) => {
+            let message = format!("failed to execute {}: {}", program, e);
+            return Err(builtin_error("exec", message));
+        }
+    };
+
+    let Some(code) = output.status.code() else {
+        let message = format!("{} is terminated by a signal", program);
+        return Err(builtin_error("exec", message));
+    };
+
+    let to_string = |bytes: Vec<u8>, name: &str| match String::from_utf8(bytes) {
+        Ok(s) => Ok(s.trim_end().to_string()),
+        Err(_) => {
+            let message = format!("{} of {} is not valid UTF-8", name, program);
+            Err(builtin_error("exec", message))
+        }
+    };
+
+    Ok(ExecResult {
+        code,
+        stdout: to_string(output.stdout, "stdout")?,
+        stderr: to_string(output.stderr, "stderr")?,
+    })
+}
+
+/// Run command and collect its output, kill it if it is still running at the deadline
+///
+/// # Returns
+///
+/// `io::Result<Option<Output>>` - Output, `None` if the command is killed
+fn output_until(command: &mut Command, deadline: Instant) -> io::Result<Option<Output>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // read pipes while waiting, or the command blocks on a full pipe
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    Ok(status.map(|status| Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+fn read_pipe<R>(pipe: Option<R>) -> JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+
+        buffer
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::builtins::exec::system;
+    use crate::utils::do_blocking;
 
     #[test]
     fn test_system() {
@@ -87,7 +185,7 @@ mod tests {
             .iter()
             .map(|e| (*e).into())
             .collect::<Vec<String>>();
-        let result = system(program, args);
+        let result = system(program, args, None).unwrap();
 
         assert_eq!(0, result.code);
         assert!(result.stdout.starts_with("rustc"));
@@ -100,7 +198,8 @@ mod tests {
         let lua = Lua::new();
 
         let _ = Exec {}.set_function(&lua);
-        let _ = lua.load(r#"result = exec("rustc", {"--version"})"#).exec();
+        let chunk = lua.load(r#"result = exec("rustc", {"--version"})"#);
+        let _ = do_blocking(chunk.exec_async());
 
         let result = lua.globals().get::<ExecResult>("result").unwrap();
 
@@ -109,4 +208,37 @@ mod tests {
         assert!(result.stdout.ends_with(")"));
         assert_eq!("", result.stderr);
     }
+
+    #[test]
+    fn test_exec_error() {
+        let lua = Lua::new();
+
+        let _ = Exec {}.set_function(&lua);
+        let chunk = lua.load(
+            r#"
+                local ok, e = pcall(exec, "not_found_command", {})
+                return ok, tostring(e)
+            "#,
+        );
+        let result = do_blocking(chunk.eval_async::<(bool, String)>()).unwrap();
+
+        let (ok, message) = result.unwrap();
+        assert!(!ok);
+        assert!(message.starts_with("exec: failed to execute not_found_command: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_system_deadline() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        let result = system("sleep".to_string(), vec!["5".to_string()], Some(deadline));
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result = system("echo".to_string(), vec!["hoge".to_string()], Some(deadline));
+        assert_eq!("hoge", result.unwrap().stdout);
+    }
 }
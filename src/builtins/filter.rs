@@ -0,0 +1,64 @@
+//! Register template filter command
+//!
+//! # Example
+//! ```lua
+//! mll.template.register_filter("shout", function(value, suffix)
+//!     return string.upper(value) .. (suffix or "!")
+//! end)
+//! ```
+//!
+//! The filter can be used in the template as `{{ name | shout("!!") }}`.
+//! Filters registered by Lua take precedence over the standard filters of the same name.
+
+use mlua::{Function, Lua, Table};
+
+use super::builtin::BuiltinFunction;
+use crate::template::filters::FILTERS_REGISTRY_KEY;
+
+pub struct RegisterFilter;
+
+impl BuiltinFunction for RegisterFilter {
+    fn get_name(&self) -> &str {
+        "register_filter"
+    }
+
+    fn get_function(&self, lua: &Lua) -> Function {
+        lua.create_function(|lua, (name, function): (String, Function)| {
+            let filters = match lua.named_registry_value::<Option<Table>>(FILTERS_REGISTRY_KEY)? {
+                Some(filters) => filters,
+                None => {
+                    let filters = lua.create_table()?;
+                    lua.set_named_registry_value(FILTERS_REGISTRY_KEY, filters.clone())?;
+                    filters
+                }
+            };
+
+            filters.set(name, function)
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mll;
+
+    #[test]
+    fn test_register_filter() {
+        let template = r#"{{ name | shout("!!") }} {{ name | upper }}"#;
+        let script = r#"
+            mll.template.register_filter("shout", function(value, suffix)
+                return string.upper(value) .. (suffix or "!")
+            end)
+            mll.template.register_filter("upper", function(value)
+                return "overridden"
+            end)
+            name = "hoge"
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+
+        assert_eq!("HOGE!! overridden", mll.render_with_lua(script).unwrap());
+    }
+}
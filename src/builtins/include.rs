@@ -2,15 +2,15 @@
 //!
 //! # Example
 //! ```lua
-//! content = include("other_file.txt")
+//! content = mll.template.include("other_file.txt")
 //! ```
 
-use core::panic;
 use std::{fs, path::PathBuf};
 
 use mlua::Lua;
 
 use super::builtin::*;
+use crate::capabilities::check_capability;
 
 pub struct Include;
 
@@ -23,15 +23,18 @@ impl BuiltinFunction for Include {
         let lua_ref = lua.clone();
         lua_ref
             .clone()
-            .create_function(move |_, path: PathBuf| {
-                let content = match fs::read_to_string(path) {
+            .create_function(move |lua, path: PathBuf| {
+                check_capability(lua, "include", |c| c.check_read(&path))?;
+
+                let content = match fs::read_to_string(&path) {
                     Ok(c) => c,
                     Err(e) => {
-                        panic!("Error reading file: {}", e);
+                        let message = format!("cannot read {}: {}", path.display(), e);
+                        return Err(builtin_error("include", message));
                     }
                 };
 
-                return Ok(content);
+                Ok(content)
             })
             .unwrap()
     }
@@ -41,13 +44,13 @@ impl BuiltinFunction for Include {
 mod tests {
     use std::fs;
 
-    use crate::Mll;
+    use crate::{Mll, MllError};
 
     #[test]
     fn test_include() {
         let template = r#"{{content}}"#;
         let script = r#"
-            content = include("LICENSE")
+            content = mll.template.include("LICENSE")
         "#;
 
         let mut mll = Mll::new();
@@ -62,16 +65,29 @@ mod tests {
 
     #[cfg(target_os = "linux")]
     #[test]
-    #[should_panic(expected = "No such file or directory (os error 2)")]
-    fn test_include_panic() {
+    fn test_include_error() {
         let template = r#"{{content}}"#;
         let script = r#"
-            content = include("LICENSE1")
+            content = mll.template.include("LICENSE1")
         "#;
 
         let mut mll = Mll::new();
         mll.set_template(template.to_string());
 
-        let _ = mll.render_with_lua(script);
+        let e = mll.render_with_lua(script).unwrap_err();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        assert_eq!(
+            "include: cannot read LICENSE1: No such file or directory (os error 2)",
+            e.to_string()
+        );
+
+        let script = r#"
+            local ok, e = pcall(mll.template.include, "LICENSE1")
+            content = tostring(ok) .. ": " .. tostring(e):match("[^\n]*")
+        "#;
+        assert_eq!(
+            "false: include: cannot read LICENSE1: No such file or directory (os error 2)",
+            mll.render_with_lua(script).unwrap()
+        );
     }
 }
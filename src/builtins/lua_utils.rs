@@ -3,8 +3,8 @@
 //! # Examples
 //!
 //! ```lua
-//! local table = json_to_table('{"key": "value"}')
-//! local json = table_to_json(table)
+//! local table = mll.json.decode('{"key": "value"}')
+//! local json = mll.json.encode(table)
 //! ```
 
 use mlua::Lua;
@@ -27,10 +27,10 @@ impl BuiltinFunction for TableToJson {
         lua_ref
             .clone()
             .create_function(move |_, table: Table| {
-                Ok(
-                    serde_json::to_string(&lua_table_to_json_str(&lua_ref, table).unwrap())
-                        .unwrap(),
-                )
+                let json = lua_table_to_json_str(&lua_ref, table)
+                    .map_err(|e| builtin_error_with_source("table_to_json", e.to_string(), e))?;
+                serde_json::to_string(&json)
+                    .map_err(|e| builtin_error_with_source("table_to_json", e.to_string(), e))
             })
             .unwrap()
     }
@@ -47,7 +47,10 @@ impl BuiltinFunction for JsonToTable {
         let lua_ref = lua.clone();
         lua_ref
             .clone()
-            .create_function(move |_, json: String| Ok(json_str_to_lua_table(&lua_ref, &json)))
+            .create_function(move |_, json: String| {
+                json_str_to_lua_table(&lua_ref, &json)
+                    .map_err(|e| builtin_error_with_source("json_to_table", e.to_string(), e))
+            })
             .unwrap()
     }
 }
@@ -3,8 +3,10 @@ pub(crate) mod builtin;
 #[cfg(feature = "datetime")]
 pub(crate) mod datetime;
 pub(crate) mod exec;
+pub(crate) mod filter;
 pub(crate) mod include;
 pub(crate) mod lua_utils;
+pub(crate) mod print;
 pub(crate) mod random;
 pub(crate) mod render;
 pub(crate) mod s;
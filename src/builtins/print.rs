@@ -0,0 +1,56 @@
+//! Print command, which also records the output for the render report
+//!
+//! # Example
+//! ```lua
+//! print("hello", 1)  -- hello	1
+//! ```
+
+use mlua::{Function, Lua, MultiValue};
+
+use super::builtin::BuiltinFunction;
+use crate::report::record_print;
+
+pub struct Print;
+
+impl BuiltinFunction for Print {
+    fn get_name(&self) -> &str {
+        "print"
+    }
+
+    fn get_function(&self, lua: &Lua) -> Function {
+        lua.create_function(|lua, args: MultiValue| {
+            let tostring = lua.globals().get::<Function>("tostring")?;
+
+            let mut values = Vec::new();
+            for value in args {
+                values.push(tostring.call::<String>(value)?);
+            }
+
+            let line = values.join("\t");
+            println!("{}", line);
+            record_print(lua, line);
+
+            Ok(())
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mll;
+
+    #[test]
+    fn test_print() {
+        let mut mll = Mll::new();
+        mll.set_template("{{name}}".to_string());
+        mll.set_pre_process_script(r#"name = "hoge"; print(name, 1, nil); print()"#.to_string());
+
+        let (rendered, report) = mll.render_lua_globals_with_report();
+        assert_eq!("hoge", rendered.unwrap());
+        assert_eq!(
+            &["hoge\t1\tnil".to_string(), "".to_string()],
+            report.prints()
+        );
+    }
+}
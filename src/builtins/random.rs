@@ -3,17 +3,21 @@
 //! # Examples
 //!
 //! ```lua
-//! local random_int = random_int(1, 100)
+//! local random_int = mll.random.int(1, 100)
 //! print(random_int)   -- e.g. 42
 //!
-//! local random_string = random_string(10)
+//! local random_string = mll.random.string(10)
 //! print(random_string)   -- e.g. "aBcDeFgHiJ"
+//!
+//! local uuid = mll.random.uuid()
+//! print(uuid)   -- e.g. "67e55044-10b1-426f-9247-bb680e5fe0c8"
 //! ```
 
 use mlua::Lua;
 use rand::prelude::*;
 
 use super::builtin::*;
+use crate::deterministic::{new_uuid, with_rng};
 
 pub struct RandomInt;
 
@@ -26,9 +30,13 @@ impl BuiltinFunction for RandomInt {
         let lua_ref = lua.clone();
         lua_ref
             .clone()
-            .create_function(move |_, (minimum, maximum): (i32, i32)| {
-                let mut rng = rand::rng();
-                let rand = rng.random_range(minimum..maximum);
+            .create_function(move |lua, (minimum, maximum): (i32, i32)| {
+                if minimum >= maximum {
+                    let message = format!("empty range: {}..{}", minimum, maximum);
+                    return Err(builtin_error("random_int", message));
+                }
+
+                let rand = with_rng(lua, |rng| rng.random_range(minimum..maximum));
 
                 Ok(rand)
             })
@@ -47,18 +55,39 @@ impl BuiltinFunction for RandomString {
         let lua_ref = lua.clone();
         lua_ref
             .clone()
-            .create_function(move |_, length: usize| {
-                let rng = rand::rng();
-                let str = rng.sample_iter(rand::distr::Alphanumeric).take(length);
-                Ok(String::from_utf8(str.collect()).unwrap())
+            .create_function(move |lua, length: usize| {
+                let str = with_rng(lua, |rng| {
+                    (0..length)
+                        .map(|_| rng.sample(rand::distr::Alphanumeric))
+                        .collect::<Vec<u8>>()
+                });
+                Ok(String::from_utf8(str).unwrap())
             })
             .unwrap()
     }
 }
 
+pub struct RandomUuid;
+
+impl BuiltinFunction for RandomUuid {
+    fn get_name(&self) -> &str {
+        "random_uuid"
+    }
+
+    fn get_function(&self, lua: &Lua) -> mlua::Function {
+        let lua_ref = lua.clone();
+        lua_ref
+            .clone()
+            .create_function(move |lua, ()| Ok(new_uuid(lua).to_string()))
+            .unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::Mll;
+    use std::time::UNIX_EPOCH;
+
+    use crate::{Deterministic, Mll};
 
     #[test]
     fn test_random_int() {
@@ -66,9 +95,9 @@ mod tests {
 
         let template = r#"{{a}},{{b}},{{c}}"#;
         let script = r#"
-            a = random_int(1, 100)
-            b = random_int(-100, 0)
-            c = random_int(-100, 100)
+            a = mll.random.int(1, 100)
+            b = mll.random.int(-100, 0)
+            c = mll.random.int(-100, 100)
         "#;
 
         mll.set_template(template.to_string());
@@ -102,9 +131,9 @@ mod tests {
 
         let template = r#"{{a}},{{b}},{{c}}"#;
         let script = r#"
-            a = random_string(10)
-            b = random_string(20)
-            c = random_string(30)
+            a = mll.random.string(10)
+            b = mll.random.string(20)
+            c = mll.random.string(30)
         "#;
 
         mll.set_template(template.to_string());
@@ -125,4 +154,22 @@ mod tests {
         assert!(!split[1].contains(&split[0]));
         assert!(!split[2].contains(&split[0]));
     }
+
+    #[test]
+    fn test_deterministic() {
+        let render = |seed| {
+            let mut mll = Mll::new();
+            mll.set_deterministic(Some(Deterministic::new(seed, UNIX_EPOCH)));
+            mll.set_template("{{a}},{{b}},{{c}}".to_string());
+            mll.render_with_lua(
+                "a = mll.random.int(1, 1000000); b = mll.random.string(16); c = mll.random.uuid()",
+            )
+            .unwrap()
+        };
+
+        let rendered = render(1);
+        assert_eq!(rendered, render(1));
+        assert_ne!(rendered, render(2));
+        assert_eq!(36, rendered.split(',').nth(2).unwrap().len());
+    }
 }
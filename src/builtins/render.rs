@@ -2,18 +2,48 @@
 //!
 //! # Example
 //! ```lua
-//! content = render("{{name}}", {name="John Doe"})
+//! content = mll.template.render("{{name}}", {name="John Doe"})
 //! print(content)  -- John Doe
 //! ```
 
-use core::panic;
 use std::{fs, path::PathBuf};
 
 use mlua::{Lua, Table, Value};
 
-use crate::Mll;
+use crate::{BuiltinRegistry, MissingVariablePolicy, Mll, MllError, ValueFormat, ValueSource};
 
 use super::builtin::*;
+use crate::capabilities::{capabilities_of, check_capability};
+use crate::deterministic;
+use crate::limits::remaining_limits;
+
+/// Settings of the session which the templates rendered by `mll.template.render` inherit
+#[derive(Clone, Default)]
+pub(crate) struct Session {
+    pub(crate) builtins: BuiltinRegistry,
+    pub(crate) missing_variable_policy: MissingVariablePolicy,
+    pub(crate) value_format: ValueFormat,
+}
+
+impl Session {
+    /// Get settings of the session of the Lua state
+    fn of(lua: &Lua) -> Self {
+        lua.app_data_ref::<Session>()
+            .map(|session| session.clone())
+            .unwrap_or_default()
+    }
+
+    /// Create a nested session with the settings of the Lua state
+    fn nested(lua: &Lua) -> Mll {
+        let session = Self::of(lua);
+
+        let mut mll = Mll::new();
+        mll.set_builtins(session.builtins);
+        mll.set_missing_variable_policy(session.missing_variable_policy);
+        mll.set_value_format(session.value_format);
+        mll
+    }
+}
 
 pub struct Render;
 
@@ -23,53 +53,72 @@ impl BuiltinFunction for Render {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(
-                move |_, (template, params_path_or_table): (String, Value)| {
-                    match params_path_or_table {
-                        Value::String(p) => {
-                            let path = PathBuf::from(p.to_string_lossy());
-                            return Ok(render_with_file(template, path));
-                        }
-                        Value::Table(t) => {
-                            return Ok(render_with_table(template, t));
-                        }
-                        _ => {
-                            panic!("Unexpected data type");
-                        }
+        lua.create_async_function(
+            |lua, (template, params_path_or_table): (String, Value)| async move {
+                let rendered = match params_path_or_table {
+                    Value::String(p) => {
+                        let path = PathBuf::from(p.to_string_lossy());
+                        check_capability(&lua, "render", |c| c.check_read(&path))?;
+                        render_with_file(&lua, template, path).await
                     }
-                },
-            )
-            .unwrap()
+                    Value::Table(t) => render_with_table(&lua, template, t).await,
+                    v => {
+                        let message = format!(
+                            "expected a path or a table as parameters, got {}",
+                            v.type_name()
+                        );
+                        return Err(builtin_error("render", message));
+                    }
+                };
+
+                rendered.map_err(|e| builtin_error_with_source("render", e.to_string(), e))
+            },
+        )
+        .unwrap()
     }
 }
 
-fn render_with_file(template_content: String, params_path: PathBuf) -> Result<String, String> {
-    let params_content = match fs::read_to_string(params_path) {
+async fn render_with_file(
+    lua: &Lua,
+    template_content: String,
+    params_path: PathBuf,
+) -> Result<String, MllError> {
+    let params_content = match fs::read_to_string(&params_path) {
         Ok(c) => c,
-        Err(e) => panic!("Error reading file: {}", e),
+        Err(e) => {
+            return Err(MllError::Io {
+                path: params_path.display().to_string(),
+                source: e,
+            });
+        }
     };
 
-    let mut mll = Mll::new();
+    // parameters are run in a new session with the same settings, capabilities, the rest of the
+    // limits, and a deterministic mode seeded by the parent
+    let mut mll = Session::nested(lua);
+    mll.set_capabilities(capabilities_of(lua));
+    mll.set_deterministic(deterministic::nested(lua));
+    mll.set_limits(remaining_limits(lua));
     mll.set_template(template_content);
-    let result = mll.render_with_lua(&params_content);
-
-    result
+    mll.render_with_lua_async(&params_content).await
 }
 
-fn render_with_table(template_content: String, params: Table) -> Result<String, String> {
-    let mut mll = Mll::new();
+async fn render_with_table(
+    lua: &Lua,
+    template_content: String,
+    params: Table,
+) -> Result<String, MllError> {
+    let mut mll = Session::nested(lua);
     mll.set_template(template_content);
-    let result = mll.render(&params);
-
-    result
+    mll.render_in(lua, &params, ValueSource::Context).await
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Mll;
+    use std::fs;
+    use std::time::UNIX_EPOCH;
+
+    use crate::{Deterministic, MissingVariablePolicy, Mll, MllError};
 
     #[test]
     fn test_render_with_table() {
@@ -81,7 +130,7 @@ mod tests {
             }
             name = "Jane Doe"
             age = 20
-            content = render('{"name": "{{name}}", "age": {{age}}}', table)
+            content = mll.template.render('{"name": "{{name}}", "age": {{age}}}', table)
         "#;
 
         let mut mll = Mll::new();
@@ -96,16 +145,52 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Unexpected data type")]
-    fn test_render_panic() {
+    fn test_render_with_file_session() {
+        let path = std::env::temp_dir().join("mll_test_render_with_file_session.lua");
+        fs::write(&path, "x = random_int(1, 1000000)").unwrap();
+        let script = r#"content = mll.template.render("{{x}} {{y}}", PATH)"#
+            .replace("PATH", &format!("{:?}", path.display().to_string()));
+
+        let render = || {
+            let mut mll = Mll::new();
+            mll.set_deterministic(Some(Deterministic::new(1, UNIX_EPOCH)));
+            mll.set_legacy_globals(true);
+            mll.set_missing_variable_policy(MissingVariablePolicy::Default("-".to_string()));
+            mll.set_template("{{content}}".to_string());
+            mll.render_with_lua(&script).unwrap()
+        };
+
+        let (first, second) = (render(), render());
+        fs::remove_file(&path).unwrap();
+
+        assert!(first.ends_with(" -"));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_render_error() {
         let template = r#"{{content}}"#;
         let script = r#"
-            content = render("", nil)
+            content = mll.template.render("", nil)
         "#;
 
         let mut mll = Mll::new();
         mll.set_template(template.to_string());
 
-        let _ = mll.render_with_lua(script);
+        let e = mll.render_with_lua(script).unwrap_err();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        assert_eq!(
+            "render: expected a path or a table as parameters, got nil",
+            e.to_string()
+        );
+
+        let script = r#"
+            local ok, e = pcall(mll.template.render, "{{name}}", {})
+            content = tostring(e):match("[^\n]*")
+        "#;
+        assert_eq!(
+            "render: missing variables: name",
+            mll.render_with_lua(script).unwrap()
+        );
     }
 }
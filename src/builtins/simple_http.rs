@@ -1,14 +1,28 @@
 //! HTTP request commands.
+//!
+//! The data of a `GET` request is a JSON object sent as the query parameters, and the data of
+//! the other methods is sent as the body.
 
-use core::panic;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use chitose::method::HttpMethod;
 use mlua::{FromLua, IntoLua, Lua, Table};
+use serde_json::Value as JsonValue;
+use tokio::task;
+use url::Url;
 
 use crate::utils::json_str_to_lua_table;
 
 use super::builtin::*;
+use crate::Limit;
+use crate::capabilities::{Capabilities, capabilities_of, check_capability};
+use crate::limits::{deadline, limit_error};
+
+/// Maximum number of redirects followed by a request
+const MAX_REDIRECTS: usize = 10;
+
+/// Timeout of a request, including its redirects
+const TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct SimpleHttpGet;
 
@@ -18,19 +32,14 @@ impl BuiltinFunction for SimpleHttpGet {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(move |_, (url, data): (String, String)| {
-                let c = "";
-                let t = HashMap::<&str, &str>::new();
+        lua.create_async_function(|lua, (url, data): (String, String)| async move {
+            check_capability(&lua, "simple_http_get", |c| c.check_http(&url))?;
 
-                let r = chitose::sync_http_get(&url, &c, t, &data);
-                let r2 = json_str_to_lua_table(&lua_ref, &r);
-
-                Ok(r2.unwrap())
-            })
-            .unwrap()
+            let request = HttpRequest::new(url, HttpMethod::Get, data);
+            let r = send(&lua, "simple_http_get", request).await?;
+            response_to_lua_table(&lua, "simple_http_get", &r)
+        })
+        .unwrap()
     }
 }
 
@@ -42,19 +51,14 @@ impl BuiltinFunction for SimpleHttpPost {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(move |_, (url, data): (String, String)| {
-                let c = "";
-                let t = HashMap::<&str, &str>::new();
+        lua.create_async_function(|lua, (url, data): (String, String)| async move {
+            check_capability(&lua, "simple_http_post", |c| c.check_http(&url))?;
 
-                let r = chitose::sync_http_post(&url, &c, t, &data);
-                let r2 = json_str_to_lua_table(&lua_ref, &r);
-
-                Ok(r2.unwrap())
-            })
-            .unwrap()
+            let request = HttpRequest::new(url, HttpMethod::Post, data);
+            let r = send(&lua, "simple_http_post", request).await?;
+            response_to_lua_table(&lua, "simple_http_post", &r)
+        })
+        .unwrap()
     }
 }
 
@@ -66,19 +70,14 @@ impl BuiltinFunction for SimpleHttpPut {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(move |_, (url, data): (String, String)| {
-                let c = "";
-                let t = HashMap::<&str, &str>::new();
-
-                let r = chitose::sync_http_put(&url, &c, t, &data);
-                let r2 = json_str_to_lua_table(&lua_ref, &r);
+        lua.create_async_function(|lua, (url, data): (String, String)| async move {
+            check_capability(&lua, "simple_http_put", |c| c.check_http(&url))?;
 
-                Ok(r2.unwrap())
-            })
-            .unwrap()
+            let request = HttpRequest::new(url, HttpMethod::Put, data);
+            let r = send(&lua, "simple_http_put", request).await?;
+            response_to_lua_table(&lua, "simple_http_put", &r)
+        })
+        .unwrap()
     }
 }
 
@@ -90,39 +89,180 @@ impl BuiltinFunction for SimpleHttpDelete {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(move |_, (url, data): (String, String)| {
-                let c = "";
-                let t = HashMap::<&str, &str>::new();
+        lua.create_async_function(|lua, (url, data): (String, String)| async move {
+            check_capability(&lua, "simple_http_delete", |c| c.check_http(&url))?;
 
-                let r = chitose::sync_http_delete(&url, &c, t, &data);
-                let r2 = json_str_to_lua_table(&lua_ref, &r);
+            let request = HttpRequest::new(url, HttpMethod::Delete, data);
+            let r = send(&lua, "simple_http_delete", request).await?;
+            response_to_lua_table(&lua, "simple_http_delete", &r)
+        })
+        .unwrap()
+    }
+}
 
-                Ok(r2.unwrap())
-            })
-            .unwrap()
+/// Send a request on a blocking thread, so that the blocking HTTP client neither blocks nor
+/// nests the runtime of the render
+///
+/// The request is stopped at the deadline of the wall time limit, and fails by the limit.
+async fn send(lua: &Lua, name: &str, request: HttpRequest) -> mlua::Result<String> {
+    let capabilities = capabilities_of(lua);
+    let deadline = deadline(lua);
+    let sent = task::spawn_blocking(move || send_blocking(&capabilities, &request, deadline));
+
+    let sent = sent
+        .await
+        .map_err(|e| builtin_error_with_source(name, e.to_string(), e))?;
+    match sent {
+        Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+            Err(limit_error(Limit::WallTime))
+        }
+        sent => sent.map_err(|message| builtin_error(name, message)),
+    }
+}
+
+/// Send a request, and get the body of the response
+///
+/// Redirects are followed here instead of the HTTP client, so that the location of each
+/// redirect is checked by the capabilities as well as the first URL.
+fn send_blocking(
+    capabilities: &Capabilities,
+    request: &HttpRequest,
+    deadline: Option<Instant>,
+) -> Result<String, String> {
+    let agent = ureq::AgentBuilder::new()
+        .redirects(0)
+        .timeout(TIMEOUT)
+        .build();
+
+    let mut url = Url::parse(request.url()).map_err(|e| format!("invalid url: {}", e))?;
+    let mut method = request.method().0;
+    let mut body = request.body().as_str();
+    for _ in 0..=MAX_REDIRECTS {
+        let timeout = match deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(TIMEOUT),
+            None => TIMEOUT,
+        };
+        if timeout.is_zero() {
+            return Err("time limit exceeded".to_string());
+        }
+
+        let response = send_once(&agent, &url, method, request.header(), body, timeout)?;
+
+        let location = match response.status() {
+            301 | 302 | 303 | 307 | 308 => response.header("location"),
+            _ => None,
+        };
+        let Some(location) = location else {
+            return response.into_string().map_err(|e| e.to_string());
+        };
+
+        url = url
+            .join(location)
+            .map_err(|e| format!("invalid redirect to {}: {}", location, e))?;
+        capabilities.check_http(url.as_str())?;
+
+        // `303 See Other` is fetched by `GET`, the others repeat the request
+        if response.status() == 303 {
+            method = HttpMethod::Get;
+            body = "";
+        }
+    }
+
+    Err(format!("too many redirects (more than {})", MAX_REDIRECTS))
+}
+
+/// Send a request without following redirects
+fn send_once(
+    agent: &ureq::Agent,
+    url: &Url,
+    method: HttpMethod,
+    header: &HashMap<String, String>,
+    body: &str,
+    timeout: Duration,
+) -> Result<ureq::Response, String> {
+    let mut url = url.clone();
+    if method == HttpMethod::Get && !body.is_empty() {
+        let JsonValue::Object(parameters) =
+            serde_json::from_str(body).map_err(|e| format!("invalid JSON data: {}", e))?
+        else {
+            return Err("data of GET request is not a JSON object".to_string());
+        };
+
+        let mut query = url.query_pairs_mut();
+        for (key, value) in parameters {
+            match value {
+                JsonValue::String(value) => query.append_pair(&key, &value),
+                value => query.append_pair(&key, &value.to_string()),
+            };
+        }
+    }
+
+    let request = header.iter().fold(
+        agent.request_url(method.as_str(), &url).timeout(timeout),
+        |request, (k, v)| request.set(k, v),
+    );
+    let sent = match method {
+        HttpMethod::Get => request.call(),
+        _ => request.send_string(body),
+    };
+
+    match sent {
+        // the body of an error response is returned as it is
+        Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a JSON response into a Lua table, raise an error of the builtin if it is invalid
+fn response_to_lua_table(lua: &Lua, name: &str, response: &str) -> mlua::Result<Table> {
+    json_str_to_lua_table(lua, response)
+        .map_err(|e| builtin_error_with_source(name, format!("invalid JSON response: {}", e), e))
+}
+
+/// Method of an HTTP request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl HttpMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+        }
     }
 }
 
 pub(crate) struct MllHttpMethod(HttpMethod);
 
-impl From<String> for MllHttpMethod {
-    fn from(value: String) -> Self {
+impl TryFrom<String> for MllHttpMethod {
+    type Error = mlua::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.to_ascii_uppercase().as_str() {
-            "GET" => MllHttpMethod(HttpMethod::GET),
-            "POST" => MllHttpMethod(HttpMethod::POST),
-            "PUT" => MllHttpMethod(HttpMethod::PUT),
-            "DELETE" => MllHttpMethod(HttpMethod::DELETE),
-            _ => panic!("Unknown http method"),
+            "GET" => Ok(MllHttpMethod(HttpMethod::Get)),
+            "POST" => Ok(MllHttpMethod(HttpMethod::Post)),
+            "PUT" => Ok(MllHttpMethod(HttpMethod::Put)),
+            "DELETE" => Ok(MllHttpMethod(HttpMethod::Delete)),
+            _ => Err(builtin_error(
+                "send_http_request",
+                format!("unknown http method: {}", value),
+            )),
         }
     }
 }
 
 impl Default for MllHttpMethod {
     fn default() -> Self {
-        MllHttpMethod(HttpMethod::GET)
+        MllHttpMethod(HttpMethod::Get)
     }
 }
 
@@ -135,6 +275,15 @@ pub struct HttpRequest {
 }
 
 impl HttpRequest {
+    fn new(url: String, method: HttpMethod, body: String) -> Self {
+        Self {
+            url,
+            method: MllHttpMethod(method),
+            header: HashMap::new(),
+            body,
+        }
+    }
+
     pub fn url(&self) -> &String {
         &self.url
     }
@@ -154,39 +303,29 @@ impl HttpRequest {
 
 impl ToString for MllHttpMethod {
     fn to_string(&self) -> String {
-        match self.0 {
-            HttpMethod::GET => "GET".to_string(),
-            HttpMethod::POST => "POST".to_string(),
-            HttpMethod::PUT => "PUT".to_string(),
-            HttpMethod::DELETE => "DELETE".to_string(),
-        }
+        self.0.as_str().to_string()
     }
 }
 
 impl FromLua for HttpRequest {
     fn from_lua(value: mlua::Value, _: &mlua::Lua) -> mlua::Result<Self> {
-        let table = value.as_table().unwrap();
-        let url = table.get::<String>("url").unwrap().as_str().to_string();
-        let method = table
-            .get::<String>("method")
-            .unwrap()
-            .as_str()
-            .to_string()
-            .into();
+        let table = value
+            .as_table()
+            .ok_or_else(|| mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "HttpRequest".to_string(),
+                message: Some("expected a table".to_string()),
+            })?;
+
+        let url = table.get::<String>("url")?;
+        let method = match table.get::<Option<String>>("method")? {
+            Some(method) => method.try_into()?,
+            None => MllHttpMethod::default(),
+        };
         let header = table
-            .get::<Table>("header")
-            .unwrap()
-            .pairs::<mlua::String, mlua::String>()
-            .into_iter()
-            .map(|p| match p {
-                Ok((k, v)) => (
-                    k.to_str().unwrap().to_string(),
-                    v.to_str().unwrap().to_string(),
-                ),
-                Err(_) => panic!("Error parsing header"),
-            })
-            .collect();
-        let body = table.get::<String>("body").unwrap().to_string();
+            .get::<Option<HashMap<String, String>>>("header")?
+            .unwrap_or_default();
+        let body = table.get::<Option<String>>("body")?.unwrap_or_default();
 
         Ok(Self {
             url,
@@ -217,34 +356,112 @@ impl BuiltinFunction for SendHttpRequest {
     }
 
     fn get_function(&self, lua: &Lua) -> mlua::Function {
-        let lua_ref = lua.clone();
-        lua_ref
-            .clone()
-            .create_function(move |_, request: HttpRequest| {
-                let header = request
-                    .header()
-                    .into_iter()
-                    .map(|(k, v)| (k.as_str(), v.as_str()))
-                    .collect();
-                let r = match request.method().0 {
-                    HttpMethod::GET => {
-                        chitose::sync_http_get(request.url(), "", header, request.body())
-                    }
-                    HttpMethod::POST => {
-                        chitose::sync_http_post(request.url(), "", header, request.body())
-                    }
-                    HttpMethod::PUT => {
-                        chitose::sync_http_put(request.url(), "", header, request.body())
-                    }
-                    HttpMethod::DELETE => {
-                        chitose::sync_http_delete(request.url(), "", header, request.body())
-                    }
-                };
-
-                let r2 = json_str_to_lua_table(&lua_ref, &r);
-
-                Ok(r2.unwrap())
-            })
-            .unwrap()
+        lua.create_async_function(|lua, request: HttpRequest| async move {
+            check_capability(&lua, "send_http_request", |c| c.check_http(request.url()))?;
+
+            let r = send(&lua, "send_http_request", request).await?;
+            response_to_lua_table(&lua, "send_http_request", &r)
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::{Limits, Mll, MllError};
+
+    /// Serve the responses on a local port, one per connection, and get the base URL
+    fn serve(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    fn redirect(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            location
+        )
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn test_redirect() {
+        let capabilities = Capabilities::sandboxed().allow_http_host("127.0.0.1");
+        let get = |url: String| {
+            let request = HttpRequest::new(url, HttpMethod::Get, String::new());
+            send_blocking(&capabilities, &request, None)
+        };
+
+        let url = serve(vec![redirect("/next"), ok(r#"{"a": 1}"#)]);
+        assert_eq!(Ok(r#"{"a": 1}"#.to_string()), get(url));
+
+        // the location of a redirect is checked as well as the first URL
+        let url = serve(vec![redirect("http://example.com/")]);
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            get(url)
+        );
+
+        let url = serve(vec![redirect("http://127.0.0.1@example.com/")]);
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            get(url)
+        );
+
+        let url = serve(vec![redirect("/"); MAX_REDIRECTS + 1]);
+        assert!(get(url).unwrap_err().starts_with("too many redirects"));
+    }
+
+    #[test]
+    fn test_wall_time() {
+        // accept the connection, and never reply
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _stream = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(60));
+        });
+
+        let mut mll = Mll::new();
+        mll.set_limits(Limits::new().wall_time(Duration::from_millis(200)));
+        mll.set_template("{{ x }}".to_string());
+        mll.set_pre_process_script(format!("x = mll.http.get('{}', '')", url));
+
+        let start = Instant::now();
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(
+            e,
+            MllError::LimitExceeded {
+                limit: Limit::WallTime,
+                ..
+            }
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
     }
 }
@@ -22,8 +22,8 @@ mod my_sql {
     use sqlx::{Column, ConnectOptions, Connection, Row};
     use uuid::Uuid;
 
-    use crate::builtins::builtin::BuiltinFunction;
-    use crate::utils::do_blocking;
+    use crate::builtins::builtin::{BuiltinFunction, builtin_error_with_source};
+    use crate::deterministic;
 
     use super::DatabaseSystemName;
 
@@ -42,7 +42,7 @@ mod my_sql {
             lua_ref
                 .clone()
                 .create_function(move |lua, config: MySqlConnectionConfig| {
-                    let uuid = Uuid::new_v4();
+                    let uuid = deterministic::new_uuid(lua);
                     let key = uuid
                         .simple()
                         .encode_lower(&mut Uuid::encode_buffer())
@@ -66,39 +66,38 @@ mod my_sql {
         }
 
         fn get_function(&self, lua: &Lua) -> mlua::Function {
-            let lua_ref = lua.clone();
-            lua_ref
-                .clone()
-                .create_function(move |lua, (connection_string, query): (String, String)| {
+            lua.create_async_function(
+                |lua, (connection_string, query): (String, String)| async move {
                     let table: Table = lua.globals().get("qlp_internal")?;
                     let connection_data: MySqlConnectionConfig = table.get(connection_string)?;
 
-                    let conn = do_blocking(
-                        MySqlConnectOptions::new()
-                            .host(connection_data.host.as_str())
-                            .port(connection_data.port)
-                            .username(connection_data.username.as_str())
-                            .password(connection_data.password.as_str())
-                            .database(connection_data.database.as_str())
-                            .connect(),
-                    );
-
-                    if conn.is_err() {
-                        return Err(mlua::Error::RuntimeError(
-                            "Failed to connect to database".to_string(),
-                        ));
-                    }
-
-                    let result =
-                        do_blocking(sqlx::query(query.as_str()).fetch_all(&mut conn.unwrap()));
-
-                    if result.is_err() {
-                        return Err(mlua::Error::RuntimeError(
-                            "Failed to execute query".to_string(),
-                        ));
-                    }
-
-                    let fetched = result.unwrap();
+                    let conn = MySqlConnectOptions::new()
+                        .host(connection_data.host.as_str())
+                        .port(connection_data.port)
+                        .username(connection_data.username.as_str())
+                        .password(connection_data.password.as_str())
+                        .database(connection_data.database.as_str())
+                        .connect()
+                        .await;
+
+                    let mut conn = conn.map_err(|e| {
+                        builtin_error_with_source(
+                            "execute_sql",
+                            format!("failed to connect to database: {}", e),
+                            e,
+                        )
+                    })?;
+
+                    let fetched = sqlx::query(query.as_str())
+                        .fetch_all(&mut conn)
+                        .await
+                        .map_err(|e| {
+                            builtin_error_with_source(
+                                "execute_sql",
+                                format!("failed to execute query: {}", e),
+                                e,
+                            )
+                        })?;
                     let table = lua.create_table()?;
 
                     for row in fetched {
@@ -112,8 +111,9 @@ mod my_sql {
                     }
 
                     return Ok(90);
-                })
-                .unwrap()
+                },
+            )
+            .unwrap()
         }
     }
 
@@ -135,13 +135,19 @@ mod my_sql {
             table.set("username", self.username)?;
             table.set("password", self.password)?;
 
-            Ok(table.into_lua(lua).unwrap())
+            Ok(mlua::Value::Table(table))
         }
     }
 
     impl FromLua for MySqlConnectionConfig {
         fn from_lua(value: mlua::Value, lua: &Lua) -> mlua::Result<Self> {
-            let table = value.as_table().unwrap();
+            let table = value
+                .as_table()
+                .ok_or_else(|| mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "MySqlConnectionConfig".to_string(),
+                    message: Some("expected a table".to_string()),
+                })?;
 
             Ok(Self {
                 host: table.get("host")?,
@@ -0,0 +1,344 @@
+use std::path::{Path, PathBuf};
+
+use mlua::{Lua, LuaOptions, StdLib};
+use url::{Host, Url};
+
+use crate::builtins::builtin::builtin_error;
+
+/// What scripts and templates are allowed to do
+///
+/// `Capabilities::default()` allows everything, which is the behavior for trusted templates.
+/// `Capabilities::sandboxed()` denies reading files, executing commands and network access,
+/// and loads only the `coroutine`, `table`, `string`, `utf8` and `math` libraries of Lua.
+/// Builtins called without the capability raise a Lua error (e.g. `exec: executing rm is not
+/// allowed`), which can be caught by `pcall`.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::{Capabilities, Mll};
+///
+/// let mut mll = Mll::new();
+/// mll.set_capabilities(Capabilities::sandboxed().allow_read("src"));
+///
+/// mll.set_template("{{= #mll.template.include('src/lib.rs') > 0 }}".to_string());
+/// assert_eq!("true", mll.render_with_lua("").unwrap());
+///
+/// mll.set_template("{{= mll.process.exec('rustc', {'--version'}).stdout }}".to_string());
+/// let e = mll.render_with_lua("").unwrap_err();
+/// assert!(e.to_string().contains("exec: executing rustc is not allowed"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    /// Directories whose files can be read, `None` for any file
+    read_roots: Option<Vec<PathBuf>>,
+    /// Programs which can be executed, `None` for any program
+    executables: Option<Vec<String>>,
+    /// Hosts which can be accessed by HTTP, `None` for any host
+    http_hosts: Option<Vec<String>>,
+    network: bool,
+    lua_stdlib: StdLib,
+}
+
+impl Capabilities {
+    /// Deny everything except rendering and the pure builtins
+    ///
+    /// # Returns
+    ///
+    /// `Capabilities` - Capabilities of the sandbox, which can be extended by `allow_*`
+    pub fn sandboxed() -> Self {
+        Self {
+            read_roots: Some(Vec::new()),
+            executables: Some(Vec::new()),
+            http_hosts: Some(Vec::new()),
+            network: false,
+            lua_stdlib: StdLib::COROUTINE
+                | StdLib::TABLE
+                | StdLib::STRING
+                | StdLib::UTF8
+                | StdLib::MATH,
+        }
+    }
+
+    /// Allow reading files under the directory (by `include` and `render`)
+    ///
+    /// Once a directory is allowed, files outside of the allowed directories cannot be read.
+    ///
+    /// # Arguments
+    ///
+    /// `root: P` - Directory
+    pub fn allow_read<P>(mut self, root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.read_roots
+            .get_or_insert_with(Vec::new)
+            .push(root.into());
+        self
+    }
+
+    /// Allow executing the program (by `exec`)
+    ///
+    /// The program must be given to `exec` exactly as allowed (e.g. `git` does not allow
+    /// `/usr/bin/git`). Once a program is allowed, the other programs cannot be executed.
+    ///
+    /// # Arguments
+    ///
+    /// `program: &str` - Name or path of the program
+    pub fn allow_executable(mut self, program: &str) -> Self {
+        self.executables
+            .get_or_insert_with(Vec::new)
+            .push(program.to_string());
+        self
+    }
+
+    /// Allow HTTP requests to the host, and turn on the network access
+    ///
+    /// Once a host is allowed, the other hosts cannot be accessed, and redirects to them are
+    /// refused.
+    ///
+    /// # Arguments
+    ///
+    /// `host: &str` - Host name (e.g. `httpbin.org`)
+    pub fn allow_http_host(mut self, host: &str) -> Self {
+        self.http_hosts
+            .get_or_insert_with(Vec::new)
+            .push(host.to_string());
+        self.network = true;
+        self
+    }
+
+    /// Turn on or off the network access
+    ///
+    /// # Arguments
+    ///
+    /// `network: bool` - `true` to allow the network access
+    pub fn allow_network(mut self, network: bool) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Set the Lua standard libraries to load, unsafe libraries (e.g. `debug`) are ignored
+    ///
+    /// The `coroutine` library is always loaded, because the async builtins yield by it.
+    ///
+    /// # Arguments
+    ///
+    /// `lua_stdlib: StdLib` - Libraries (e.g. `StdLib::STRING | StdLib::MATH`)
+    pub fn lua_stdlib(mut self, lua_stdlib: StdLib) -> Self {
+        self.lua_stdlib = lua_stdlib & StdLib::ALL_SAFE;
+        self
+    }
+
+    /// Create a Lua state with the allowed standard libraries
+    pub(crate) fn create_lua(&self) -> Lua {
+        let lua = Lua::new_with(self.lua_stdlib | StdLib::COROUTINE, LuaOptions::new())
+            .expect("unsafe libraries are not allowed");
+
+        // base library can read files without `io`
+        if self.read_roots.is_some() {
+            let globals = lua.globals();
+            let _ = globals.raw_set("dofile", mlua::Value::Nil);
+            let _ = globals.raw_set("loadfile", mlua::Value::Nil);
+        }
+
+        lua
+    }
+
+    pub(crate) fn check_read(&self, path: &Path) -> Result<(), String> {
+        let Some(roots) = &self.read_roots else {
+            return Ok(());
+        };
+
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let allowed = roots
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .any(|root| canonical.starts_with(root));
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("reading {} is not allowed", path.display()))
+        }
+    }
+
+    pub(crate) fn check_executable(&self, program: &str) -> Result<(), String> {
+        match &self.executables {
+            Some(executables) if !executables.iter().any(|e| e == program) => {
+                Err(format!("executing {} is not allowed", program))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_http(&self, url: &str) -> Result<(), String> {
+        if !self.network {
+            return Err("network access is not allowed".to_string());
+        }
+
+        let Some(hosts) = &self.http_hosts else {
+            return Ok(());
+        };
+
+        match host_of(url) {
+            Some(host) if hosts.iter().any(|h| h.eq_ignore_ascii_case(&host)) => Ok(()),
+            Some(host) => Err(format!("access to {} is not allowed", host)),
+            None => Err(format!("access to {} is not allowed", url)),
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            read_roots: None,
+            executables: None,
+            http_hosts: None,
+            network: true,
+            lua_stdlib: StdLib::ALL_SAFE,
+        }
+    }
+}
+
+/// Get host of URL (e.g. `httpbin.org` of `https://user@httpbin.org:443/get`)
+///
+/// The URL is parsed in the same way as the HTTP client, so that the host is the one which is
+/// actually accessed (e.g. `a.example` of `http://a.example\@b.example/`).
+fn host_of(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+
+    match url.host()? {
+        Host::Domain(domain) => Some(domain.to_string()),
+        Host::Ipv4(address) => Some(address.to_string()),
+        Host::Ipv6(address) => Some(address.to_string()),
+    }
+}
+
+/// Check a capability of the Lua state, raise an error of the builtin if it is denied
+///
+/// # Arguments
+///
+/// * `lua` - The Lua context, which has capabilities set by `Builtins::init`
+/// * `name` - The name of the builtin
+/// * `check` - The check of the capability
+pub(crate) fn check_capability<F>(lua: &Lua, name: &str, check: F) -> mlua::Result<()>
+where
+    F: FnOnce(&Capabilities) -> Result<(), String>,
+{
+    let result = match lua.app_data_ref::<Capabilities>() {
+        Some(capabilities) => check(&capabilities),
+        None => Ok(()),
+    };
+
+    result.map_err(|message| builtin_error(name, message))
+}
+
+/// Get capabilities of the Lua state
+pub(crate) fn capabilities_of(lua: &Lua) -> Capabilities {
+    lua.app_data_ref::<Capabilities>()
+        .map(|capabilities| capabilities.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_read() {
+        let capabilities = Capabilities::sandboxed().allow_read("src");
+
+        assert!(capabilities.check_read(Path::new("src/lib.rs")).is_ok());
+        assert!(
+            capabilities
+                .check_read(Path::new("./src/../src/lib.rs"))
+                .is_ok()
+        );
+        assert_eq!(
+            Err("reading src/../Cargo.toml is not allowed".to_string()),
+            capabilities.check_read(Path::new("src/../Cargo.toml"))
+        );
+        assert!(
+            Capabilities::default()
+                .check_read(Path::new("Cargo.toml"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_check_executable() {
+        let capabilities = Capabilities::sandboxed().allow_executable("rustc");
+
+        assert!(capabilities.check_executable("rustc").is_ok());
+        assert_eq!(
+            Err("executing /usr/bin/rustc is not allowed".to_string()),
+            capabilities.check_executable("/usr/bin/rustc")
+        );
+    }
+
+    #[test]
+    fn test_check_http() {
+        assert_eq!(
+            Err("network access is not allowed".to_string()),
+            Capabilities::sandboxed().check_http("https://httpbin.org/get")
+        );
+
+        let capabilities = Capabilities::sandboxed().allow_http_host("httpbin.org");
+        assert!(capabilities.check_http("https://httpbin.org/get").is_ok());
+        assert!(
+            capabilities
+                .check_http("http://user@HTTPBIN.org:80?a=b")
+                .is_ok()
+        );
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            capabilities.check_http("https://example.com/?httpbin.org")
+        );
+
+        // a backslash ends the host, as the HTTP client does
+        assert!(
+            capabilities
+                .check_http(r"http://httpbin.org\@example.com/")
+                .is_ok()
+        );
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            capabilities.check_http(r"http://example.com\@httpbin.org/")
+        );
+
+        // user information is not the host
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            capabilities.check_http("http://httpbin.org@example.com/")
+        );
+        assert_eq!(
+            Err("access to example.com is not allowed".to_string()),
+            capabilities.check_http("http://httpbin.org:80@example.com/")
+        );
+
+        assert!(capabilities.check_http("file:///httpbin.org").is_err());
+        assert!(capabilities.check_http("httpbin.org").is_err());
+
+        let capabilities = Capabilities::sandboxed().allow_http_host("::1");
+        assert!(capabilities.check_http("http://[::1]:8080/").is_ok());
+    }
+
+    #[test]
+    fn test_create_lua() {
+        let lua = Capabilities::sandboxed().create_lua();
+        let denied = lua
+            .load("return os == nil and io == nil and require == nil and dofile == nil")
+            .eval::<bool>()
+            .unwrap();
+        assert!(denied);
+
+        let lua = Capabilities::default().create_lua();
+        assert!(lua.load("return os.time() > 0").eval::<bool>().unwrap());
+    }
+}
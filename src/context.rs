@@ -0,0 +1,478 @@
+use std::fs::read_to_string;
+use std::io::{self, Read};
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::map::Entry;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::{MllError, MllValue, RenderContext};
+
+/// Format of a context file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextFormat {
+    /// JSON object (`.json`)
+    Json,
+    /// YAML mapping (`.yaml` or `.yml`)
+    #[cfg(feature = "yaml")]
+    Yaml,
+    /// TOML table (`.toml`)
+    #[cfg(feature = "toml")]
+    Toml,
+    /// `KEY=VALUE` lines (`.env`, `.env.local` or `production.env`), all values are strings
+    Dotenv,
+}
+
+impl ContextFormat {
+    /// Guess format from the name of a file
+    ///
+    /// # Arguments
+    ///
+    /// `path: &Path` - Path of the file
+    ///
+    /// # Returns
+    ///
+    /// `Option<ContextFormat>` - Format, `None` if the extension is unknown
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        if file_name == ".env" || file_name.starts_with(".env.") {
+            return Some(ContextFormat::Dotenv);
+        }
+
+        match path.extension()?.to_str()? {
+            "json" => Some(ContextFormat::Json),
+            #[cfg(feature = "yaml")]
+            "yaml" | "yml" => Some(ContextFormat::Yaml),
+            #[cfg(feature = "toml")]
+            "toml" => Some(ContextFormat::Toml),
+            "env" => Some(ContextFormat::Dotenv),
+            _ => None,
+        }
+    }
+}
+
+/// Variables loaded from files, in layers where later ones override earlier ones
+///
+/// Tables (objects) of the layers are merged key by key, and any other value replaces the value
+/// of the earlier layers. The context can be passed to `Mll::render` as it is, or set into the
+/// Lua globals of the session by `Mll::set_context`, so that the pre-process script can use it.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::{Context, ContextFormat, Mll};
+///
+/// let mut context = Context::new();
+/// let json = r#"{"name": "hoge", "db": {"host": "localhost", "port": 5432}}"#;
+/// context.load_str(json, ContextFormat::Json).unwrap();
+/// context.load_str("name=fuga", ContextFormat::Dotenv).unwrap();
+/// context.load_str(r#"{"db": {"port": 3306}}"#, ContextFormat::Json).unwrap();
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("{{name}} {{db.host}}:{{db.port}}".to_string());
+/// assert_eq!("fuga localhost:3306", mll.render(&context).unwrap());
+///
+/// mll.set_context(&context).unwrap();
+/// mll.set_template("{{ url }}".to_string());
+/// mll.set_pre_process_script("url = db.host .. ':' .. db.port".to_string());
+/// assert_eq!("localhost:3306", mll.render_lua_globals().unwrap());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Context {
+    values: Map<String, JsonValue>,
+}
+
+impl Context {
+    /// Create an empty context
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a file on top of the context, the format is guessed from its name
+    ///
+    /// # Arguments
+    ///
+    /// `path: &str` - Path of the file (e.g. `config.yaml` or `.env`)
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the file cannot be read or parsed, or its format is
+    /// unknown
+    pub fn load_file(&mut self, path: &str) -> Result<(), MllError> {
+        let format =
+            ContextFormat::from_path(Path::new(path)).ok_or_else(|| MllError::InvalidContext {
+                path: path.to_string(),
+                message: "unknown format".to_string(),
+            })?;
+
+        self.load_file_as(path, format)
+    }
+
+    /// Load a file in the format on top of the context
+    ///
+    /// # Arguments
+    ///
+    /// `path: &str` - Path of the file
+    ///
+    /// `format: ContextFormat` - Format of the file
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the file cannot be read or parsed
+    pub fn load_file_as(&mut self, path: &str, format: ContextFormat) -> Result<(), MllError> {
+        let text = read_to_string(path).map_err(|e| MllError::Io {
+            path: path.to_string(),
+            source: e,
+        })?;
+
+        self.load(path, &text, format)
+    }
+
+    /// Load the standard input on top of the context
+    ///
+    /// # Arguments
+    ///
+    /// `format: ContextFormat` - Format of the input
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the input cannot be read or parsed
+    pub fn load_stdin(&mut self, format: ContextFormat) -> Result<(), MllError> {
+        let mut text = String::new();
+        io::stdin()
+            .lock()
+            .read_to_string(&mut text)
+            .map_err(|e| MllError::Io {
+                path: "<stdin>".to_string(),
+                source: e,
+            })?;
+
+        self.load("<stdin>", &text, format)
+    }
+
+    /// Load a string on top of the context
+    ///
+    /// # Arguments
+    ///
+    /// `text: &str` - Content of a file
+    ///
+    /// `format: ContextFormat` - Format of the content
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the content cannot be parsed
+    pub fn load_str(&mut self, text: &str, format: ContextFormat) -> Result<(), MllError> {
+        self.load("<string>", text, format)
+    }
+
+    fn load(&mut self, path: &str, text: &str, format: ContextFormat) -> Result<(), MllError> {
+        let invalid = |message: String| MllError::InvalidContext {
+            path: path.to_string(),
+            message,
+        };
+
+        let value = match format {
+            ContextFormat::Json => {
+                serde_json::from_str::<JsonValue>(text).map_err(|e| invalid(e.to_string()))?
+            }
+            #[cfg(feature = "yaml")]
+            ContextFormat::Yaml => {
+                serde_yaml::from_str::<JsonValue>(text).map_err(|e| invalid(e.to_string()))?
+            }
+            #[cfg(feature = "toml")]
+            ContextFormat::Toml => {
+                let table = text
+                    .parse::<toml::Table>()
+                    .map_err(|e| invalid(e.to_string()))?;
+                toml_to_json(toml::Value::Table(table))
+            }
+            ContextFormat::Dotenv => parse_dotenv(text).map_err(invalid)?,
+        };
+
+        match value {
+            JsonValue::Object(values) => {
+                merge_objects(&mut self.values, values);
+                Ok(())
+            }
+            // an empty YAML document
+            JsonValue::Null => Ok(()),
+            _ => Err(invalid("top-level value is not a table".to_string())),
+        }
+    }
+
+    /// Get a variable
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the variable
+    ///
+    /// # Returns
+    ///
+    /// `Option<&JsonValue>` - Value, `None` if it is not loaded
+    pub fn get(&self, name: &str) -> Option<&JsonValue> {
+        self.values.get(name)
+    }
+
+    /// Get the variables as a JSON object
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Object(self.values.clone())
+    }
+}
+
+impl RenderContext for Context {
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        match self.values.get(name) {
+            Some(JsonValue::Null) | None => None,
+            Some(value) => Some(value.clone().into()),
+        }
+    }
+
+    fn get_this(&self) -> Option<MllValue> {
+        Some(self.to_json().into())
+    }
+}
+
+impl From<Context> for JsonValue {
+    fn from(context: Context) -> Self {
+        JsonValue::Object(context.values)
+    }
+}
+
+/// Merge a layer into the values, tables are merged key by key
+fn merge_objects(values: &mut Map<String, JsonValue>, layer: Map<String, JsonValue>) {
+    for (key, value) in layer {
+        match values.entry(key) {
+            Entry::Occupied(mut entry) => match (entry.get_mut(), value) {
+                (JsonValue::Object(values), JsonValue::Object(layer)) => {
+                    merge_objects(values, layer);
+                }
+                (current, value) => *current = value,
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+        }
+    }
+}
+
+/// Convert a TOML value into JSON, dates and times become strings (e.g. `2025-01-01`)
+#[cfg(feature = "toml")]
+fn toml_to_json(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(s) => JsonValue::String(s),
+        toml::Value::Integer(i) => JsonValue::from(i),
+        toml::Value::Float(f) => JsonValue::from(f),
+        toml::Value::Boolean(b) => JsonValue::Bool(b),
+        toml::Value::Datetime(datetime) => JsonValue::String(datetime.to_string()),
+        toml::Value::Array(array) => array.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(table) => table
+            .into_iter()
+            .map(|(key, value)| (key, toml_to_json(value)))
+            .collect(),
+    }
+}
+
+/// Parse `KEY=VALUE` lines into an object of strings
+///
+/// Empty lines and comments (`# ...`) are skipped, and `export` before a key is ignored.
+/// Values in double quotes can have escapes (`\n`, `\t`, `\"` and `\\`), values in single
+/// quotes are taken as they are, and unquoted values end at a comment.
+fn parse_dotenv(text: &str) -> Result<JsonValue, String> {
+    let mut values = Map::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY=VALUE", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {}: empty key", i + 1));
+        }
+
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            let end = closing_quote(quoted, '"')
+                .ok_or_else(|| format!("line {}: unterminated string", i + 1))?;
+            unescape(&quoted[..end])
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            let end = closing_quote(quoted, '\'')
+                .ok_or_else(|| format!("line {}: unterminated string", i + 1))?;
+            quoted[..end].to_string()
+        } else {
+            let end = value.find(" #").unwrap_or(value.len());
+            value[..end].trim_end().to_string()
+        };
+
+        values.insert(key.to_string(), JsonValue::String(value));
+    }
+
+    Ok(JsonValue::Object(values))
+}
+
+/// Get index of the quote which closes a string, skipping escaped quotes in double quotes
+fn closing_quote(text: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            c if c == quote && !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_layers() {
+        let mut context = Context::new();
+        context
+            .load_str(
+                r#"{"name": "hoge", "db": {"host": "localhost", "port": 5432}, "tags": [1, 2]}"#,
+                ContextFormat::Json,
+            )
+            .unwrap();
+        context
+            .load_str(
+                r#"{"db": {"port": 3306, "user": "root"}, "tags": [3]}"#,
+                ContextFormat::Json,
+            )
+            .unwrap();
+
+        assert_eq!(
+            json!({
+                "name": "hoge",
+                "db": {"host": "localhost", "port": 3306, "user": "root"},
+                "tags": [3],
+            }),
+            context.to_json()
+        );
+
+        let e = context.load_str("[1, 2]", ContextFormat::Json).unwrap_err();
+        assert_eq!(
+            "invalid context <string>: top-level value is not a table",
+            e.to_string()
+        );
+        assert!(context.load_str("{", ContextFormat::Json).is_err());
+        assert_eq!(Some(&json!("hoge")), context.get("name"));
+    }
+
+    #[test]
+    fn test_dotenv() {
+        let text = r#"
+            # comment
+            NAME=hoge # comment
+            export PATH_LIST = /bin:/usr/bin
+            MESSAGE="Hello,\n\"world\"" # comment
+            RAW='a\nb # c'
+            EMPTY=
+        "#;
+
+        assert_eq!(
+            Ok(json!({
+                "NAME": "hoge",
+                "PATH_LIST": "/bin:/usr/bin",
+                "MESSAGE": "Hello,\n\"world\"",
+                "RAW": "a\\nb # c",
+                "EMPTY": "",
+            })),
+            parse_dotenv(text)
+        );
+        assert_eq!(
+            Err("line 2: expected KEY=VALUE".to_string()),
+            parse_dotenv("A=1\nB")
+        );
+        assert_eq!(
+            Err("line 1: unterminated string".to_string()),
+            parse_dotenv("A=\"1")
+        );
+    }
+
+    #[test]
+    fn test_from_path() {
+        let format = |path: &str| ContextFormat::from_path(Path::new(path));
+
+        assert_eq!(Some(ContextFormat::Json), format("config/context.json"));
+        assert_eq!(Some(ContextFormat::Dotenv), format(".env"));
+        assert_eq!(Some(ContextFormat::Dotenv), format("app/.env.local"));
+        assert_eq!(Some(ContextFormat::Dotenv), format("production.env"));
+        assert_eq!(None, format("context.txt"));
+
+        let e = Context::new().load_file("context.txt").unwrap_err();
+        assert_eq!("invalid context context.txt: unknown format", e.to_string());
+        let e = Context::new().load_file("not_found.json").unwrap_err();
+        assert!(matches!(e, MllError::Io { .. }));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_yaml() {
+        let mut context = Context::new();
+        context
+            .load_str("name: hoge\nitems:\n  - 1\n  - fuga\n", ContextFormat::Yaml)
+            .unwrap();
+        context.load_str("", ContextFormat::Yaml).unwrap();
+
+        assert_eq!(
+            json!({"name": "hoge", "items": [1, "fuga"]}),
+            context.to_json()
+        );
+        assert_eq!(
+            Some(ContextFormat::Yaml),
+            ContextFormat::from_path(Path::new("a.yml"))
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml() {
+        let mut context = Context::new();
+        context.load_file("Cargo.toml").unwrap();
+        context
+            .load_str(
+                "[package]\nversion = \"9.9.9\"\nreleased = 2025-01-01",
+                ContextFormat::Toml,
+            )
+            .unwrap();
+
+        let package = context.get("package").unwrap();
+        assert_eq!("libmll", package["name"]);
+        assert_eq!("9.9.9", package["version"]);
+        assert_eq!("2025-01-01", package["released"]);
+    }
+}
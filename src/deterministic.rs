@@ -0,0 +1,243 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mlua::{Function, Lua, Table};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use uuid::Uuid;
+
+/// Seed and fixed time which make renders reproducible
+///
+/// With a deterministic mode, the random builtins (`mll.random.*`), `math.random`, and the
+/// clock (`mll.datetime.now`, `os.time`, `os.date` and `os.clock`) do not depend on the
+/// environment, and dates are in UTC instead of the local timezone. Renders of a new session
+/// (or after `reset`) with the same inputs produce byte-identical output.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use libmll::{Deterministic, Mll};
+///
+/// let render = || {
+///     let mut mll = Mll::new();
+///     mll.set_deterministic(Some(Deterministic::new(
+///         42,
+///         UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+///     )));
+///     mll.set_template("{{= mll.random.string(8) }} {{= os.time() }}".to_string());
+///     mll.render_with_lua("").unwrap()
+/// };
+///
+/// assert_eq!(render(), render());
+/// assert!(render().ends_with(" 1700000000"));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deterministic {
+    seed: u64,
+    now: SystemTime,
+}
+
+impl Deterministic {
+    /// Create a deterministic mode
+    ///
+    /// # Arguments
+    ///
+    /// `seed: u64` - Seed of the random numbers
+    ///
+    /// `now: SystemTime` - Time returned as the current time
+    pub fn new(seed: u64, now: SystemTime) -> Self {
+        Self { seed, now }
+    }
+
+    /// Get seed of the random numbers
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Get time returned as the current time
+    pub fn now(&self) -> SystemTime {
+        self.now
+    }
+
+    /// Get the deterministic mode of an item of a batch, whose seed is derived from the seed and
+    /// the index of the item
+    pub(crate) fn for_item(&self, index: usize) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(index as u64);
+
+        Self {
+            seed: rng.next_u64(),
+            now: self.now,
+        }
+    }
+
+    /// Seed the Lua state and freeze its clock
+    pub(crate) fn apply(&self, lua: &Lua) -> mlua::Result<()> {
+        self.reseed(lua)?;
+
+        if let Ok(os) = lua.globals().get::<Table>("os") {
+            let now = unix_time(self.now);
+
+            let time = os.get::<Function>("time")?;
+            let frozen_time = lua.create_function(move |_, table: Option<Table>| match table {
+                Some(table) => time.call::<mlua::Value>(table),
+                None => Ok(mlua::Value::Integer(now)),
+            })?;
+            os.set("time", frozen_time)?;
+
+            // in UTC, so that the date does not depend on the timezone of the machine
+            let date = os.get::<Function>("date")?;
+            let frozen_date = lua.create_function(
+                move |lua, (format, time): (Option<mlua::String>, Option<i64>)| {
+                    let format = match format {
+                        Some(format) if format.as_bytes().starts_with(b"!") => format,
+                        Some(format) => {
+                            lua.create_string([b"!".as_slice(), &format.as_bytes()[..]].concat())?
+                        }
+                        None => lua.create_string("!%c")?,
+                    };
+                    date.call::<mlua::Value>((format, time.unwrap_or(now)))
+                },
+            )?;
+            os.set("date", frozen_date)?;
+
+            os.set("clock", lua.create_function(|_, ()| Ok(0.0))?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Start the random numbers of the Lua state from the seed again
+    pub(crate) fn reseed(&self, lua: &Lua) -> mlua::Result<()> {
+        lua.set_app_data(Entropy {
+            rng: ChaCha8Rng::seed_from_u64(self.seed),
+            now: self.now,
+        });
+
+        if let Ok(math) = lua.globals().get::<Table>("math") {
+            math.get::<Function>("randomseed")?
+                .call::<()>(self.seed as i64)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Random numbers and clock of a Lua state in the deterministic mode
+///
+/// ChaCha8 is used instead of `StdRng`, whose algorithm may change between versions of `rand`,
+/// so that the same seed produces the same output with any version of this crate.
+struct Entropy {
+    rng: ChaCha8Rng,
+    now: SystemTime,
+}
+
+/// Run `f` with the random number generator of the Lua state, or of the thread if the state is
+/// not deterministic
+pub(crate) fn with_rng<R, F>(lua: &Lua, f: F) -> R
+where
+    F: FnOnce(&mut dyn RngCore) -> R,
+{
+    match lua.app_data_mut::<Entropy>() {
+        Some(mut entropy) => f(&mut entropy.rng),
+        None => f(&mut rand::rng()),
+    }
+}
+
+/// Get the deterministic mode of a session nested in the Lua state (e.g. by
+/// `mll.template.render`), seeded by the random number generator of the state, or `None` if the
+/// state is not deterministic
+pub(crate) fn nested(lua: &Lua) -> Option<Deterministic> {
+    let mut entropy = lua.app_data_mut::<Entropy>()?;
+    let seed = entropy.rng.next_u64();
+
+    Some(Deterministic::new(seed, entropy.now))
+}
+
+/// Get the current time of the Lua state
+pub(crate) fn now(lua: &Lua) -> SystemTime {
+    lua.app_data_ref::<Entropy>()
+        .map_or_else(SystemTime::now, |entropy| entropy.now)
+}
+
+/// Check if the Lua state is deterministic
+pub(crate) fn is_deterministic(lua: &Lua) -> bool {
+    lua.app_data_ref::<Entropy>().is_some()
+}
+
+/// Make a random (version 4) UUID by the random number generator of the Lua state
+pub(crate) fn new_uuid(lua: &Lua) -> Uuid {
+    let mut bytes = [0u8; 16];
+    with_rng(lua, |rng| rng.fill_bytes(&mut bytes));
+
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// Get seconds since the Unix epoch (negative before the epoch)
+fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let deterministic = Deterministic::new(1, UNIX_EPOCH + Duration::from_secs(86400));
+
+        let sample = || {
+            let lua = Lua::new();
+            deterministic.apply(&lua).unwrap();
+
+            let random = with_rng(&lua, |rng| rng.next_u64());
+            let values = lua
+                .load("return math.random(1, 1000000), os.time(), os.date('%Y-%m-%d %H')")
+                .eval::<(i64, i64, String)>()
+                .unwrap();
+
+            (random, new_uuid(&lua), values)
+        };
+
+        let (random, uuid, (math_random, time, date)) = sample();
+        assert_eq!((random, uuid, (math_random, time, date.clone())), sample());
+        assert_eq!(4, uuid.get_version_num());
+        assert_eq!((86400, "1970-01-02 00"), (time, date.as_str()));
+
+        let lua = Lua::new();
+        assert_ne!(new_uuid(&lua), new_uuid(&lua));
+        assert!(now(&lua) > UNIX_EPOCH + Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn test_fixed_values() {
+        let deterministic = Deterministic::new(1, UNIX_EPOCH);
+
+        let lua = Lua::new();
+        deterministic.apply(&lua).unwrap();
+        assert_eq!(
+            (7424550030962593201, 1482817706323250795),
+            with_rng(&lua, |rng| (rng.next_u64(), rng.next_u64()))
+        );
+
+        let lua = Lua::new();
+        deterministic.apply(&lua).unwrap();
+        assert_eq!(
+            "b10da48c-ea4c-4967-ab8e-0efcd8069414",
+            new_uuid(&lua).to_string()
+        );
+
+        assert_eq!(
+            (15715005604373573095, 16166618085559792950),
+            (
+                deterministic.for_item(1).seed(),
+                deterministic.for_item(2).seed()
+            )
+        );
+    }
+}
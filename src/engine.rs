@@ -0,0 +1,498 @@
+use std::collections::HashSet;
+
+use mlua::{FromLua, Lua, LuaSerdeExt, Table};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+
+use crate::builtin::Builtins;
+use crate::limits::call_limited;
+use crate::report::init_records;
+use crate::traceback::{call_traced, load_named};
+use crate::utils::do_blocking;
+use crate::{BuiltinRegistry, Capabilities, Deterministic, MllError};
+
+/// Lua state with all builtins, shared by the scripts and the renders of a session
+///
+/// Scripts are run incrementally: globals defined by a script are visible to the following
+/// scripts and to the expressions of the template, until `reset` is called. What the scripts
+/// can do is limited by `Capabilities`.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::Engine;
+///
+/// let mut engine = Engine::new();
+/// engine.run("x = 1").unwrap();
+/// engine.run("x = x + mll.random.int(1, 2)").unwrap();
+/// assert_eq!(2, engine.eval::<i64>("x").unwrap());
+///
+/// engine.reset();
+/// assert_eq!(None, engine.eval::<Option<i64>>("x").unwrap());
+/// ```
+pub struct Engine {
+    lua: Lua,
+    capabilities: Capabilities,
+    builtins: BuiltinRegistry,
+    deterministic: Option<Deterministic>,
+    /// Globals of the standard libraries and the builtins, which are not variables
+    initial_globals: HashSet<String>,
+}
+
+impl Engine {
+    /// Create a Lua state with all builtins
+    ///
+    /// # Returns
+    ///
+    /// `Engine` - New engine
+    pub fn new() -> Self {
+        Self::with_capabilities(Capabilities::default())
+    }
+
+    /// Create a Lua state with the allowed standard libraries and builtins
+    ///
+    /// # Arguments
+    ///
+    /// `capabilities: Capabilities` - What the scripts are allowed to do
+    ///
+    /// # Returns
+    ///
+    /// `Engine` - New engine
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::{Capabilities, Engine};
+    ///
+    /// let engine = Engine::with_capabilities(Capabilities::sandboxed());
+    /// assert!(engine.run("os.remove('Cargo.toml')").is_err());
+    /// ```
+    pub fn with_capabilities(capabilities: Capabilities) -> Self {
+        let builtins = BuiltinRegistry::new();
+        let lua = Self::create_lua(&capabilities, &builtins, None).unwrap();
+
+        Self {
+            initial_globals: global_names(&lua),
+            lua,
+            capabilities,
+            builtins,
+            deterministic: None,
+        }
+    }
+
+    fn create_lua(
+        capabilities: &Capabilities,
+        builtins: &BuiltinRegistry,
+        deterministic: Option<&Deterministic>,
+    ) -> mlua::Result<Lua> {
+        let lua = capabilities.create_lua();
+        init_records(&lua);
+        let _ = Builtins::init(&lua, capabilities);
+        builtins.apply(&lua)?;
+        if let Some(deterministic) = deterministic {
+            let _ = deterministic.apply(&lua);
+        }
+
+        Ok(lua)
+    }
+
+    /// Run Lua script in the state
+    ///
+    /// The chunk is named `script` in the errors (see `run_named`).
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script, globals set before an error are kept
+    pub fn run(&self, script: &str) -> Result<(), MllError> {
+        do_blocking(self.run_async(script))?
+    }
+
+    /// Run Lua script in the state as a named chunk
+    ///
+    /// Errors of the script are located by the name (see `MllError::location`).
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the chunk (e.g. the path of the script)
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script, globals set before an error are kept
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Engine;
+    ///
+    /// let engine = Engine::new();
+    /// let e = engine.run_named("hoge.lua", "x = 1\nx = x .. {}").unwrap_err();
+    ///
+    /// assert!(e.to_string().contains("hoge.lua:2:"));
+    /// assert_eq!(2, e.location().unwrap().line());
+    /// assert_eq!(Some("x = x .. {}"), e.location().unwrap().source_line());
+    /// ```
+    pub fn run_named(&self, name: &str, script: &str) -> Result<(), MllError> {
+        do_blocking(self.run_named_async(name, script))?
+    }
+
+    /// Run Lua script in the state on the runtime of the caller
+    ///
+    /// Async builtins called by the script (e.g. `mll.http.get`) wait without blocking the
+    /// runtime. The future is not `Send`, because the Lua state is not.
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script, globals set before an error are kept
+    pub async fn run_async(&self, script: &str) -> Result<(), MllError> {
+        self.run_named_async("script", script).await
+    }
+
+    /// Run Lua script in the state as a named chunk on the runtime of the caller
+    ///
+    /// See `run_async` and `run_named`.
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the chunk (e.g. the path of the script)
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script, globals set before an error are kept
+    pub async fn run_named_async(&self, name: &str, script: &str) -> Result<(), MllError> {
+        let function = load_named(&self.lua, name, script)?;
+        call_traced(&self.lua, function, ()).await
+    }
+
+    /// Evaluate Lua expression in the state
+    ///
+    /// # Arguments
+    ///
+    /// `expression: &str` - Lua expression
+    ///
+    /// # Returns
+    ///
+    /// `Result<T, MllError>` - Value of the expression
+    pub fn eval<T>(&self, expression: &str) -> Result<T, MllError>
+    where
+        T: FromLua,
+    {
+        let function = self
+            .lua
+            .load(format!("return {}", expression))
+            .into_function()?;
+        let value = do_blocking(call_limited(&self.lua, function, ()))??;
+
+        Ok(value)
+    }
+
+    /// Set a Lua global converted from a Rust value
+    ///
+    /// Integers, floats and strings are kept as they are, `None` and `()` become `nil` (which is
+    /// falsy and rendered as a missing value), and sequences keep their items including `null`.
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the global
+    ///
+    /// `value: &T` - Value
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the value cannot be converted
+    pub fn set_variable<T>(&self, name: &str, value: &T) -> Result<(), MllError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = self.lua.to_value(value)?;
+        self.lua.globals().set(name, null_to_nil(value))?;
+
+        Ok(())
+    }
+
+    /// Set each field of a Rust value (e.g. a struct or a map) as a Lua global
+    ///
+    /// Fields are converted in the same way as `set_variable`.
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Value which is converted into a Lua table
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the value is not converted into a table
+    pub fn set_context<T>(&self, context: &T) -> Result<(), MllError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = self.lua.to_value(context)?;
+        let table = Table::from_lua(value, &self.lua)?;
+        let globals = self.lua.globals();
+
+        for pair in table.pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            globals.set(key, null_to_nil(value))?;
+        }
+
+        Ok(())
+    }
+
+    /// Get a Lua global converted into a Rust value
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the global
+    ///
+    /// # Returns
+    ///
+    /// `Result<T, MllError>` - Value, error if the global cannot be converted (e.g. `nil` for a
+    /// type which is not `Option`)
+    pub fn get_variable<T>(&self, name: &str) -> Result<T, MllError>
+    where
+        T: DeserializeOwned,
+    {
+        let value = self.lua.globals().get::<mlua::Value>(name)?;
+        let value = self.lua.from_value(value)?;
+
+        Ok(value)
+    }
+
+    /// Get Lua globals defined by the scripts (and `set_variable`) as JSON
+    ///
+    /// Globals of the standard libraries and the builtins are not included, and values which
+    /// cannot be represented in JSON (e.g. functions) are skipped or converted into `null`.
+    ///
+    /// # Returns
+    ///
+    /// `Result<JsonValue, MllError>` - JSON object of the globals
+    pub fn globals_as_json(&self) -> Result<JsonValue, MllError> {
+        let mut globals = serde_json::Map::new();
+
+        for pair in self.lua.globals().pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            let mlua::Value::String(key) = key else {
+                continue;
+            };
+            let key = key.to_string_lossy();
+            if self.initial_globals.contains(&key) || is_unsupported(&value) {
+                continue;
+            }
+
+            let json = serde_json::to_value(value.to_serializable().deny_unsupported_types(false))
+                .map_err(|e| mlua::Error::SerializeError(e.to_string()))?;
+            globals.insert(key, json);
+        }
+
+        Ok(JsonValue::Object(globals))
+    }
+
+    /// Discard the state, and start again with a new Lua state with the same capabilities
+    pub fn reset(&mut self) {
+        // the builtins are already set into a state by `set_builtins`
+        self.lua = Self::create_lua(
+            &self.capabilities,
+            &self.builtins,
+            self.deterministic.as_ref(),
+        )
+        .unwrap();
+        self.initial_globals = global_names(&self.lua);
+    }
+
+    /// Get builtins added, replaced or removed on top of the standard builtins
+    pub fn builtins(&self) -> &BuiltinRegistry {
+        &self.builtins
+    }
+
+    /// Set builtins added, replaced or removed on top of the standard builtins, the state is
+    /// reset so that no builtin of the previous registry is left
+    ///
+    /// # Arguments
+    ///
+    /// `builtins: BuiltinRegistry` - Builtins
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the builtins can not be set, the state is kept then
+    pub fn set_builtins(&mut self, builtins: BuiltinRegistry) -> Result<(), MllError> {
+        let lua = Self::create_lua(&self.capabilities, &builtins, self.deterministic.as_ref())?;
+
+        self.initial_globals = global_names(&lua);
+        self.lua = lua;
+        self.builtins = builtins;
+
+        Ok(())
+    }
+
+    /// Get the deterministic mode, `None` if it is off
+    pub fn deterministic(&self) -> Option<&Deterministic> {
+        self.deterministic.as_ref()
+    }
+
+    /// Turn on or off the deterministic mode, the state is reset
+    ///
+    /// # Arguments
+    ///
+    /// `deterministic: Option<Deterministic>` - Seed and time, `None` to turn it off
+    pub fn set_deterministic(&mut self, deterministic: Option<Deterministic>) {
+        self.deterministic = deterministic;
+        self.reset();
+    }
+
+    /// Get what the scripts are allowed to do
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Get the Lua state
+    pub fn lua(&self) -> &Lua {
+        &self.lua
+    }
+
+    /// Get global table of the Lua state
+    pub fn globals(&self) -> Table {
+        self.lua.globals()
+    }
+}
+
+/// Convert `null` (`None` or `()` of Rust) into `nil`, so that it is falsy in Lua
+fn null_to_nil(value: mlua::Value) -> mlua::Value {
+    match value {
+        mlua::Value::LightUserData(ud) if ud.0.is_null() => mlua::Value::Nil,
+        value => value,
+    }
+}
+
+/// Get names of all globals of the Lua state
+fn global_names(lua: &Lua) -> HashSet<String> {
+    lua.globals()
+        .pairs::<mlua::Value, mlua::Value>()
+        .filter_map(|pair| match pair {
+            Ok((mlua::Value::String(key), _)) => Some(key.to_string_lossy()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the value is not data (e.g. a function defined by a script)
+fn is_unsupported(value: &mlua::Value) -> bool {
+    matches!(
+        value,
+        mlua::Value::Function(_) | mlua::Value::Thread(_) | mlua::Value::UserData(_)
+    )
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_scripts() {
+        let mut engine = Engine::new();
+
+        engine.run("items = {}").unwrap();
+        engine.run("table.insert(items, 'hoge')").unwrap();
+        assert!(
+            engine
+                .run("table.insert(items, 'fuga'); error('x')")
+                .is_err()
+        );
+        assert_eq!(2, engine.eval::<i64>("#items").unwrap());
+
+        let e = engine.run("items = nil\nlocal n = #items").unwrap_err();
+        assert_eq!("script", e.location().unwrap().chunk());
+        assert_eq!(
+            Some("local n = #items"),
+            e.location().unwrap().source_line()
+        );
+
+        engine.reset();
+        assert!(engine.run("table.insert(items, 'hoge')").is_err());
+        assert!(engine.eval::<i64>("mll.random.int(1, 2)").is_ok());
+    }
+
+    #[test]
+    fn test_set_variable() {
+        let engine = Engine::new();
+
+        engine.set_variable("id", &42).unwrap();
+        engine.set_variable("ratio", &1.5).unwrap();
+        engine
+            .set_variable("items", &vec![Some(1), None, Some(3)])
+            .unwrap();
+        let types = engine
+            .eval::<String>(
+                "string.format('%s %s %s %s %d %s', math.type(id), id, math.type(ratio), ratio, \
+                 #items, type(items[2]))",
+            )
+            .unwrap();
+        assert_eq!("integer 42 float 1.5 3 userdata", types);
+
+        assert!(engine.set_context(&"hoge").is_err());
+    }
+
+    #[test]
+    fn test_set_builtins() {
+        let mut engine = Engine::new();
+
+        let mut builtins = BuiltinRegistry::new();
+        builtins.set_legacy_globals(true);
+        engine.set_builtins(builtins).unwrap();
+        engine.run("items = {1, 2}").unwrap();
+
+        let globals = engine.globals_as_json().unwrap();
+        assert_eq!(serde_json::json!({"items": [1, 2]}), globals);
+        assert_eq!(
+            Some(1),
+            engine.eval::<Option<i64>>("random_int(1, 1)").unwrap()
+        );
+
+        // the legacy globals of the previous builtins are not left
+        engine.set_builtins(BuiltinRegistry::new()).unwrap();
+        assert_eq!(None, engine.eval::<Option<i64>>("items").unwrap());
+        assert!(engine.run("random_int(1, 1)").is_err());
+    }
+
+    #[derive(serde::Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_get_variable() {
+        let engine = Engine::new();
+        engine
+            .run("id = 42; user = {name = 'hoge', tags = {'a', 'b'}}; function f() end")
+            .unwrap();
+
+        assert_eq!(42, engine.get_variable::<u64>("id").unwrap());
+        let user = engine.get_variable::<User>("user").unwrap();
+        assert_eq!("hoge", user.name);
+        assert_eq!(vec!["a", "b"], user.tags);
+        assert_eq!(None, engine.get_variable::<Option<i64>>("missing").unwrap());
+        assert!(engine.get_variable::<i64>("user").is_err());
+
+        assert_eq!(
+            serde_json::json!({"id": 42, "user": {"name": "hoge", "tags": ["a", "b"]}}),
+            engine.globals_as_json().unwrap()
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{Limit, ParseError, ScriptLocation};
+
+/// Error of loading, compiling or rendering a template
+///
+/// A render which produced output in spite of the error keeps the partially rendered text in
+/// `output`, so callers can still use it (e.g. write it with the missing variables left empty).
+///
+/// # Examples
+///
+/// ```
+/// use libmll::{Mll, MllError};
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("{{hello}}, {{name}}!".to_string());
+///
+/// match mll.render_with_lua("name = 'hoge'") {
+///     Err(MllError::MissingVariables { output, variables }) => {
+///         assert_eq!(", hoge!", output);
+///         assert_eq!(vec!["hello".to_string()], variables);
+///     }
+///     _ => unreachable!(),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum MllError {
+    /// Failed to read a file
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// Lua script, expression or filter raised an error
+    LuaRuntime {
+        /// Tag which failed (e.g. `{{= expr }}`), `None` if the error is not raised by a tag
+        tag: Option<String>,
+        /// Partially rendered template, `None` if rendering is not started
+        output: Option<String>,
+        source: mlua::Error,
+        /// Where the error is raised in the script, `None` if it is not raised by a script
+        location: Option<Box<ScriptLocation>>,
+    },
+    /// Lua script has a syntax error
+    LuaSyntax {
+        /// Rendered template if the post-process script failed, `None` otherwise
+        output: Option<String>,
+        source: mlua::Error,
+        location: Option<Box<ScriptLocation>>,
+    },
+    /// Some variables of the template are not found
+    MissingVariables {
+        /// Rendered template, missing variables are left empty
+        output: String,
+        /// Names of the missing variables, in order of appearance
+        variables: Vec<String>,
+    },
+    /// Template has syntax errors
+    Parse(ParseError),
+    /// Builtin function raised an error which is not caught by `pcall`
+    Builtin {
+        name: String,
+        message: String,
+        /// Underlying error (e.g. `io::Error`), `None` if the builtin raised only a message
+        source: Option<Arc<dyn Error + Send + Sync>>,
+        /// Rendered template if the post-process script failed, `None` otherwise
+        output: Option<String>,
+        /// Line of the script which called the builtin
+        location: Option<Box<ScriptLocation>>,
+    },
+    /// Context file has a syntax error or an unknown format
+    InvalidContext {
+        /// Path of the file (`<stdin>` for the standard input)
+        path: String,
+        message: String,
+    },
+    /// Failed to convert a string into an encoding
+    Encoding {
+        encoding: String,
+        message: String,
+        /// Underlying error (e.g. `FromUtf8Error`)
+        source: Option<Arc<dyn Error + Send + Sync>>,
+        /// Rendered template if the post-process script failed, `None` otherwise
+        output: Option<String>,
+    },
+    /// Render is stopped by a limit (see `Limits`)
+    LimitExceeded {
+        limit: Limit,
+        /// Tag which was rendered, `None` if the limit is exceeded by a script
+        tag: Option<String>,
+        /// Partially rendered template, `None` if rendering is not started
+        output: Option<String>,
+    },
+    /// Blocking method (e.g. `Mll::render`) is called on a current-thread Tokio runtime, which
+    /// cannot run the render while it is blocked (await the async method instead)
+    CurrentThreadRuntime,
+}
+
+impl MllError {
+    /// Get partially rendered template
+    ///
+    /// # Returns
+    ///
+    /// `Option<&str>` - Rendered text, `None` if the error occurred before rendering
+    pub fn output(&self) -> Option<&str> {
+        match self {
+            MllError::LuaRuntime { output, .. }
+            | MllError::LuaSyntax { output, .. }
+            | MllError::Builtin { output, .. }
+            | MllError::Encoding { output, .. }
+            | MllError::LimitExceeded { output, .. } => output.as_deref(),
+            MllError::MissingVariables { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+
+    /// Get missing variables
+    ///
+    /// # Returns
+    ///
+    /// `&[String]` - Names of the missing variables, empty for the other errors
+    pub fn missing_variables(&self) -> &[String] {
+        match self {
+            MllError::MissingVariables { variables, .. } => variables,
+            _ => &[],
+        }
+    }
+
+    /// Get where the error is raised in the Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Option<&ScriptLocation>` - Chunk, line and traceback, `None` for the errors which are
+    /// not raised by a script (e.g. an expression of the template)
+    pub fn location(&self) -> Option<&ScriptLocation> {
+        match self {
+            MllError::LuaRuntime { location, .. }
+            | MllError::LuaSyntax { location, .. }
+            | MllError::Builtin { location, .. } => location.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Set the rendered template, for the errors which can be raised by a script
+    pub(crate) fn with_output(mut self, output: String) -> Self {
+        if let MllError::LuaRuntime { output: o, .. }
+        | MllError::LuaSyntax { output: o, .. }
+        | MllError::Builtin { output: o, .. }
+        | MllError::Encoding { output: o, .. }
+        | MllError::LimitExceeded { output: o, .. } = &mut self
+        {
+            *o = Some(output);
+        }
+
+        self
+    }
+
+    /// Set where the error is raised, for the errors which can be raised by a script
+    pub(crate) fn with_location(mut self, location: Option<ScriptLocation>) -> Self {
+        if let MllError::LuaRuntime { location: l, .. }
+        | MllError::LuaSyntax { location: l, .. }
+        | MllError::Builtin { location: l, .. } = &mut self
+        {
+            *l = location.map(Box::new);
+        }
+
+        self
+    }
+}
+
+impl fmt::Display for MllError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MllError::Io { path, source } => write!(f, "failed to read {}: {}", path, source),
+            MllError::LuaRuntime {
+                tag: Some(tag),
+                source,
+                ..
+            } => write!(f, "{}: {}", tag, source),
+            MllError::LuaRuntime { source, .. } => write!(f, "lua error: {}", source),
+            MllError::LuaSyntax { source, .. } => write!(f, "lua syntax error: {}", source),
+            MllError::MissingVariables { variables, .. } => {
+                write!(f, "missing variables: {}", variables.join(", "))
+            }
+            MllError::Parse(e) => write!(f, "{}", e),
+            MllError::Builtin { name, message, .. } => write!(f, "{}: {}", name, message),
+            MllError::InvalidContext { path, message } => {
+                write!(f, "invalid context {}: {}", path, message)
+            }
+            MllError::Encoding {
+                encoding, message, ..
+            } => {
+                write!(f, "cannot convert into {}: {}", encoding, message)
+            }
+            MllError::LimitExceeded {
+                limit,
+                tag: Some(tag),
+                ..
+            } => write!(f, "{}: {} exceeded", tag, limit),
+            MllError::LimitExceeded { limit, .. } => write!(f, "{} exceeded", limit),
+            MllError::CurrentThreadRuntime => write!(
+                f,
+                "blocking method called on a current-thread runtime, await the async method instead"
+            ),
+        }
+    }
+}
+
+impl Error for MllError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MllError::Io { source, .. } => Some(source),
+            MllError::LuaRuntime { source, .. } => Some(source),
+            MllError::LuaSyntax { source, .. } => Some(source),
+            MllError::Parse(e) => Some(e),
+            MllError::Builtin {
+                source: Some(source),
+                ..
+            }
+            | MllError::Encoding {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Find the error raised by a builtin (wrapped by callbacks) in a Lua error
+fn find_builtin_error(e: &mlua::Error) -> Option<&MllError> {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => find_builtin_error(cause),
+        mlua::Error::ExternalError(e) => e
+            .downcast_ref::<MllError>()
+            .filter(|e| matches!(e, MllError::Builtin { .. } | MllError::Encoding { .. })),
+        _ => None,
+    }
+}
+
+/// Find the limit exceeded by a script in a Lua error
+pub(crate) fn find_limit(e: &mlua::Error) -> Option<Limit> {
+    match e {
+        mlua::Error::MemoryError(_) => Some(Limit::Memory),
+        mlua::Error::CallbackError { cause, .. } => find_limit(cause),
+        mlua::Error::ExternalError(e) => match e.downcast_ref::<MllError>() {
+            Some(MllError::LimitExceeded { limit, .. }) => Some(*limit),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl From<mlua::Error> for MllError {
+    fn from(e: mlua::Error) -> Self {
+        if let Some(limit) = find_limit(&e) {
+            return MllError::LimitExceeded {
+                limit,
+                tag: None,
+                output: None,
+            };
+        }
+
+        match find_builtin_error(&e) {
+            Some(MllError::Builtin {
+                name,
+                message,
+                source,
+                ..
+            }) => {
+                return MllError::Builtin {
+                    name: name.clone(),
+                    message: message.clone(),
+                    source: source.clone(),
+                    output: None,
+                    location: None,
+                };
+            }
+            Some(MllError::Encoding {
+                encoding,
+                message,
+                source,
+                ..
+            }) => {
+                return MllError::Encoding {
+                    encoding: encoding.clone(),
+                    message: message.clone(),
+                    source: source.clone(),
+                    output: None,
+                };
+            }
+            _ => {}
+        }
+
+        match e {
+            mlua::Error::SyntaxError { .. } => MllError::LuaSyntax {
+                output: None,
+                source: e,
+                location: None,
+            },
+            e => MllError::LuaRuntime {
+                tag: None,
+                output: None,
+                source: e,
+                location: None,
+            },
+        }
+    }
+}
+
+impl From<ParseError> for MllError {
+    fn from(e: ParseError) -> Self {
+        MllError::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mlua::Lua;
+
+    #[test]
+    fn test_from_lua_error() {
+        let lua = Lua::new();
+
+        let e: MllError = lua.load("x = ").exec().unwrap_err().into();
+        assert!(matches!(e, MllError::LuaSyntax { .. }));
+        assert!(e.source().is_some());
+
+        let e: MllError = lua.load("error('hoge')").exec().unwrap_err().into();
+        assert!(matches!(e, MllError::LuaRuntime { .. }));
+        assert!(e.to_string().contains("hoge"));
+        assert_eq!(None, e.output());
+        assert_eq!(None, e.location());
+
+        let cause = "x".parse::<i32>().unwrap_err();
+        let e: MllError = crate::builtin_error_with_source("hoge", "fuga", cause).into();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        let source = e.source().unwrap();
+        assert!(source.downcast_ref::<std::num::ParseIntError>().is_some());
+        assert!(
+            MllError::from(crate::builtin_error("hoge", "fuga"))
+                .source()
+                .is_none()
+        );
+    }
+}
@@ -1,13 +1,49 @@
+pub(crate) mod batch;
 pub(crate) mod builtin;
 pub(crate) mod builtins;
+pub(crate) mod capabilities;
+pub(crate) mod context;
+pub(crate) mod deterministic;
+pub(crate) mod engine;
+pub(crate) mod error;
+pub(crate) mod limits;
+pub(crate) mod report;
+pub(crate) mod template;
+pub(crate) mod traceback;
 pub(crate) mod utils;
 
+pub use batch::BatchContext;
+pub use builtin::BuiltinRegistry;
+pub use builtins::builtin::{BuiltinFunction, builtin_error, builtin_error_with_source};
+pub use capabilities::Capabilities;
+pub use context::{Context, ContextFormat};
+pub use deterministic::Deterministic;
+pub use engine::Engine;
+pub use error::MllError;
+pub use limits::{Limit, Limits};
+pub use report::{BuiltinTiming, RenderReport, TagReport, ValueSource};
+pub use template::CompiledTemplate;
+pub use template::diagnostic::{Diagnostic, ParseError};
+pub use template::format::ValueFormat;
+pub use template::missing::MissingVariablePolicy;
+pub use template::value::{MllValue, RenderContext};
+pub use traceback::{ScriptLocation, StackFrame};
+
 use mlua::{FromLua, Lua, Table};
-use regex::Regex;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::OnceCell;
 use std::collections::{HashMap, HashSet};
 use std::fs::read_to_string;
 use std::path::Path;
-use uuid::Uuid;
+use std::time::Instant;
+
+use builtins::render::Session;
+use limits::LimitGuard;
+use report::take_records;
+use template::renderer::RenderResult;
+use traceback::{call_traced, load_named};
+use utils::do_blocking;
 
 /// Trait for getting value by name
 ///
@@ -59,41 +95,44 @@ where
     }
 }
 
-struct Internal {
-    lua: Lua,
-}
-
-impl Internal {
-    pub fn new() -> Self {
-        let lua = Lua::new();
-        let _ = builtin::Builtins::init(&lua);
-
-        Self { lua }
-    }
-
-    pub fn load_script(&self, script: &str) -> Result<(), String> {
-        let result = self.lua.load(script).exec();
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
-        }
-    }
-}
-
 pub struct Mll {
     template: String,
+    template_name: String,
     pre_process_script: String,
-    tags: HashMap<String, String>,
+    pre_process_script_name: String,
+    post_process_script: String,
+    post_process_script_name: String,
+    tags: Vec<String>,
     processed_tags: HashSet<String>,
+    missing_variable_policy: MissingVariablePolicy,
+    value_format: ValueFormat,
+    compiled: Option<CompiledTemplate>,
+    capabilities: Capabilities,
+    limits: Limits,
+    builtins: BuiltinRegistry,
+    deterministic: Option<Deterministic>,
+    engine: OnceCell<Engine>,
 }
 
 impl Mll {
     pub fn new() -> Self {
         Self {
             template: String::new(),
+            template_name: "template".to_string(),
             pre_process_script: String::new(),
-            tags: HashMap::new(),
+            pre_process_script_name: "pre_process".to_string(),
+            post_process_script: String::new(),
+            post_process_script_name: "post_process".to_string(),
+            tags: Vec::new(),
             processed_tags: HashSet::new(),
+            missing_variable_policy: MissingVariablePolicy::default(),
+            value_format: ValueFormat::default(),
+            compiled: None,
+            capabilities: Capabilities::default(),
+            limits: Limits::default(),
+            builtins: BuiltinRegistry::new(),
+            deterministic: None,
+            engine: OnceCell::new(),
         }
     }
 
@@ -105,6 +144,141 @@ impl Mll {
         self.pre_process_script = script;
     }
 
+    /// Get name of the pre-process script shown in errors
+    ///
+    /// # Returns
+    ///
+    /// `&String` - Name of the script, path of the file if loaded by `load_pre_process_script`
+    pub fn pre_process_script_name(&self) -> &String {
+        &self.pre_process_script_name
+    }
+
+    /// Set name of the pre-process script shown in errors
+    ///
+    /// # Arguments
+    ///
+    /// `name: String` - Name of the script
+    pub fn set_pre_process_script_name(&mut self, name: String) {
+        self.pre_process_script_name = name;
+    }
+
+    /// Load pre-process script from file
+    ///
+    /// # Arguments
+    ///
+    /// `path: &str` - Path to Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of loading script
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// let result = mll.load_pre_process_script("pre_process.lua");
+    ///
+    /// assert!(result.is_ok());
+    /// ```
+    pub fn load_pre_process_script(&mut self, path: &str) -> Result<(), MllError> {
+        let path = Path::new(path);
+        match read_to_string(path) {
+            Ok(script) => {
+                self.set_pre_process_script(script);
+                self.pre_process_script_name = path.display().to_string();
+                Ok(())
+            }
+            Err(e) => Err(MllError::Io {
+                path: path.display().to_string(),
+                source: e,
+            }),
+        }
+    }
+
+    /// Get script run after rendering, empty if nothing is run
+    pub fn post_process_script(&self) -> &String {
+        &self.post_process_script
+    }
+
+    /// Set script run after rendering
+    ///
+    /// The script is run in the session after each successful render, so it can use the
+    /// globals of the pre-process script. It receives the rendered output and the report of the
+    /// render (a table with `tags`, `missing`, `prints`, `warnings`, `builtins`,
+    /// `pre_process_time` and `render_time`) as `...`, and returns the output, or `nil` to keep
+    /// it as it is. If the script raises an error, the render fails with it, and the rendered
+    /// output is kept in `MllError::output`.
+    ///
+    /// # Arguments
+    ///
+    /// `script: String` - Lua script
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// mll.set_pre_process_script("name = 'hoge'; footer = '-- '".to_string());
+    /// mll.set_post_process_script(
+    ///     r#"
+    ///         local output, report = ...
+    ///         return output .. "\n" .. footer .. #report.tags .. " tags"
+    ///     "#
+    ///     .to_string(),
+    /// );
+    ///
+    /// assert_eq!("Hello, hoge!\n-- 1 tags", mll.render_lua_globals().unwrap());
+    /// ```
+    pub fn set_post_process_script(&mut self, script: String) {
+        self.post_process_script = script;
+    }
+
+    /// Get name of the post-process script shown in errors
+    ///
+    /// # Returns
+    ///
+    /// `&String` - Name of the script, path of the file if loaded by `load_post_process_script`
+    pub fn post_process_script_name(&self) -> &String {
+        &self.post_process_script_name
+    }
+
+    /// Set name of the post-process script shown in errors
+    ///
+    /// # Arguments
+    ///
+    /// `name: String` - Name of the script
+    pub fn set_post_process_script_name(&mut self, name: String) {
+        self.post_process_script_name = name;
+    }
+
+    /// Load post-process script from file
+    ///
+    /// # Arguments
+    ///
+    /// `path: &str` - Path to Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of loading script
+    pub fn load_post_process_script(&mut self, path: &str) -> Result<(), MllError> {
+        let path = Path::new(path);
+        match read_to_string(path) {
+            Ok(script) => {
+                self.set_post_process_script(script);
+                self.post_process_script_name = path.display().to_string();
+                Ok(())
+            }
+            Err(e) => Err(MllError::Io {
+                path: path.display().to_string(),
+                source: e,
+            }),
+        }
+    }
+
     /// Get template string
     ///
     /// # Returns
@@ -141,330 +315,2039 @@ impl Mll {
     /// ```
     pub fn set_template(&mut self, template: String) {
         self.template = template;
+        self.compiled = None;
     }
 
-    /// Load template from file
-    ///
-    /// # Arguments
-    ///
-    /// `path: &str` - Path to template file
+    /// Get name of the template shown in diagnostics
     ///
     /// # Returns
     ///
-    /// `Result<(), String>` - Result of loading template
+    /// `&String` - Name of the template, path of the file if loaded by `load_template`
+    pub fn template_name(&self) -> &String {
+        &self.template_name
+    }
+
+    /// Set name of the template shown in diagnostics
     ///
-    /// # Examples
+    /// # Arguments
     ///
-    /// ```ignore
-    /// use libmll::Mll;
+    /// `name: String` - Name of the template
+    pub fn set_template_name(&mut self, name: String) {
+        self.template_name = name;
+        self.compiled = None;
+    }
+
+    /// Get how to render a variable which is not found
     ///
-    /// let mut mll = Mll::new();
-    /// let result = mll.load_template("template.json");
+    /// # Returns
     ///
-    /// assert!(result.is_ok());
-    /// ```
-    pub fn load_template(&mut self, path: &str) -> Result<(), String> {
-        let path = Path::new(path);
-        let template = read_to_string(path);
-        match template {
-            Ok(template) => {
-                self.set_template(template);
-                Ok(())
-            }
-            Err(e) => Err(e.to_string()),
-        }
+    /// `&MissingVariablePolicy` - Policy for missing variables
+    pub fn missing_variable_policy(&self) -> &MissingVariablePolicy {
+        &self.missing_variable_policy
     }
 
-    /// Render template with Lua script
-    ///
-    /// # Arguments
+    /// Set how to render a variable which is not found
     ///
-    /// `script: &str` - Lua script
+    /// `MissingVariablePolicy::Strict` (default) fails the render, `Empty`, `Keep` and `Default`
+    /// render the variable without failing, and `Callback` and `Lua` ask a function.
+    /// Missing variables are reported by `get_missing_variables` with any policy.
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// `Result<String, String>` - Rendered template
+    /// `policy: MissingVariablePolicy` - Policy for missing variables
     ///
     /// # Examples
     ///
     /// ```
-    /// use libmll::Mll;
+    /// use libmll::{MissingVariablePolicy, Mll};
     ///
     /// let mut mll = Mll::new();
     /// mll.set_template("Hello, {{name}}!".to_string());
-    /// let rendered = mll.render_with_lua("name = 'hoge'");
+    /// mll.set_missing_variable_policy(MissingVariablePolicy::Default("guest".to_string()));
     ///
-    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// assert_eq!("Hello, guest!", mll.render_with_lua("").unwrap());
     /// ```
-    pub fn render_with_lua(&mut self, script: &str) -> Result<String, String> {
-        let internal = Internal::new();
+    pub fn set_missing_variable_policy(&mut self, policy: MissingVariablePolicy) {
+        if let Some(compiled) = &mut self.compiled {
+            compiled.set_missing_variable_policy(policy.clone());
+        }
 
-        let result = internal.load_script(script);
-        match result {
-            Ok(_) => {
-                let table = internal.lua.globals();
-                let rendered = self.render(&table);
-                rendered
-            }
-            Err(e) => Err(e),
+        self.missing_variable_policy = policy;
+        if let Some(engine) = self.engine.get() {
+            self.share_session(engine.lua());
         }
     }
 
-    /// Render template with Lua globals
-    ///   
+    /// Get how to render values which are not strings
+    pub fn value_format(&self) -> &ValueFormat {
+        &self.value_format
+    }
+
+    /// Set how to render values which are not strings (e.g. precision of floats)
+    ///
+    /// # Arguments
+    ///
+    /// `format: ValueFormat` - Format of values
+    pub fn set_value_format(&mut self, format: ValueFormat) {
+        if let Some(compiled) = &mut self.compiled {
+            compiled.set_value_format(format);
+        }
+
+        self.value_format = format;
+        if let Some(engine) = self.engine.get() {
+            self.share_session(engine.lua());
+        }
+    }
+
+    /// Compile template
+    ///
     /// # Returns
     ///
-    /// `Result<String, String>` - Rendered template
+    /// `Result<CompiledTemplate, ParseError>` - Compiled template, which can be rendered many
+    /// times, or diagnostics with the line and column of each error
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::collections::HashMap;
     /// use libmll::Mll;
     ///
     /// let mut mll = Mll::new();
     /// mll.set_template("Hello, {{name}}!".to_string());
-    /// mll.set_pre_process_script("name = 'hoge'".to_string());
-    /// let rendered = mll.render_lua_globals();
+    /// let compiled = mll.compile().unwrap();
     ///
-    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// assert_eq!("Hello, hoge!", compiled.render(&table).unwrap());
     /// ```
-    pub fn render_lua_globals(&mut self) -> Result<String, String> {
-        let internal = Internal::new();
-        let _ = internal.load_script(&self.pre_process_script);
+    pub fn compile(&self) -> Result<CompiledTemplate, ParseError> {
+        let mut compiled = CompiledTemplate::compile_named(&self.template_name, &self.template)?;
+        compiled.set_missing_variable_policy(self.missing_variable_policy.clone());
+        compiled.set_value_format(self.value_format);
+        compiled.set_capabilities(self.capabilities.clone());
+        compiled.set_limits(self.limits);
+        compiled.set_builtins(self.builtins.clone());
+        compiled.set_deterministic(self.deterministic);
 
-        let table = internal.lua.globals();
+        Ok(compiled)
+    }
 
-        // print
-        // for pair in table.pairs::<String, mlua::Value>() {
-        //     let (key, value) = pair.unwrap();
-        //     println!("{}: {:?}", key, value);
-        // }
+    /// Get compiled template, compile it if the template is changed
+    fn compiled(&mut self) -> Result<&CompiledTemplate, MllError> {
+        if self.compiled.is_none() {
+            self.compiled = Some(self.compile()?);
+        }
 
-        self.render(&table)
+        Ok(self.compiled.as_ref().unwrap())
     }
 
-    /// Render template with map like object
+    /// Load template from file
     ///
     /// # Arguments
     ///
-    /// `table: &T` - Map like object
+    /// `path: &str` - Path to template file
     ///
     /// # Returns
     ///
-    /// `Result<String, String>` - Rendered template
+    /// `Result<(), MllError>` - Result of loading template
     ///
     /// # Examples
     ///
-    /// ```
-    /// use std::collections::HashMap;
-    /// use libmll::{Mll, GetValueByName};
-    ///
-    /// let template = "Hello, {{name}}!";
-    ///
-    /// let mut table = HashMap::new();
-    /// table.insert("name", "hoge".to_string());
+    /// ```ignore
+    /// use libmll::Mll;
     ///
     /// let mut mll = Mll::new();
-    /// mll.set_template(template.to_string());
-    /// let rendered = mll.render(&table);
+    /// let result = mll.load_template("template.json");
     ///
-    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// assert!(result.is_ok());
     /// ```
-    pub fn render<T>(&mut self, table: &T) -> Result<String, String>
-    where
-        T: GetValueByName<String>,
-    {
-        // define regex pattern for Mustache's variable-like syntax (e.g. {{ name }})
-        let re_variable = Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap();
-        // let re_function = Regex::new(r#"\{\{\s*([\w\(\)"]+)\s*\}\}"#).unwrap();
-
-        let internal = Internal::new();
-
-        // internal.load_script(&self.pre_process_script);
-
-        let mut succeeded = true;
-        let rendered = re_variable
-            .replace_all(&self.template.as_str(), |caps: &regex::Captures| {
-                // extract variable name (or Lua script) from template
-                let tag = caps.get(1).unwrap().as_str();
-
-                // make temporary variable name
-                let uuid = Uuid::new_v4();
-                let variable_name = format!(
-                    "v_{}",
-                    uuid.simple().encode_lower(&mut Uuid::encode_buffer())
-                );
-
-                // map variable name and temporary variable
-                self.tags.insert(variable_name.to_string(), tag.to_string());
-
-                // set Lua global table
-                // TODO: replace following script, too general purpose:
-                //  local function f_uuid()
-                //      return tag
-                //  end
-                //  v_uuid = f_uuid()
-                let result = internal.lua.load(format!("{variable_name} = {tag}")).exec();
-                match result {
-                    Ok(_) => match table.get_by_name(tag) {
-                        Some(value) => {
-                            self.processed_tags.insert(tag.to_owned());
-                            value
-                        }
-                        None => {
-                            succeeded = false;
-                            eprintln!("variable not found: {}", tag);
-                            return "".to_string();
-                        }
-                    },
-                    Err(e) => {
-                        succeeded = false;
-                        eprintln!("result: {}", e);
-                        return "".to_string();
-                    }
-                }
-            })
-            .into_owned();
-
-        // let rendered = re_function
-        //     .replace_all(rendered.as_str(), |caps: &regex::Captures| {
-        //         let calling = caps.get(1).unwrap().as_str();
-
-        //         // make temporary variable name
-        //         let uuid = Uuid::new_v4();
-        //         let variable_name = format!(
-        //             "f_{}",
-        //             uuid.simple().encode_lower(&mut Uuid::encode_buffer())
-        //         );
-
-        //         // map variable name and temporary variable
-        //         self.tags
-        //             .insert(variable_name.to_string(), calling.to_string());
-
-        //         // set Lua global table
-        //         let result = internal
-        //             .lua
-        //             .load(format!("{variable_name} = {calling}"))
-        //             .exec();
-        //         match result {
-        //             Ok(_) => match internal.lua.globals().get(variable_name) {
-        //                 Ok(value) => value,
-        //                 Err(e) => {
-        //                     eprintln!("{}", e);
-        //                     "".to_string()
-        //                 }
-        //             },
-        //             Err(e) => {
-        //                 eprintln!("result: {}", e);
-        //                 return "".to_string();
-        //             }
-        //         }
-        //     })
-        //     .into_owned();
-
-        if succeeded {
-            Ok(rendered)
-        } else {
-            Err(rendered)
+    pub fn load_template(&mut self, path: &str) -> Result<(), MllError> {
+        let path = Path::new(path);
+        let template = read_to_string(path);
+        match template {
+            Ok(template) => {
+                self.set_template(template);
+                self.template_name = path.display().to_string();
+                Ok(())
+            }
+            Err(e) => Err(MllError::Io {
+                path: path.display().to_string(),
+                source: e,
+            }),
         }
     }
 
-    /// Get rendered tags
+    /// Get what scripts and templates are allowed to do
     ///
     /// # Returns
     ///
-    /// `Vec<String>` - Rendered tags
+    /// `&Capabilities` - Capabilities of the session
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Set what scripts and templates are allowed to do
+    ///
+    /// The session is reset (see `reset`) to create the Lua state with the capabilities.
+    ///
+    /// # Arguments
+    ///
+    /// `capabilities: Capabilities` - Capabilities of the session (e.g.
+    /// `Capabilities::sandboxed()` for untrusted templates)
     ///
     /// # Examples
     ///
     /// ```
-    /// use libmll::Mll;
+    /// use libmll::{Capabilities, Mll};
     ///
     /// let mut mll = Mll::new();
-    /// mll.set_template("Hello, {{name}}!".to_string());
-    /// let _ = mll.render_lua_globals();
+    /// mll.set_capabilities(Capabilities::sandboxed());
+    /// mll.set_template("{{= os }}".to_string());
     ///
-    /// let tags = mll.get_rendered_tags();
-    /// assert!(tags.contains(&"name".to_string()));
-    /// assert_eq!(1, tags.len());
+    /// assert_eq!("", mll.render_with_lua("").unwrap());
     /// ```
-    pub fn get_rendered_tags(&self) -> Vec<String> {
-        self.tags.values().cloned().collect()
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.reset();
     }
 
-    /// Get missing variables
-    ///     
+    /// Get limits of each render
+    ///
     /// # Returns
     ///
-    /// `Vec<String>` - Missing variables
+    /// `&Limits` - Limits of a render
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Set limits of each render
+    ///
+    /// Limits are applied to each call of `run_script` and the render methods, including the
+    /// scripts run by them (e.g. the pre-process script of `render_lua_globals`).
+    ///
+    /// # Arguments
+    ///
+    /// `limits: Limits` - Limits of a render
     ///
     /// # Examples
     ///
     /// ```
-    /// use libmll::Mll;
-    /// use std::collections::HashMap;
+    /// use libmll::{Limits, Mll};
     ///
     /// let mut mll = Mll::new();
-    /// mll.set_template("{{hello}}, {{name}}!".to_string());
+    /// mll.set_limits(Limits::new().instructions(100_000));
     ///
-    /// let mut table = HashMap::new();
-    /// table.insert("name", "hoge".to_string());
-    ///
-    /// let _ = mll.render(&table);
-    ///
-    /// let missing_variables = mll.get_missing_variables();
-    /// assert_eq!(1, missing_variables.len());
-    /// assert_eq!("hello", missing_variables[0]);
+    /// let e = mll.run_script("while true do end").unwrap_err();
+    /// assert_eq!("instruction limit exceeded", e.to_string());
     /// ```
-    pub fn get_missing_variables(&self) -> Vec<String> {
-        let mut missing_variables = Vec::new();
-
-        for tag in self.tags.values() {
-            if !self.processed_tags.contains(tag) {
-                missing_variables.push(tag.to_string());
-            }
+    pub fn set_limits(&mut self, limits: Limits) {
+        if let Some(compiled) = &mut self.compiled {
+            compiled.set_limits(limits);
         }
 
-        missing_variables
+        self.limits = limits;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Get the deterministic mode of the session
+    ///
+    /// # Returns
+    ///
+    /// `Option<&Deterministic>` - Seed and time, `None` if the mode is off
+    pub fn deterministic(&self) -> Option<&Deterministic> {
+        self.deterministic.as_ref()
+    }
 
-    #[test]
-    fn test_values_lua_table() {
-        let template = "Hello, {{name}}!";
+    /// Turn on or off the deterministic mode, which makes renders reproducible
+    ///
+    /// The session is reset (see `reset`), and the random builtins and the clock of the new
+    /// session are driven by the seed and the time.
+    ///
+    /// # Arguments
+    ///
+    /// `deterministic: Option<Deterministic>` - Seed and time, `None` to turn it off
+    pub fn set_deterministic(&mut self, deterministic: Option<Deterministic>) {
+        if let Some(compiled) = &mut self.compiled {
+            compiled.set_deterministic(deterministic);
+        }
 
-        let lua = Lua::new();
-        lua.load("name = 'hoge'").exec().unwrap();
+        self.deterministic = deterministic;
+        self.reset();
+    }
 
-        let mut mll = Mll::new();
-        mll.set_template(template.to_string());
-        let rendered = mll.render(&lua.globals());
-        assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// Get the Lua engine of the session
+    ///
+    /// The engine is created on first use, and shared by all scripts and renders of this
+    /// instance until `reset` is called.
+    ///
+    /// # Returns
+    ///
+    /// `&Engine` - Lua engine
+    pub fn engine(&self) -> &Engine {
+        self.engine.get_or_init(|| {
+            let mut engine = Engine::with_capabilities(self.capabilities.clone());
+            // the builtins can not be set only if the state runs out of memory
+            engine.set_builtins(self.builtins.clone()).unwrap();
+            if self.deterministic.is_some() {
+                engine.set_deterministic(self.deterministic);
+            }
+            self.share_session(engine.lua());
+            engine
+        })
+    }
 
-        let tags = mll.get_rendered_tags();
-        assert!(tags.contains(&"name".to_string()));
-        assert_eq!(1, tags.len());
+    /// Share the settings of the session with the templates rendered by `mll.template.render`
+    fn share_session(&self, lua: &Lua) {
+        lua.set_app_data(Session {
+            builtins: self.builtins.clone(),
+            missing_variable_policy: self.missing_variable_policy.clone(),
+            value_format: self.value_format,
+        });
     }
 
-    #[test]
-    fn test_values_hashmap() {
-        let template = "Hello, {{name}}!";
+    /// Get builtins added, replaced or removed on top of the standard builtins
+    ///
+    /// # Returns
+    ///
+    /// `&BuiltinRegistry` - Builtins of the session
+    pub fn builtins(&self) -> &BuiltinRegistry {
+        &self.builtins
+    }
 
-        let mut table = HashMap::new();
-        table.insert("name", "hoge".to_string());
+    /// Set builtins added, replaced or removed on top of the standard builtins
+    ///
+    /// The session is reset, so that no builtin of the previous registry is left.
+    ///
+    /// # Arguments
+    ///
+    /// `builtins: BuiltinRegistry` - Builtins of the session
+    pub fn set_builtins(&mut self, builtins: BuiltinRegistry) {
+        self.builtins = builtins;
+        self.update_builtins();
+    }
+
+    /// Add a builtin as a global, or replace the builtin of the same name
+    ///
+    /// # Arguments
+    ///
+    /// `builtin: B` - Builtin
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use mlua::Lua;
+    /// use libmll::{BuiltinFunction, Mll};
+    ///
+    /// struct RandomInt;
+    ///
+    /// impl BuiltinFunction for RandomInt {
+    ///     fn get_name(&self) -> &str {
+    ///         "random_int"
+    ///     }
+    ///
+    ///     fn get_function(&self, lua: &Lua) -> mlua::Function {
+    ///         lua.create_function(|_, (min, _): (i64, i64)| Ok(min)).unwrap()
+    ///     }
+    /// }
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.register_builtin(RandomInt);
+    /// mll.set_template("{{= random_int(3, 10) }}".to_string());
+    ///
+    /// assert_eq!("3", mll.render_with_lua("").unwrap());
+    /// ```
+    pub fn register_builtin<B>(&mut self, builtin: B)
+    where
+        B: BuiltinFunction + Send + Sync + 'static,
+    {
+        self.builtins.register(builtin);
+        self.update_builtins();
+    }
+
+    /// Add a builtin to a module (global table), or replace the builtin of the same name in it
+    ///
+    /// # Arguments
+    ///
+    /// `module: &str` - Name of the module
+    ///
+    /// `builtin: B` - Builtin
+    pub fn register_builtin_in<B>(&mut self, module: &str, builtin: B)
+    where
+        B: BuiltinFunction + Send + Sync + 'static,
+    {
+        self.builtins.register_in(module, builtin);
+        self.update_builtins();
+    }
+
+    /// Remove a global builtin
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the builtin
+    pub fn remove_builtin(&mut self, name: &str) {
+        self.builtins.remove(name);
+        self.update_builtins();
+    }
+
+    /// Remove a builtin from a module, including the standard builtins (e.g. `exec` in
+    /// `mll.process`)
+    ///
+    /// # Arguments
+    ///
+    /// `module: &str` - Name of the module
+    ///
+    /// `name: &str` - Name of the builtin
+    pub fn remove_builtin_in(&mut self, module: &str, name: &str) {
+        self.builtins.remove_in(module, name);
+        self.update_builtins();
+    }
+
+    /// Set the standard builtins also as the globals of the previous versions (e.g.
+    /// `random_int` for `mll.random.int`), for templates and scripts written for them
+    ///
+    /// # Arguments
+    ///
+    /// `enabled: bool` - `true` to set the legacy globals, `false` by default
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_legacy_globals(true);
+    /// mll.set_template("{{= random_int(1, 2) }} {{= mll.random.int(2, 3) }}".to_string());
+    ///
+    /// assert_eq!("1 2", mll.render_with_lua("").unwrap());
+    /// ```
+    pub fn set_legacy_globals(&mut self, enabled: bool) {
+        self.builtins.set_legacy_globals(enabled);
+        self.update_builtins();
+    }
+
+    /// Set the builtins into the compiled template, and reset the session to set them into it
+    fn update_builtins(&mut self) {
+        if let Some(compiled) = &mut self.compiled {
+            compiled.set_builtins(self.builtins.clone());
+        }
+
+        self.reset();
+    }
+
+    /// Run Lua script in the session
+    ///
+    /// Globals defined by the script are visible to the following scripts and renders.
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.run_script("function greet(name) return 'Hello, ' .. name end").unwrap();
+    /// mll.run_script("suffix = '!'").unwrap();
+    /// mll.set_template("{{= greet(name) .. suffix }}".to_string());
+    ///
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// assert_eq!("Hello, hoge!", mll.render(&table).unwrap());
+    /// ```
+    pub fn run_script(&mut self, script: &str) -> Result<(), MllError> {
+        do_blocking(self.run_script_async(script))?
+    }
+
+    /// Run Lua script in the session on the runtime of the caller
+    ///
+    /// See `render_async`.
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Result of the script
+    pub async fn run_script_async(&mut self, script: &str) -> Result<(), MllError> {
+        let lua = self.engine().lua().clone();
+        let _guard = LimitGuard::new(&lua, &self.limits);
+
+        self.engine().run_async(script).await
+    }
+
+    /// Set a variable of the session from a Rust value
+    ///
+    /// The value is converted into a Lua value (e.g. a struct into a table), so that the
+    /// pre-process script can use it without `mll.json.decode`. Integers and floats are kept
+    /// as they are, and `None` becomes `nil` which is falsy and rendered as a missing value.
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the Lua global
+    ///
+    /// `value: &T` - Value
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the value cannot be converted
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use serde::Serialize;
+    /// use libmll::Mll;
+    ///
+    /// #[derive(Serialize)]
+    /// struct User {
+    ///     name: String,
+    ///     age: u32,
+    /// }
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{user.name}} {{next_age}}".to_string());
+    /// mll.set_pre_process_script("next_age = user.age + 1".to_string());
+    ///
+    /// let user = User { name: "hoge".to_string(), age: 20 };
+    /// mll.set_variable("user", &user).unwrap();
+    ///
+    /// assert_eq!("hoge 21", mll.render_lua_globals().unwrap());
+    /// ```
+    pub fn set_variable<T>(&mut self, name: &str, value: &T) -> Result<(), MllError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.engine().set_variable(name, value)
+    }
+
+    /// Set each field of a Rust value (e.g. a struct or a map) as a variable of the session
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Value which is converted into a Lua table
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), MllError>` - Error if the value is not converted into a table
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use serde_json::json;
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{name}}: {{= #items }}".to_string());
+    ///
+    /// mll.set_context(&json!({"name": "hoge", "items": [1, 2, 3]})).unwrap();
+    ///
+    /// assert_eq!("hoge: 3", mll.render_lua_globals().unwrap());
+    /// ```
+    pub fn set_context<T>(&mut self, context: &T) -> Result<(), MllError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.engine().set_context(context)
+    }
+
+    /// Get a variable of the session as a Rust value (e.g. a value computed by the
+    /// pre-process script)
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the Lua global
+    ///
+    /// # Returns
+    ///
+    /// `Result<T, MllError>` - Value, error if the global cannot be converted (e.g. `nil` for a
+    /// type which is not `Option`)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{id}}".to_string());
+    /// mll.set_pre_process_script("id = 40 + 2; tags = {'a', 'b'}".to_string());
+    /// mll.render_lua_globals().unwrap();
+    ///
+    /// assert_eq!(42, mll.get_variable::<i64>("id").unwrap());
+    /// assert_eq!(vec!["a", "b"], mll.get_variable::<Vec<String>>("tags").unwrap());
+    /// assert_eq!(None, mll.get_variable::<Option<String>>("name").unwrap());
+    /// ```
+    pub fn get_variable<T>(&self, name: &str) -> Result<T, MllError>
+    where
+        T: DeserializeOwned,
+    {
+        self.engine().get_variable(name)
+    }
+
+    /// Get variables of the session as JSON, without the standard libraries and the builtins
+    ///
+    /// # Returns
+    ///
+    /// `Result<serde_json::Value, MllError>` - JSON object of the variables
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use serde_json::json;
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.run_script("id = 42; user = {name = 'hoge'}; function f() end").unwrap();
+    ///
+    /// assert_eq!(
+    ///     json!({"id": 42, "user": {"name": "hoge"}}),
+    ///     mll.globals_as_json().unwrap()
+    /// );
+    /// ```
+    pub fn globals_as_json(&self) -> Result<serde_json::Value, MllError> {
+        self.engine().globals_as_json()
+    }
+
+    /// Reset the session
+    ///
+    /// The Lua state is discarded with all globals defined by scripts, and rendered and missing
+    /// tags are cleared. The template and the settings are kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{name}}".to_string());
+    /// assert_eq!("hoge", mll.render_with_lua("name = 'hoge'").unwrap());
+    ///
+    /// mll.reset();
+    /// assert!(mll.render_with_lua("").is_err());
+    /// assert_eq!(vec!["name".to_string()], mll.get_missing_variables());
+    /// ```
+    pub fn reset(&mut self) {
+        self.engine = OnceCell::new();
+
+        self.tags.clear();
+        self.processed_tags.clear();
+    }
+
+    /// Render template with Lua script
+    ///
+    /// The script is run in the session (see `run_script`), so globals of the previous scripts
+    /// are also visible.
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// let rendered = mll.render_with_lua("name = 'hoge'");
+    ///
+    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// ```
+    pub fn render_with_lua(&mut self, script: &str) -> Result<String, MllError> {
+        do_blocking(self.render_with_lua_async(script))?
+    }
+
+    /// Render template with Lua script on the runtime of the caller
+    ///
+    /// See `render_async`.
+    ///
+    /// # Arguments
+    ///
+    /// `script: &str` - Lua script
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template
+    pub async fn render_with_lua_async(&mut self, script: &str) -> Result<String, MllError> {
+        let lua = self.engine().lua().clone();
+        take_records(&lua);
+        let _guard = LimitGuard::new(&lua, &self.limits);
+
+        self.engine().run_async(script).await?;
+
+        let table = self.engine().globals();
+        let (rendered, mut report) = self.render_session(&table, ValueSource::LuaGlobal).await;
+        self.post_process(rendered, &mut report).await
+    }
+
+    /// Render template with Lua globals
+    ///
+    /// The pre-process script is run in the session before rendering. If it fails, its error is
+    /// returned with the location in the script (see `MllError::location`).
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// mll.set_pre_process_script("name = 'hoge'".to_string());
+    /// let rendered = mll.render_lua_globals();
+    ///
+    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// ```
+    pub fn render_lua_globals(&mut self) -> Result<String, MllError> {
+        let (rendered, _) = self.render_lua_globals_with_report();
+        rendered
+    }
+
+    /// Render template with Lua globals on the runtime of the caller
+    ///
+    /// See `render_async`.
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template
+    pub async fn render_lua_globals_async(&mut self) -> Result<String, MllError> {
+        let (rendered, _) = self.render_lua_globals_with_report_async().await;
+        rendered
+    }
+
+    /// Render template with Lua globals, and report how it is rendered
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render,
+    /// including the time spent in the pre-process script
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{= mll.random.int(1, 10) }}".to_string());
+    /// mll.set_pre_process_script("x = mll.random.int(1, 10)".to_string());
+    /// let (_, report) = mll.render_lua_globals_with_report();
+    ///
+    /// assert!(report.pre_process_time().is_some());
+    /// assert_eq!(2, report.builtins()["mll.random.int"].calls);
+    /// ```
+    pub fn render_lua_globals_with_report(&mut self) -> (Result<String, MllError>, RenderReport) {
+        do_blocking(self.render_lua_globals_with_report_async())
+            .unwrap_or_else(|e| (Err(e), RenderReport::default()))
+    }
+
+    /// Render template with Lua globals on the runtime of the caller, and report how it is
+    /// rendered
+    ///
+    /// See `render_async`.
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render
+    pub async fn render_lua_globals_with_report_async(
+        &mut self,
+    ) -> (Result<String, MllError>, RenderReport) {
+        let lua = self.engine().lua().clone();
+        take_records(&lua);
+        let _guard = LimitGuard::new(&lua, &self.limits);
+
+        let start = Instant::now();
+        let result = self.run_pre_process_async().await;
+        let pre_process_time = start.elapsed();
+
+        let (rendered, mut report) = match result {
+            Ok(()) => {
+                let table = self.engine().globals();
+                self.render_session(&table, ValueSource::LuaGlobal).await
+            }
+            Err(e) => {
+                let records = take_records(&lua);
+                (Err(e), RenderReport::from_records(records))
+            }
+        };
+        report.set_pre_process_time(pre_process_time);
+        let rendered = self.post_process(rendered, &mut report).await;
+
+        (rendered, report)
+    }
+
+    /// Render template with map like object
+    ///
+    /// A tag is a path to a value: `{{response.args.foo}}` looks up keys of nested tables, and
+    /// `{{items[2].name}}` looks up an item of a list by 1-based index (same as Lua). Nested
+    /// values are resolved through Lua tables, `serde_json::Value` and `HashMap`. If any segment
+    /// of the path is missing, the whole path is reported by `get_missing_variables`.
+    /// Numbers, booleans and tables are rendered by `set_value_format` (tables as JSON).
+    ///
+    /// `{{#name}}...{{/name}}` renders its body once per item if `name` is a list (Lua sequence
+    /// table), once if it is any other truthy value, and not at all otherwise. Inside the
+    /// section, names are looked up in the current item first, and `{{this}}` (or `{{.}}`) is the
+    /// item itself.
+    ///
+    /// `{{^name}}...{{/name}}` renders its body only if `name` is missing, falsy (`nil` or
+    /// `false`) or an empty list.
+    ///
+    /// `{{#if expr}}...{{else}}...{{/if}}` evaluates `expr` as a Lua expression and chooses a
+    /// branch by Lua truthiness. Names in `expr` are looked up in the sections and `table` first,
+    /// and then in the Lua globals of the session (see `run_script`).
+    ///
+    /// `{{= expr }}` evaluates `expr` as a Lua expression in the same way, and writes the result
+    /// converted by Lua's `tostring` (`nil` is written as an empty string). All builtins
+    /// (e.g. `{{= mll.random.int(1, 10) }}`) are available. A bad expression makes the render fail.
+    ///
+    /// `{{ name | upper | truncate(20) }}` applies filters to the value from left to right.
+    /// Arguments of a filter are Lua expressions. The standard filters are `upper`, `lower`,
+    /// `trim`, `default`, `json`, `escape`, `date` (with the `datetime` feature), `pad`,
+    /// `replace` and `truncate`, and Lua scripts can add their own by `mll.template.register_filter`.
+    ///
+    /// `\{{` is written as a literal `{{` (e.g. `\{{name}}` renders `{{name}}`), and `\\{{` as a
+    /// literal backslash followed by a tag (e.g. `C:\\{{dir}}` renders `C:\` and `dir`).
+    ///
+    /// # Arguments
+    ///
+    /// `table: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template, or error with the partially rendered
+    /// template (`MllError::output`) if any variable is missing (see
+    /// `set_missing_variable_policy`) or any expression failed
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use libmll::Mll;
+    ///
+    /// let template = "Hello, {{name}}!";
+    ///
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template(template.to_string());
+    /// let rendered = mll.render(&table);
+    ///
+    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// ```
+    pub fn render<T>(&mut self, table: &T) -> Result<String, MllError>
+    where
+        T: RenderContext,
+    {
+        let (rendered, _) = self.render_with_report(table);
+        rendered
+    }
+
+    /// Render template with map like object on the runtime of the caller
+    ///
+    /// Async builtins (e.g. `mll.http.get` and `mll.process.exec`) wait on the runtime of the
+    /// caller instead of blocking it, and no runtime is created or nested, so this can be
+    /// awaited in async code, including on a current-thread runtime. The blocking methods
+    /// (e.g. `render`) run the same futures to completion, but return
+    /// `MllError::CurrentThreadRuntime` on a current-thread runtime, which they cannot block.
+    ///
+    /// The future is not `Send`, because the Lua state is not: await it in `block_on`, in a
+    /// `LocalSet`, or on a blocking thread of the runtime.
+    ///
+    /// # Arguments
+    ///
+    /// `table: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template (see `render`)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use libmll::Mll;
+    ///
+    /// let runtime = tokio::runtime::Builder::new_current_thread()
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// let rendered = runtime.block_on(mll.render_async(&table));
+    ///
+    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// ```
+    pub async fn render_async<T>(&mut self, table: &T) -> Result<String, MllError>
+    where
+        T: RenderContext,
+    {
+        let (rendered, _) = self.render_with_report_async(table).await;
+        rendered
+    }
+
+    /// Render template with map like object, and report how it is rendered
+    ///
+    /// The report has every occurrence of variable tags with its position and where the value
+    /// came from, missing variables, output of `print`, warnings, and time spent in builtins.
+    ///
+    /// # Arguments
+    ///
+    /// `table: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use libmll::{Mll, ValueSource};
+    ///
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// let (rendered, report) = mll.render_with_report(&table);
+    ///
+    /// assert_eq!("Hello, hoge!", rendered.unwrap());
+    /// assert_eq!(ValueSource::Context, report.tags()[0].source());
+    /// assert_eq!(Some("hoge"), report.tags()[0].value());
+    /// ```
+    pub fn render_with_report<T>(&mut self, table: &T) -> (Result<String, MllError>, RenderReport)
+    where
+        T: RenderContext,
+    {
+        do_blocking(self.render_with_report_async(table))
+            .unwrap_or_else(|e| (Err(e), RenderReport::default()))
+    }
+
+    /// Render template with map like object on the runtime of the caller, and report how it is
+    /// rendered
+    ///
+    /// See `render_async`.
+    ///
+    /// # Arguments
+    ///
+    /// `table: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render
+    pub async fn render_with_report_async<T>(
+        &mut self,
+        table: &T,
+    ) -> (Result<String, MllError>, RenderReport)
+    where
+        T: RenderContext,
+    {
+        let lua = self.engine().lua().clone();
+        take_records(&lua);
+        let _guard = LimitGuard::new(&lua, &self.limits);
+
+        let (rendered, mut report) = self.render_session(table, ValueSource::Context).await;
+        let rendered = self.post_process(rendered, &mut report).await;
+
+        (rendered, report)
+    }
+
+    /// Render template with each context of a batch on worker threads
+    ///
+    /// The items are rendered on as many workers as the available parallelism, see
+    /// `render_batch_with_workers`.
+    ///
+    /// # Arguments
+    ///
+    /// `contexts: I` - Contexts of the items (JSON values, maps or Lua scripts)
+    ///
+    /// # Returns
+    ///
+    /// `Vec<Result<String, MllError>>` - Rendered template or error of each item, in the order
+    /// of the contexts
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use serde_json::json;
+    /// use libmll::{BatchContext, Mll};
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    ///
+    /// let contexts = vec![
+    ///     BatchContext::from(json!({"name": "hoge"})),
+    ///     BatchContext::Script("name = 'fuga'".to_string()),
+    ///     BatchContext::from(json!({})),
+    /// ];
+    /// let results = mll.render_batch(contexts);
+    ///
+    /// assert_eq!("Hello, hoge!", results[0].as_ref().unwrap());
+    /// assert_eq!("Hello, fuga!", results[1].as_ref().unwrap());
+    /// assert!(results[2].is_err());
+    /// ```
+    pub fn render_batch<I>(&self, contexts: I) -> Vec<Result<String, MllError>>
+    where
+        I: IntoIterator,
+        I::IntoIter: Send,
+        I::Item: Into<BatchContext>,
+    {
+        self.render_batch_with_workers(contexts, batch::default_workers())
+    }
+
+    /// Render template with each context of a batch on the given number of worker threads
+    ///
+    /// Each worker has its own session (Lua state) with the settings of this `Mll`, where the
+    /// pre-process script is run once before rendering the items. An item which fails does not
+    /// stop the other items. In the deterministic mode, each item is rendered from a seed
+    /// derived from the seed and its index, so the output does not depend on the number of
+    /// workers, and items with the same context can still differ.
+    ///
+    /// # Arguments
+    ///
+    /// `contexts: I` - Contexts of the items, which are taken by the workers one by one
+    ///
+    /// `workers: usize` - Number of worker threads
+    ///
+    /// # Returns
+    ///
+    /// `Vec<Result<String, MllError>>` - Rendered template or error of each item, in the order
+    /// of the contexts
+    pub fn render_batch_with_workers<I>(
+        &self,
+        contexts: I,
+        workers: usize,
+    ) -> Vec<Result<String, MllError>>
+    where
+        I: IntoIterator,
+        I::IntoIter: Send,
+        I::Item: Into<BatchContext>,
+    {
+        batch::render_batch(self, contexts, workers)
+    }
+
+    /// Run the pre-process script in the session as a chunk named by `pre_process_script_name`
+    pub(crate) async fn run_pre_process_async(&self) -> Result<(), MllError> {
+        self.engine()
+            .run_named_async(&self.pre_process_script_name, &self.pre_process_script)
+            .await
+    }
+
+    /// Run the post-process script on the output of a successful render
+    async fn post_process(
+        &self,
+        rendered: Result<String, MllError>,
+        report: &mut RenderReport,
+    ) -> Result<String, MllError> {
+        let rendered = rendered?;
+        if self.post_process_script.is_empty() {
+            return Ok(rendered);
+        }
+
+        let start = Instant::now();
+        let result = self.run_post_process_async(&rendered, report).await;
+        report.set_post_process_time(start.elapsed());
+        report.add_records(take_records(self.engine().lua()));
+
+        match result {
+            Ok(output) => Ok(output.unwrap_or(rendered)),
+            Err(e) => Err(e.with_output(rendered)),
+        }
+    }
+
+    /// Run the post-process script in the session as a chunk named by `post_process_script_name`
+    async fn run_post_process_async(
+        &self,
+        rendered: &str,
+        report: &RenderReport,
+    ) -> Result<Option<String>, MllError> {
+        let lua = self.engine().lua();
+        let function = load_named(
+            lua,
+            &self.post_process_script_name,
+            &self.post_process_script,
+        )?;
+        let report = report.to_lua(lua)?;
+
+        call_traced(lua, function, (rendered, report)).await
+    }
+
+    pub(crate) async fn render_in<T>(
+        &mut self,
+        lua: &Lua,
+        table: &T,
+        context_source: ValueSource,
+    ) -> Result<String, MllError>
+    where
+        T: RenderContext,
+    {
+        let result = self.compiled()?.render_in(lua, table, context_source).await;
+
+        self.finish_render(result)
+    }
+
+    /// Render template in the session, and make report with the records of the session
+    async fn render_session<T>(
+        &mut self,
+        table: &T,
+        context_source: ValueSource,
+    ) -> (Result<String, MllError>, RenderReport)
+    where
+        T: RenderContext,
+    {
+        if let Err(e) = self.compiled() {
+            let records = take_records(self.engine().lua());
+            return (Err(e), RenderReport::from_records(records));
+        }
+
+        let compiled = self.compiled.as_ref().unwrap();
+        let lua = self.engine().lua().clone();
+
+        let start = Instant::now();
+        let result = compiled.render_in(&lua, table, context_source).await;
+        let report = RenderReport::new(&result, take_records(&lua), start.elapsed());
+
+        (self.finish_render(result), report)
+    }
+
+    fn finish_render(&mut self, result: RenderResult) -> Result<String, MllError> {
+        for tag in &result.tags {
+            if !self.tags.iter().any(|name| name == tag.name()) {
+                self.tags.push(tag.name().to_string());
+            }
+
+            if !tag.source().is_missing() {
+                self.processed_tags.insert(tag.name().to_string());
+            }
+        }
+
+        result.into_result()
+    }
+
+    /// Get rendered tags
+    ///
+    /// Tags of all renders are returned once per name, in order of their first appearance. Use
+    /// `render_with_report` to get every occurrence of the tags with their positions.
+    ///
+    /// # Returns
+    ///
+    /// `Vec<String>` - Rendered tags
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("Hello, {{name}}!".to_string());
+    /// let _ = mll.render_lua_globals();
+    ///
+    /// let tags = mll.get_rendered_tags();
+    /// assert!(tags.contains(&"name".to_string()));
+    /// assert_eq!(1, tags.len());
+    /// ```
+    pub fn get_rendered_tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+
+    /// Get missing variables
+    ///
+    /// A variable is missing if none of its tags is found, even if the missing variable policy
+    /// rendered a text for it (same as `RenderReport::missing`).
+    ///
+    /// # Returns
+    ///
+    /// `Vec<String>` - Missing variables
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::Mll;
+    /// use std::collections::HashMap;
+    ///
+    /// let mut mll = Mll::new();
+    /// mll.set_template("{{hello}}, {{name}}!".to_string());
+    ///
+    /// let mut table = HashMap::new();
+    /// table.insert("name", "hoge".to_string());
+    ///
+    /// let _ = mll.render(&table);
+    ///
+    /// let missing_variables = mll.get_missing_variables();
+    /// assert_eq!(1, missing_variables.len());
+    /// assert_eq!("hello", missing_variables[0]);
+    /// ```
+    pub fn get_missing_variables(&self) -> Vec<String> {
+        let mut missing_variables = Vec::new();
+
+        for tag in &self.tags {
+            if !self.processed_tags.contains(tag) {
+                missing_variables.push(tag.to_string());
+            }
+        }
+
+        missing_variables
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_lua_table() {
+        let template = "Hello, {{name}}!";
+
+        let lua = Lua::new();
+        lua.load("name = 'hoge'").exec().unwrap();
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render(&lua.globals());
+        assert_eq!("Hello, hoge!", rendered.unwrap());
+
+        let tags = mll.get_rendered_tags();
+        assert!(tags.contains(&"name".to_string()));
+        assert_eq!(1, tags.len());
+    }
+
+    #[test]
+    fn test_values_hashmap() {
+        let template = "Hello, {{name}}!";
+
+        let mut table = HashMap::new();
+        table.insert("name", "hoge".to_string());
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render(&table);
+        assert_eq!("Hello, hoge!", rendered.unwrap());
+
+        let tags = mll.get_rendered_tags();
+        assert!(tags.contains(&"name".to_string()));
+        assert_eq!(1, tags.len());
+    }
+
+    #[test]
+    fn test_section_list() {
+        let template = "{{#items}}[{{name}}:{{price}}]{{/items}}";
+
+        let lua = Lua::new();
+        lua.load(
+            r#"
+            items = {
+                {name = "apple", price = 100},
+                {name = "banana", price = 200},
+            }
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render(&lua.globals());
+        assert_eq!("[apple:100][banana:200]", rendered.unwrap());
+    }
+
+    #[test]
+    fn test_section_scalar_list() {
+        let template = "{{#tags}}{{.}},{{/tags}}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render_with_lua("tags = {'hoge', 'fuga', 'piyo'}");
+        assert_eq!("hoge,fuga,piyo,", rendered.unwrap());
+    }
+
+    #[test]
+    fn test_section_nested() {
+        let template = "{{#groups}}{{name}}({{#members}}{{.}}{{/members}}){{/groups}}";
+        let script = r#"
+            groups = {
+                {name = "a", members = {1, 2}},
+                {name = "b", members = {}},
+            }
+        "#;
 
         let mut mll = Mll::new();
         mll.set_template(template.to_string());
-        let rendered = mll.render(&table);
-        assert_eq!("Hello, hoge!", rendered.unwrap());
+        let rendered = mll.render_with_lua(script);
+        assert_eq!("a(12)b()", rendered.unwrap());
+    }
 
-        let tags = mll.get_rendered_tags();
-        assert!(tags.contains(&"name".to_string()));
-        assert_eq!(1, tags.len());
+    #[test]
+    fn test_section_single_pass() {
+        let template = "{{#user}}{{name}} ({{title}}){{/user}}{{#missing}}never{{/missing}}";
+        let script = r#"
+            title = "outer"
+            user = {name = "hoge"}
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render_with_lua(script);
+        assert_eq!("hoge (outer)", rendered.unwrap());
+    }
+
+    #[test]
+    fn test_inverted_section() {
+        let template = "{{#items}}{{.}}{{/items}}{{^items}}no items{{/items}}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+
+        assert_eq!("12", mll.render_with_lua("items = {1, 2}").unwrap());
+        assert_eq!("no items", mll.render_with_lua("items = {}").unwrap());
+        assert_eq!("no items", mll.render_with_lua("items = false").unwrap());
+        assert_eq!("no items", mll.render_with_lua("").unwrap());
+    }
+
+    #[test]
+    fn test_if() {
+        let template = "{{#if count > 1}}many{{else}}{{#if count == 1}}one{{/if}}{{/if}}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+
+        assert_eq!("many", mll.render_with_lua("count = 3").unwrap());
+        assert_eq!("one", mll.render_with_lua("count = 1").unwrap());
+        assert_eq!("", mll.render_with_lua("count = 0").unwrap());
+    }
+
+    #[test]
+    fn test_if_with_section_scope() {
+        let template = "{{#users}}{{#if age >= limit}}{{name}} {{/if}}{{/users}}";
+        let script = r#"
+            limit = 20
+            users = {
+                {name = "hoge", age = 20},
+                {name = "fuga", age = 19},
+                {name = "piyo", age = 30},
+            }
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("hoge piyo ", mll.render_with_lua(script).unwrap());
+    }
+
+    #[test]
+    fn test_if_error() {
+        let template = "{{#if count >}}never{{/if}}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert!(mll.render_with_lua("count = 1").is_err());
+    }
+
+    #[test]
+    fn test_path_lua_table() {
+        let template = "{{response.args.foo}},{{items[2].name}},{{items[1].tags[2]}}";
+        let script = r#"
+            response = {args = {foo = "bar"}}
+            items = {
+                {name = "hoge", tags = {"a", "b"}},
+                {name = "fuga", tags = {}},
+            }
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("bar,fuga,b", mll.render_with_lua(script).unwrap());
+    }
+
+    #[test]
+    fn test_path_json() {
+        let template = "{{#users}}{{this.name}}({{address.city}}) {{/users}}{{meta.count}}";
+        let json = serde_json::json!({
+            "users": [
+                {"name": "hoge", "address": {"city": "Tokyo"}},
+                {"name": "fuga", "address": {"city": "Osaka"}},
+            ],
+            "meta": {"count": 2},
+        });
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("hoge(Tokyo) fuga(Osaka) 2", mll.render(&json).unwrap());
+    }
+
+    #[test]
+    fn test_path_hashmap() {
+        let template = "{{user.name}}";
+
+        let mut user = HashMap::new();
+        user.insert("name", "hoge");
+
+        let mut table = HashMap::new();
+        table.insert("user", user);
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("hoge", mll.render(&table).unwrap());
+    }
+
+    #[test]
+    fn test_get_missing_path() {
+        let template = "{{response.args.foo}}{{response.args.bar}}{{items[3]}}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        let rendered = mll.render_with_lua("response = {args = {foo = 'bar'}}; items = {1, 2}");
+        assert_eq!(Some("bar"), rendered.unwrap_err().output());
+
+        let mut missing_variables = mll.get_missing_variables();
+        missing_variables.sort();
+        assert_eq!(vec!["items[3]", "response.args.bar"], missing_variables);
+    }
+
+    #[test]
+    fn test_expression() {
+        let template = r#"{{= name .. "!" }} {{= #items }} {{#items}}{{= price * 2 }},{{/items}}"#;
+        let script = r#"
+            name = "hoge"
+            items = {{price = 100}, {price = 150}}
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("hoge! 2 200,300,", mll.render_with_lua(script).unwrap());
+    }
+
+    #[test]
+    fn test_expression_builtin() {
+        let template = "{{= mll.random.int(1, 2) }}{{= nil }}{{= 1 < 2 }}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!(
+            "1true",
+            mll.render(&HashMap::<&str, String>::new()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_expression_error() {
+        let mut mll = Mll::new();
+
+        mll.set_template("a{{= 1 + }}b".to_string());
+        assert!(matches!(
+            mll.render_with_lua("").unwrap_err(),
+            MllError::Parse(_)
+        ));
+
+        mll.set_template("a{{= undefined_function() }}b".to_string());
+        let e = mll.render_with_lua("").unwrap_err();
+        assert!(matches!(e, MllError::LuaRuntime { .. }));
+        assert_eq!(Some("ab"), e.output());
+        assert!(e.to_string().starts_with("{{= undefined_function() }}: "));
+    }
+
+    #[test]
+    fn test_filters() {
+        let template = r#"{{ name | trim | upper | truncate(limit, "...") }},{{ missing | default("n/a") }},{{ items | json }}"#;
+        let script = r#"
+            name = "  hogehoge  "
+            limit = 4
+            items = {1, 2, 3}
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        assert_eq!("HOGE...,n/a,[1,2,3]", mll.render_with_lua(script).unwrap());
+        assert!(mll.get_missing_variables().is_empty());
+    }
+
+    #[test]
+    fn test_filters_error() {
+        let mut mll = Mll::new();
+
+        mll.set_template("a{{ name | unknown }}b".to_string());
+        let e = mll.render_with_lua("name = 'hoge'").unwrap_err();
+        assert_eq!(Some("ab"), e.output());
+        assert!(e.to_string().contains("unknown filter: unknown"));
+
+        mll.set_template("a{{ name | truncate('x') }}b".to_string());
+        let e = mll.render_with_lua("name = 'hoge'").unwrap_err();
+        assert_eq!(Some("ab"), e.output());
+    }
+
+    #[test]
+    fn test_compiled_template() {
+        let compiled =
+            CompiledTemplate::compile("{{#items}}{{ name | upper }}:{{= n * 2 }} {{/items}}")
+                .unwrap();
+
+        for n in 1..=3 {
+            let lua = Lua::new();
+            lua.load(format!("items = {{{{name = 'hoge', n = {n}}}}}"))
+                .exec()
+                .unwrap();
+
+            let rendered = compiled.render(&lua.globals());
+            assert_eq!(format!("HOGE:{} ", n * 2), rendered.unwrap());
+        }
+
+        assert!(CompiledTemplate::compile("{{#items}}").is_err());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut mll = Mll::new();
+        mll.set_template_name("hello.txt".to_string());
+        mll.set_template("Hello,\n{{#items}}{{ name }}".to_string());
+
+        let e = mll.compile().err().unwrap();
+        let diagnostic = &e.diagnostics()[0];
+        assert_eq!("hello.txt", diagnostic.template_name());
+        assert_eq!((2, 1), (diagnostic.line(), diagnostic.column()));
+
+        let e = mll
+            .render(&HashMap::<&str, String>::new())
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("error: unclosed section: items"));
+        assert!(e.contains("--> hello.txt:2:1"));
+    }
+
+    #[test]
+    fn test_load_template_error() {
+        let mut mll = Mll::new();
+
+        let e = mll.load_template("not_found.txt").unwrap_err();
+        assert!(matches!(e, MllError::Io { .. }));
+        assert!(e.to_string().starts_with("failed to read not_found.txt: "));
+    }
+
+    #[test]
+    fn test_render_after_set_template() {
+        let mut table = HashMap::new();
+        table.insert("name", "hoge".to_string());
+
+        let mut mll = Mll::new();
+        mll.set_template("Hello, {{name}}!".to_string());
+        assert_eq!("Hello, hoge!", mll.render(&table).unwrap());
+
+        mll.set_template("Bye, {{name}}!".to_string());
+        assert_eq!("Bye, hoge!", mll.render(&table).unwrap());
+    }
+
+    #[test]
+    fn test_missing_variable_policy() {
+        let template = "{{hello}}, {{ name }}!";
+        let script = r#"
+            function missing(name)
+                if name == "hello" then
+                    return "Hi"
+                end
+            end
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+
+        let e = mll.render_with_lua("").unwrap_err();
+        assert_eq!(Some(", !"), e.output());
+        assert_eq!(&["hello", "name"], e.missing_variables());
+
+        mll.set_missing_variable_policy(MissingVariablePolicy::Empty);
+        assert_eq!(", !", mll.render_with_lua("").unwrap());
+
+        mll.set_missing_variable_policy(MissingVariablePolicy::Keep);
+        assert_eq!(template, mll.render_with_lua("").unwrap());
+
+        mll.set_missing_variable_policy(MissingVariablePolicy::Default("?".to_string()));
+        assert_eq!("?, hoge!", mll.render_with_lua("name = 'hoge'").unwrap());
+
+        mll.reset();
+        mll.set_missing_variable_policy(MissingVariablePolicy::callback(|name| {
+            Some(name.to_uppercase())
+        }));
+        assert_eq!("HELLO, NAME!", mll.render_with_lua("").unwrap());
+
+        mll.set_missing_variable_policy(MissingVariablePolicy::Lua("missing".to_string()));
+        let e = mll.render_with_lua(script).unwrap_err();
+        assert_eq!(Some("Hi, !"), e.output());
+        assert_eq!(&["name"], e.missing_variables());
+
+        assert!(mll.get_missing_variables().contains(&"hello".to_string()));
+    }
+
+    #[test]
+    fn test_pre_process_error() {
+        let mut mll = Mll::new();
+        mll.set_template("{{name}}".to_string());
+        mll.set_capabilities(Capabilities::sandboxed());
+        mll.set_pre_process_script(
+            "name = 'hoge'\n\nlocal function read()\n  local text = \
+             mll.template.include('Cargo.toml')\n  return text\nend\nread()"
+                .to_string(),
+        );
+
+        let (rendered, report) = mll.render_lua_globals_with_report();
+        let e = rendered.unwrap_err();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        let location = e.location().unwrap();
+        assert_eq!(("pre_process", 4), (location.chunk(), location.line()));
+        assert_eq!(
+            Some("local text = mll.template.include('Cargo.toml')"),
+            location.source_line()
+        );
+        assert_eq!(
+            vec![4, 7],
+            location
+                .traceback()
+                .iter()
+                .map(StackFrame::line)
+                .collect::<Vec<_>>()
+        );
+        assert!(report.pre_process_time().is_some());
+
+        mll.set_pre_process_script_name("hoge.lua".to_string());
+        mll.set_pre_process_script("name = 'hoge'\nname = ".to_string());
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(e, MllError::LuaSyntax { .. }));
+        let location = e.location().unwrap();
+        assert_eq!(("hoge.lua", 2), (location.chunk(), location.line()));
+        assert_eq!(Some("name ="), location.source_line());
+
+        let e = mll.load_pre_process_script("not_found.lua").unwrap_err();
+        assert!(matches!(e, MllError::Io { .. }));
+        assert_eq!("hoge.lua", mll.pre_process_script_name());
+    }
+
+    #[test]
+    fn test_post_process() {
+        let mut mll = Mll::new();
+        mll.set_template("{{name}} {{ age | default(0) }}".to_string());
+        mll.set_pre_process_script("name = 'hoge'".to_string());
+        mll.set_post_process_script(
+            "local output, report = ...\nprint(report.tags[1].source, #report.missing)\n\
+             return output:upper()"
+                .to_string(),
+        );
+
+        let (rendered, report) = mll.render_lua_globals_with_report();
+        assert_eq!("HOGE 0", rendered.unwrap());
+        assert_eq!(&["lua_global\t0".to_string()], report.prints());
+        assert!(report.post_process_time().is_some());
+
+        // globals of the pre-process script are visible, and `nil` keeps the output
+        mll.set_post_process_script("assert(name == 'hoge')".to_string());
+        let mut table = HashMap::new();
+        table.insert("name", "fuga".to_string());
+        assert_eq!("fuga 0", mll.render(&table).unwrap());
+
+        mll.set_post_process_script(
+            "local output = ...\nif #output > 3 then error('too long') end".to_string(),
+        );
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(e, MllError::LuaRuntime { .. }));
+        assert_eq!(Some("hoge 0"), e.output());
+        let location = e.location().unwrap();
+        assert_eq!(("post_process", 2), (location.chunk(), location.line()));
+
+        // the output is kept by any error of the post-process script
+        mll.set_post_process_script("mll.random.int(2, 1)".to_string());
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        assert_eq!(Some("hoge 0"), e.output());
+
+        mll.set_post_process_script("x = = 1".to_string());
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(e, MllError::LuaSyntax { .. }));
+        assert_eq!(Some("hoge 0"), e.output());
+    }
+
+    #[test]
+    fn test_session() {
+        let mut table = HashMap::new();
+        table.insert("name", "hoge".to_string());
+
+        let mut mll = Mll::new();
+        mll.set_template("{{= prefix .. name }}{{ suffix }}".to_string());
+        mll.set_pre_process_script("count = (count or 0) + 1".to_string());
+
+        mll.run_script("prefix = '<'").unwrap();
+        assert!(mll.run_script("error('hoge')").is_err());
+        assert_eq!("<hoge", mll.render(&table).unwrap_err().output().unwrap());
+
+        assert_eq!(
+            "<fuga>",
+            mll.render_with_lua("suffix = '>'; name = 'fuga'").unwrap()
+        );
+        assert_eq!("<fuga>", mll.render_with_lua("").unwrap());
+        assert_eq!("<hoge>", mll.render(&table).unwrap());
+
+        let _ = mll.render_lua_globals();
+        let _ = mll.render_lua_globals();
+        assert_eq!(2, mll.engine().eval::<i64>("count").unwrap());
+
+        mll.reset();
+        assert!(
+            mll.engine()
+                .eval::<Option<String>>("prefix")
+                .unwrap()
+                .is_none()
+        );
+        assert!(mll.get_rendered_tags().is_empty());
+    }
+
+    #[test]
+    fn test_sandboxed() {
+        let template = "{{= mll.template.include('src/lib.rs') ~= '' }}{{ message }}";
+        let script = r#"
+            local ok, e = pcall(mll.process.exec, "rustc", {"--version"})
+            message = tostring(e):match("[^\n]*")
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        mll.set_capabilities(Capabilities::sandboxed().allow_read("src"));
+        assert_eq!(
+            "trueexec: executing rustc is not allowed",
+            mll.render_with_lua(script).unwrap()
+        );
+
+        let e = mll
+            .run_script("mll.template.include('Cargo.toml')")
+            .unwrap_err();
+        assert!(matches!(e, MllError::Builtin { .. }));
+        assert_eq!("include: reading Cargo.toml is not allowed", e.to_string());
+
+        let e = mll
+            .run_script("mll.template.render('{{name}}', 'Cargo.toml')")
+            .unwrap_err();
+        assert_eq!("render: reading Cargo.toml is not allowed", e.to_string());
+
+        for script in [
+            "os.exit(1)",
+            "io.open('Cargo.toml')",
+            "dofile('Cargo.toml')",
+        ] {
+            assert!(mll.run_script(script).is_err());
+        }
+    }
+
+    struct Upper;
+
+    impl BuiltinFunction for Upper {
+        fn get_name(&self) -> &str {
+            "upper"
+        }
+
+        fn get_function(&self, lua: &Lua) -> mlua::Function {
+            lua.create_function(|_, s: String| Ok(s.to_uppercase()))
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn test_register_builtin() {
+        let mut mll = Mll::new();
+        mll.set_template("{{= upper(name) }} {{= text.upper(name) }}".to_string());
+
+        mll.register_builtin(Upper);
+        mll.register_builtin_in("text", Upper);
+        mll.run_script("name = 'hoge'").unwrap();
+        assert_eq!("HOGE HOGE", mll.render_with_lua("").unwrap());
+
+        let (_, report) = mll.render_with_report(&HashMap::<&str, String>::new());
+        assert_eq!(1, report.builtins()["upper"].calls);
+        assert_eq!(1, report.builtins()["text.upper"].calls);
+
+        mll.remove_builtin("upper");
+        assert!(mll.run_script("upper('x')").is_err());
+        assert!(mll.run_script("x = text.upper('x')").is_ok());
+    }
+
+    #[test]
+    fn test_set_variable() {
+        #[derive(Serialize)]
+        struct Item {
+            id: u64,
+            price: f64,
+            note: Option<String>,
+            tags: Vec<Vec<&'static str>>,
+        }
+
+        let item = Item {
+            id: 1,
+            price: 9.5,
+            note: None,
+            tags: vec![vec!["a", "b"], vec![]],
+        };
+
+        let mut mll = Mll::new();
+        mll.set_template(
+            "{{id}} {{price}} {{tags[1][2]}} {{note}} {{#if note}}note{{else}}none{{/if}}"
+                .to_string(),
+        );
+        mll.set_missing_variable_policy(MissingVariablePolicy::Default("-".to_string()));
+        mll.set_pre_process_script("id = id + 1; price = price * 2".to_string());
+
+        mll.set_variable("id", &item.id).unwrap();
+        mll.set_context(&item).unwrap();
+        assert_eq!("2 19 b - none", mll.render_lua_globals().unwrap());
+
+        assert!(
+            mll.engine()
+                .eval::<bool>("math.type(id) == 'integer' and #tags[2] == 0")
+                .unwrap()
+        );
+
+        let mut mll = Mll::new();
+        mll.set_template(
+            "{{#if x}}x{{/if}}{{#if y}}y{{/if}}{{= x == nil and y == nil }}".to_string(),
+        );
+        mll.set_variable("x", &None::<String>).unwrap();
+        mll.set_variable("y", &()).unwrap();
+        assert_eq!("true", mll.render_lua_globals().unwrap());
+    }
+
+    #[test]
+    fn test_render_values() {
+        let mut mll = Mll::new();
+        mll.set_template(
+            r#"{"age": {{age}}, "ratio": {{ratio}}, "ok": {{ok}}, "user": {{user}}}"#.to_string(),
+        );
+
+        let json = serde_json::json!({
+            "age": 20,
+            "ratio": 0.125,
+            "ok": false,
+            "user": {"name": "hoge", "tags": ["a"]},
+        });
+        assert_eq!(
+            r#"{"age": 20, "ratio": 0.125, "ok": false, "user": {"name":"hoge","tags":["a"]}}"#,
+            mll.render(&json).unwrap()
+        );
+
+        let script = r#"
+            age = 40 / 2
+            ratio = 1 / 3
+            ok = true
+            user = setmetatable({}, {__tostring = function() return '"hoge"' end})
+        "#;
+        mll.set_value_format(ValueFormat::new().float_precision(3));
+        assert_eq!(
+            r#"{"age": 20.000, "ratio": 0.333, "ok": true, "user": "hoge"}"#,
+            mll.render_with_lua(script).unwrap()
+        );
+
+        mll.set_template("{{= items }} {{= 1 / 4 }} {{= nil }}".to_string());
+        assert_eq!(
+            "[1,2] 0.250 ",
+            mll.render_with_lua("items = {1, 2}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let deterministic = Deterministic::new(7, std::time::UNIX_EPOCH);
+        let template = "{{= mll.random.uuid() }} {{= math.random(1000000) }} {{= os.clock() }}";
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        mll.set_deterministic(Some(deterministic));
+        let first = mll.render_with_lua("").unwrap();
+        assert_ne!(first, mll.render_with_lua("").unwrap());
+
+        // a new session starts from the seed again
+        mll.reset();
+        assert_eq!(first, mll.render_with_lua("").unwrap());
+
+        let compiled = mll.compile().unwrap();
+        assert_eq!(
+            first,
+            compiled.render(&HashMap::<&str, String>::new()).unwrap()
+        );
+
+        mll.set_deterministic(None);
+        assert_ne!(first, mll.render_with_lua("").unwrap());
+    }
+
+    #[test]
+    fn test_render_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let mut mll = Mll::new();
+        mll.set_template(
+            "{{version}} {{= mll.process.exec('rustc', {'--version'}).code }}".to_string(),
+        );
+        mll.set_pre_process_script(
+            "version = mll.process.exec('rustc', {'--version'}).stdout:match('^rustc')".to_string(),
+        );
+        mll.set_limits(Limits::new().instructions(1_000_000));
+
+        let rendered = runtime.block_on(mll.render_lua_globals_async());
+        assert_eq!("rustc 0", rendered.unwrap());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        // the blocking API does not nest runtimes
+        let rendered = runtime.block_on(async { mll.render_with_lua("version = 'fuga'") });
+        assert_eq!("fuga 0", rendered.unwrap());
+
+        mll.set_template("{{= (function() while true do end end)() }}".to_string());
+        let e = runtime.block_on(mll.render_with_lua_async("")).unwrap_err();
+        assert!(matches!(
+            e,
+            MllError::LimitExceeded {
+                limit: Limit::Instructions,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_on_current_thread() {
+        let mut mll = Mll::new();
+        mll.set_template("{{name}}".to_string());
+
+        let e = mll.render_with_lua("name = 'hoge'").unwrap_err();
+        assert!(matches!(e, MllError::CurrentThreadRuntime));
+        let (rendered, _) = mll.render_with_report(&HashMap::<&str, String>::new());
+        assert!(matches!(rendered, Err(MllError::CurrentThreadRuntime)));
+
+        assert_eq!(
+            "hoge",
+            mll.render_with_lua_async("name = 'hoge'").await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_legacy_globals() {
+        let mut mll = Mll::new();
+        mll.set_template("{{= type(random_int) }} {{= mll.random.int(1, 2) }}".to_string());
+        assert_eq!("nil 1", mll.render_with_lua("").unwrap());
+
+        mll.set_legacy_globals(true);
+        assert_eq!("function 1", mll.render_with_lua("").unwrap());
+        assert!(mll.run_script("x = json_to_table('{}')").is_ok());
+
+        // replaced builtins are also set as the legacy globals
+        mll.remove_builtin_in("mll.random", "int");
+        assert!(mll.run_script("x = random_int(1, 2)").is_err());
+
+        mll.set_legacy_globals(false);
+        assert!(mll.run_script("x = json_to_table('{}')").is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut mll = Mll::new();
+        mll.set_template("{{name}}{{= (function() while true do end end)() }}".to_string());
+        mll.set_pre_process_script("name = 'hoge'".to_string());
+        mll.set_limits(Limits::new().instructions(100_000));
+
+        let e = mll.render_lua_globals().unwrap_err();
+        assert!(matches!(
+            e,
+            MllError::LimitExceeded {
+                limit: Limit::Instructions,
+                ..
+            }
+        ));
+        assert_eq!(Some("hoge"), e.output());
+        assert_eq!(
+            "{{= (function() while true do end end)() }}: instruction limit exceeded",
+            e.to_string()
+        );
+
+        // each render has its own budget
+        mll.set_template("{{name}}".to_string());
+        assert_eq!("hoge", mll.render_lua_globals().unwrap());
+
+        mll.set_limits(Limits::new().wall_time(std::time::Duration::from_millis(100)));
+        mll.set_pre_process_script(
+            "while true do pcall(function() while true do end end) end".to_string(),
+        );
+        let (rendered, report) = mll.render_lua_globals_with_report();
+        let e = rendered.unwrap_err();
+        assert!(matches!(
+            e,
+            MllError::LimitExceeded {
+                limit: Limit::WallTime,
+                tag: None,
+                ..
+            }
+        ));
+        assert!(report.pre_process_time().is_some());
+
+        mll.set_limits(Limits::new());
+        assert_eq!("hoge", mll.render_with_lua("").unwrap());
+    }
+
+    #[test]
+    fn test_render_report() {
+        let template = "{{name}}\n{{#items}}{{name}}{{/items}} {{ age | default(0) }}{{hoge}}";
+        let script = r#"
+            name = "hoge"
+            items = {{name = "fuga"}, {}}
+            warn("@on")
+            warn("something wrong")
+        "#;
+
+        let mut mll = Mll::new();
+        mll.set_template(template.to_string());
+        mll.set_pre_process_script(script.to_string());
+        mll.set_missing_variable_policy(MissingVariablePolicy::Keep);
+
+        let (rendered, report) = mll.render_lua_globals_with_report();
+        assert_eq!("hoge\nfugahoge 0{{hoge}}", rendered.unwrap());
+
+        let tags = report
+            .tags()
+            .iter()
+            .map(|tag| {
+                (
+                    tag.name(),
+                    tag.line(),
+                    tag.column(),
+                    tag.source(),
+                    tag.value(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("name", 1, 1, ValueSource::LuaGlobal, Some("hoge")),
+                ("name", 2, 11, ValueSource::Section, Some("fuga")),
+                ("name", 2, 11, ValueSource::LuaGlobal, Some("hoge")),
+                ("age", 2, 30, ValueSource::Filter, Some("0")),
+                ("hoge", 2, 52, ValueSource::Default, Some("{{hoge}}")),
+            ],
+            tags
+        );
+        assert_eq!(vec!["hoge"], report.missing());
+        assert_eq!(vec!["hoge".to_string()], mll.get_missing_variables());
+        assert_eq!(
+            vec!["name".to_string(), "age".to_string(), "hoge".to_string()],
+            mll.get_rendered_tags()
+        );
+        assert_eq!(&["something wrong".to_string()], report.warnings());
     }
 
     #[test]
@@ -477,7 +2360,10 @@ mod tests {
         let mut mll = Mll::new();
         mll.set_template(template.to_string());
         let rendered = mll.render(&table);
-        assert!(rendered.is_err());
+        assert_eq!(
+            &["hello".to_string()],
+            rendered.unwrap_err().missing_variables()
+        );
 
         let missing_variables = mll.get_missing_variables();
         assert_eq!(1, missing_variables.len());
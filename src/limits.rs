@@ -0,0 +1,413 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mlua::{FromLuaMulti, Function, HookTriggers, IntoLuaMulti, Lua, Thread, VmState};
+
+use crate::MllError;
+
+/// Instructions between checks of the instruction count and the wall time
+const CHECK_INTERVAL: u32 = 1000;
+
+/// Limits of a render, including the scripts run by the render
+///
+/// The instruction count and the wall time are checked every 1000 Lua instructions, and the
+/// wall time is also checked before and after each builtin (`exec` is killed at the deadline).
+/// A render over a limit fails with `MllError::LimitExceeded`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use libmll::{Limit, Limits, Mll, MllError};
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("{{name}}".to_string());
+/// mll.set_pre_process_script("name = 'hoge'; while true do end".to_string());
+/// mll.set_limits(Limits::new().wall_time(Duration::from_millis(100)));
+///
+/// let e = mll.render_lua_globals().unwrap_err();
+/// assert!(matches!(e, MllError::LimitExceeded { limit: Limit::WallTime, .. }));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    instructions: Option<u64>,
+    memory: Option<usize>,
+    wall_time: Option<Duration>,
+}
+
+impl Limits {
+    /// Create limits without any limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit number of Lua instructions
+    ///
+    /// # Arguments
+    ///
+    /// `instructions: u64` - Maximum number of instructions
+    pub fn instructions(mut self, instructions: u64) -> Self {
+        self.instructions = Some(instructions);
+        self
+    }
+
+    /// Limit size of the Lua heap
+    ///
+    /// # Arguments
+    ///
+    /// `bytes: usize` - Maximum size of the heap in bytes
+    pub fn memory(mut self, bytes: usize) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    /// Limit total time of the render
+    ///
+    /// # Arguments
+    ///
+    /// `wall_time: Duration` - Maximum time, including time spent in builtins
+    pub fn wall_time(mut self, wall_time: Duration) -> Self {
+        self.wall_time = Some(wall_time);
+        self
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.instructions.is_none() && self.memory.is_none() && self.wall_time.is_none()
+    }
+}
+
+/// Limit which is exceeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    Memory,
+    WallTime,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Instructions => write!(f, "instruction limit"),
+            Limit::Memory => write!(f, "memory limit"),
+            Limit::WallTime => write!(f, "time limit"),
+        }
+    }
+}
+
+/// Limits being applied to a Lua state
+struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    /// State of the hook, `None` if neither instructions nor wall time are limited
+    hook: Option<Rc<HookState>>,
+    /// Threads running Lua code with the hook, the innermost last
+    threads: RefCell<Vec<Thread>>,
+}
+
+/// State of the hook, shared by the main thread and the threads running Lua code
+struct HookState {
+    instructions: Option<u64>,
+    deadline: Option<Instant>,
+    interval: u32,
+    executed: Cell<u64>,
+}
+
+impl HookState {
+    fn check(&self, lua: &Lua) -> mlua::Result<VmState> {
+        self.executed
+            .set(self.executed.get() + self.interval as u64);
+
+        if self.instructions.is_some_and(|n| self.executed.get() > n) {
+            return exceed(lua, Limit::Instructions);
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return exceed(lua, Limit::WallTime);
+        }
+
+        Ok(VmState::Continue)
+    }
+
+    fn triggers(&self) -> HookTriggers {
+        HookTriggers::new().every_nth_instruction(self.interval)
+    }
+}
+
+/// Start applying limits to the Lua state
+pub(crate) fn start_limits(lua: &Lua, limits: &Limits) {
+    if limits.is_unlimited() {
+        return;
+    }
+
+    if let Some(memory) = limits.memory {
+        let _ = lua.set_memory_limit(memory);
+    }
+
+    let deadline = limits.wall_time.map(|wall_time| Instant::now() + wall_time);
+    let hook = (limits.instructions.is_some() || deadline.is_some()).then(|| {
+        let interval = limits
+            .instructions
+            .map_or(CHECK_INTERVAL, |n| n.clamp(1, CHECK_INTERVAL as u64) as u32);
+
+        Rc::new(HookState {
+            instructions: limits.instructions,
+            deadline,
+            interval,
+            executed: Cell::new(0),
+        })
+    });
+
+    if let Some(hook) = &hook {
+        let state = hook.clone();
+        lua.set_hook(hook.triggers(), move |lua, _| state.check(lua));
+    }
+
+    lua.set_app_data(Budget {
+        limits: *limits,
+        deadline,
+        hook,
+        threads: RefCell::new(Vec::new()),
+    });
+}
+
+/// Raise the error on every instruction, so that `pcall` cannot keep the script running
+fn exceed(lua: &Lua, limit: Limit) -> mlua::Result<VmState> {
+    lua.set_hook(HookTriggers::new().every_nth_instruction(1), move |_, _| {
+        Err(limit_error(limit))
+    });
+
+    Err(limit_error(limit))
+}
+
+/// Stop applying limits to the Lua state
+pub(crate) fn stop_limits(lua: &Lua) {
+    if lua.remove_app_data::<Budget>().is_none() {
+        return;
+    }
+
+    lua.remove_hook();
+    let _ = lua.set_memory_limit(0);
+}
+
+/// Limits applied to a Lua state until the guard is dropped
+pub(crate) struct LimitGuard {
+    lua: Lua,
+}
+
+impl LimitGuard {
+    /// Start applying limits to the Lua state (see `start_limits`)
+    pub(crate) fn new(lua: &Lua, limits: &Limits) -> Self {
+        start_limits(lua, limits);
+
+        Self { lua: lua.clone() }
+    }
+}
+
+impl Drop for LimitGuard {
+    fn drop(&mut self) {
+        stop_limits(&self.lua);
+    }
+}
+
+/// Call a function in a new Lua thread (coroutine), where async builtins can wait
+///
+/// A hook of the Lua state applies only to one thread, so the hook of the limits is set on the
+/// new thread while it runs, and set back on the outer thread after that.
+///
+/// # Arguments
+///
+/// * `lua` - The Lua context
+/// * `function` - The function
+/// * `args` - Arguments of the function
+///
+/// # Returns
+///
+/// `mlua::Result<R>` - Values returned by the function
+pub(crate) async fn call_limited<R>(
+    lua: &Lua,
+    function: Function,
+    args: impl IntoLuaMulti,
+) -> mlua::Result<R>
+where
+    R: FromLuaMulti,
+{
+    let thread = lua.create_thread(function)?;
+    let hooked = push_thread(lua, &thread);
+
+    let result = thread.into_async::<R>(args).await;
+
+    if hooked {
+        pop_thread(lua);
+    }
+
+    result
+}
+
+/// Set the hook of the limits on the thread, `false` if no hook is being applied
+fn push_thread(lua: &Lua, thread: &Thread) -> bool {
+    let Some(budget) = lua.app_data_ref::<Budget>() else {
+        return false;
+    };
+    let Some(hook) = &budget.hook else {
+        return false;
+    };
+
+    let state = hook.clone();
+    let _ = thread.set_hook(hook.triggers(), move |lua, _| state.check(lua));
+    budget.threads.borrow_mut().push(thread.clone());
+
+    true
+}
+
+/// Set the hook of the limits back on the outer thread (or the main thread)
+fn pop_thread(lua: &Lua) {
+    let Some(budget) = lua.app_data_ref::<Budget>() else {
+        return;
+    };
+    let Some(hook) = &budget.hook else {
+        return;
+    };
+
+    let mut threads = budget.threads.borrow_mut();
+    threads.pop();
+
+    let state = hook.clone();
+    match threads.last() {
+        Some(thread) => {
+            let _ = thread.set_hook(hook.triggers(), move |lua, _| state.check(lua));
+        }
+        None => lua.set_hook(hook.triggers(), move |lua, _| state.check(lua)),
+    }
+}
+
+/// Make an error of the exceeded limit
+pub(crate) fn limit_error(limit: Limit) -> mlua::Error {
+    mlua::Error::external(MllError::LimitExceeded {
+        limit,
+        tag: None,
+        output: None,
+    })
+}
+
+/// Get deadline of the wall time being applied to the Lua state
+pub(crate) fn deadline(lua: &Lua) -> Option<Instant> {
+    lua.app_data_ref::<Budget>()
+        .and_then(|budget| budget.deadline)
+}
+
+/// Raise an error if the deadline of the Lua state is passed
+pub(crate) fn check_deadline(lua: &Lua) -> mlua::Result<()> {
+    match deadline(lua) {
+        Some(deadline) if Instant::now() >= deadline => Err(limit_error(Limit::WallTime)),
+        _ => Ok(()),
+    }
+}
+
+/// Get limits being applied to the Lua state, with the rest of the wall time
+pub(crate) fn remaining_limits(lua: &Lua) -> Limits {
+    match lua.app_data_ref::<Budget>() {
+        Some(budget) => Limits {
+            wall_time: budget
+                .deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            ..budget.limits
+        },
+        None => Limits::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::do_blocking;
+
+    fn run(lua: &Lua, limits: &Limits, script: &str) -> Result<(), MllError> {
+        start_limits(lua, limits);
+        let result = lua.load(script).exec();
+        stop_limits(lua);
+
+        result.map_err(MllError::from)
+    }
+
+    fn exceeded(result: Result<(), MllError>) -> Option<Limit> {
+        match result {
+            Err(MllError::LimitExceeded { limit, .. }) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_instructions() {
+        let lua = Lua::new();
+        let limits = Limits::new().instructions(10_000);
+
+        assert!(run(&lua, &limits, "for i = 1, 100 do end").is_ok());
+        assert_eq!(
+            Some(Limit::Instructions),
+            exceeded(run(&lua, &limits, "while true do end"))
+        );
+
+        // pcall cannot catch the limit
+        let script = "while true do pcall(function() while true do end end) end";
+        assert_eq!(
+            Some(Limit::Instructions),
+            exceeded(run(&lua, &limits, script))
+        );
+
+        // limits are removed
+        assert!(lua.load("for i = 1, 100000 do end").exec().is_ok());
+    }
+
+    #[test]
+    fn test_memory() {
+        let lua = Lua::new();
+        let limits = Limits::new().memory(lua.used_memory() + 1024 * 1024);
+
+        let script = "local t = {} for i = 1, 1000000 do t[i] = tostring(i) end";
+        assert_eq!(Some(Limit::Memory), exceeded(run(&lua, &limits, script)));
+        assert!(lua.load(script).exec().is_ok());
+    }
+
+    #[test]
+    fn test_wall_time() {
+        let lua = Lua::new();
+        let limits = Limits::new().wall_time(Duration::from_millis(50));
+
+        start_limits(&lua, &limits);
+        assert!(check_deadline(&lua).is_ok());
+        assert!(remaining_limits(&lua).wall_time.unwrap() <= Duration::from_millis(50));
+        stop_limits(&lua);
+
+        assert_eq!(
+            Some(Limit::WallTime),
+            exceeded(run(&lua, &limits, "while true do end"))
+        );
+        assert!(check_deadline(&lua).is_ok());
+    }
+
+    #[test]
+    fn test_threads() {
+        let lua = Lua::new();
+        let limits = Limits::new().instructions(10_000);
+        let call = |script: &str| {
+            let function = lua.load(script).into_function().unwrap();
+            do_blocking(call_limited::<()>(&lua, function, ()))
+                .unwrap()
+                .map_err(MllError::from)
+        };
+
+        start_limits(&lua, &limits);
+        assert!(call("for i = 1, 100 do end").is_ok());
+        assert_eq!(
+            Some(Limit::Instructions),
+            exceeded(call("while true do end"))
+        );
+        stop_limits(&lua);
+
+        assert!(call("for i = 1, 100000 do end").is_ok());
+    }
+}
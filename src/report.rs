@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use mlua::{Lua, Table};
+
+use crate::template::renderer::RenderResult;
+
+/// Where the value of a tag came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    /// Context passed from Rust (e.g. `HashMap` or `serde_json::Value`)
+    Context,
+    /// Lua global of the pre-process script
+    LuaGlobal,
+    /// Current item of a section (e.g. `{{name}}` in `{{#items}}...{{/items}}`)
+    Section,
+    /// Value is missing, rendered by filters (e.g. `{{ name | default("n/a") }}`)
+    Filter,
+    /// Value is missing, rendered by the missing variable policy
+    Default,
+    /// Value is missing and nothing is rendered
+    Missing,
+}
+
+impl ValueSource {
+    /// Whether the variable is not found, and the missing variable policy is applied
+    pub(crate) fn is_missing(self) -> bool {
+        matches!(self, ValueSource::Default | ValueSource::Missing)
+    }
+}
+
+/// Occurrence of a variable tag in the template
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagReport {
+    name: String,
+    text: String,
+    line: usize,
+    column: usize,
+    source: ValueSource,
+    value: Option<String>,
+}
+
+impl TagReport {
+    pub(crate) fn new(
+        name: &str,
+        text: &str,
+        (line, column): (usize, usize),
+        source: ValueSource,
+        value: Option<String>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            line,
+            column,
+            source,
+            value,
+        }
+    }
+
+    /// Get name (path) of the variable (e.g. `response.args.foo`)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get original text of the tag (e.g. `{{ name | upper }}`)
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Get 1-based line number of the tag
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Get 1-based column number (in characters) of the tag
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn source(&self) -> ValueSource {
+        self.source
+    }
+
+    /// Get rendered text, `None` if the variable is missing
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
+
+/// Number of calls and total time spent in a builtin
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BuiltinTiming {
+    pub calls: usize,
+    pub total: Duration,
+}
+
+/// Report of a render
+///
+/// # Examples
+///
+/// ```
+/// use libmll::{Mll, ValueSource};
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("{{name}} {{name}} {{age}}".to_string());
+/// mll.set_pre_process_script("name = 'hoge'; print(name)".to_string());
+///
+/// let (rendered, report) = mll.render_lua_globals_with_report();
+/// assert!(rendered.is_err());
+///
+/// assert_eq!(3, report.tags().len());
+/// assert_eq!(ValueSource::LuaGlobal, report.tags()[1].source());
+/// assert_eq!((1, 10), (report.tags()[1].line(), report.tags()[1].column()));
+/// assert_eq!(vec!["age"], report.missing());
+/// assert_eq!(&["hoge".to_string()], report.prints());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RenderReport {
+    tags: Vec<TagReport>,
+    prints: Vec<String>,
+    warnings: Vec<String>,
+    pre_process_time: Option<Duration>,
+    post_process_time: Option<Duration>,
+    render_time: Duration,
+    builtins: BTreeMap<String, BuiltinTiming>,
+}
+
+impl RenderReport {
+    /// Make report from a result of rendering and records taken from the Lua state
+    pub(crate) fn new(result: &RenderResult, records: Records, render_time: Duration) -> Self {
+        let mut warnings = records.warnings;
+        warnings.extend(
+            result
+                .errors
+                .iter()
+                .map(|e| format!("{}: {}", e.tag, e.error)),
+        );
+
+        Self {
+            tags: result.tags.clone(),
+            prints: records.prints,
+            warnings,
+            pre_process_time: None,
+            post_process_time: None,
+            render_time,
+            builtins: records.builtins,
+        }
+    }
+
+    /// Make report of a render which failed before rendering (e.g. parse error)
+    pub(crate) fn from_records(records: Records) -> Self {
+        Self {
+            prints: records.prints,
+            warnings: records.warnings,
+            builtins: records.builtins,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_pre_process_time(&mut self, time: Duration) {
+        self.pre_process_time = Some(time);
+    }
+
+    pub(crate) fn set_post_process_time(&mut self, time: Duration) {
+        self.post_process_time = Some(time);
+    }
+
+    /// Add records collected after rendering (e.g. by the post-process script)
+    pub(crate) fn add_records(&mut self, records: Records) {
+        self.prints.extend(records.prints);
+        self.warnings.extend(records.warnings);
+
+        for (name, timing) in records.builtins {
+            let total = self.builtins.entry(name).or_default();
+            total.calls += timing.calls;
+            total.total += timing.total;
+        }
+    }
+
+    /// Convert the report into a Lua table
+    ///
+    /// Times are in seconds, and sources of the tags are in snake case (e.g. `lua_global`).
+    /// `post_process_time` is `nil` in the report passed to the post-process script, which is
+    /// still running.
+    pub(crate) fn to_lua(&self, lua: &Lua) -> mlua::Result<Table> {
+        let tags = lua.create_table()?;
+        for tag in &self.tags {
+            let table = lua.create_table()?;
+            table.set("name", tag.name())?;
+            table.set("text", tag.text())?;
+            table.set("line", tag.line())?;
+            table.set("column", tag.column())?;
+            table.set("source", source_name(tag.source()))?;
+            table.set("value", tag.value())?;
+            tags.push(table)?;
+        }
+
+        let builtins = lua.create_table()?;
+        for (name, timing) in &self.builtins {
+            let table = lua.create_table()?;
+            table.set("calls", timing.calls)?;
+            table.set("total", timing.total.as_secs_f64())?;
+            builtins.set(name.as_str(), table)?;
+        }
+
+        let report = lua.create_table()?;
+        report.set("tags", tags)?;
+        report.set("missing", lua.create_sequence_from(self.missing())?)?;
+        report.set(
+            "prints",
+            lua.create_sequence_from(self.prints.iter().map(String::as_str))?,
+        )?;
+        report.set(
+            "warnings",
+            lua.create_sequence_from(self.warnings.iter().map(String::as_str))?,
+        )?;
+        report.set(
+            "pre_process_time",
+            self.pre_process_time.map(|time| time.as_secs_f64()),
+        )?;
+        report.set(
+            "post_process_time",
+            self.post_process_time.map(|time| time.as_secs_f64()),
+        )?;
+        report.set("render_time", self.render_time.as_secs_f64())?;
+        report.set("builtins", builtins)?;
+
+        Ok(report)
+    }
+
+    /// Get every occurrence of variable tags, in order of rendering
+    pub fn tags(&self) -> &[TagReport] {
+        &self.tags
+    }
+
+    /// Get names of the missing variables, without duplicates
+    ///
+    /// A variable is missing if none of its tags is found, even if the missing variable policy
+    /// rendered a text for it (same as `Mll::get_missing_variables`).
+    pub fn missing(&self) -> Vec<&str> {
+        let mut missing = Vec::new();
+
+        for tag in &self.tags {
+            let name = tag.name();
+            if !missing.contains(&name)
+                && self
+                    .tags
+                    .iter()
+                    .filter(|tag| tag.name() == name)
+                    .all(|tag| tag.source.is_missing())
+            {
+                missing.push(name);
+            }
+        }
+
+        missing
+    }
+
+    /// Get lines printed by `print` in Lua
+    pub fn prints(&self) -> &[String] {
+        &self.prints
+    }
+
+    /// Get warnings by `warn` in Lua and errors of tags
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Get time spent in the pre-process script, `None` if no script is run
+    pub fn pre_process_time(&self) -> Option<Duration> {
+        self.pre_process_time
+    }
+
+    /// Get time spent in the post-process script, `None` if no script is run
+    pub fn post_process_time(&self) -> Option<Duration> {
+        self.post_process_time
+    }
+
+    /// Get time spent in rendering the template (including expressions and filters)
+    pub fn render_time(&self) -> Duration {
+        self.render_time
+    }
+
+    /// Get calls and time spent in each builtin, by name
+    pub fn builtins(&self) -> &BTreeMap<String, BuiltinTiming> {
+        &self.builtins
+    }
+}
+
+/// Get name of the source of a value in Lua
+fn source_name(source: ValueSource) -> &'static str {
+    match source {
+        ValueSource::Context => "context",
+        ValueSource::LuaGlobal => "lua_global",
+        ValueSource::Section => "section",
+        ValueSource::Filter => "filter",
+        ValueSource::Default => "default",
+        ValueSource::Missing => "missing",
+    }
+}
+
+/// Records collected in a Lua state while running scripts and rendering
+#[derive(Default)]
+pub(crate) struct Records {
+    prints: Vec<String>,
+    warnings: Vec<String>,
+    /// Warning which is not completed yet (`warn` with multiple arguments)
+    warning: String,
+    builtins: BTreeMap<String, BuiltinTiming>,
+}
+
+/// Start recording into the Lua state
+pub(crate) fn init_records(lua: &Lua) {
+    lua.set_app_data(Records::default());
+
+    lua.set_warning_function(|lua, message, incomplete| {
+        if let Some(mut records) = lua.app_data_mut::<Records>() {
+            records.warning.push_str(message);
+
+            if !incomplete {
+                let warning = std::mem::take(&mut records.warning);
+                // control messages (e.g. `@on`) are not warnings
+                if !warning.starts_with('@') {
+                    records.warnings.push(warning);
+                }
+            }
+        }
+
+        Ok(())
+    });
+}
+
+/// Take records collected so far, and start recording again
+pub(crate) fn take_records(lua: &Lua) -> Records {
+    lua.app_data_mut::<Records>()
+        .map(|mut records| std::mem::take(&mut *records))
+        .unwrap_or_default()
+}
+
+pub(crate) fn record_print(lua: &Lua, line: String) {
+    if let Some(mut records) = lua.app_data_mut::<Records>() {
+        records.prints.push(line);
+    }
+}
+
+pub(crate) fn record_builtin(lua: &Lua, name: &str, time: Duration) {
+    if let Some(mut records) = lua.app_data_mut::<Records>() {
+        let timing = records.builtins.entry(name.to_string()).or_default();
+        timing.calls += 1;
+        timing.total += time;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let lua = Lua::new();
+        init_records(&lua);
+
+        lua.load(r#"warn("@on"); warn("hoge", "fuga"); warn("piyo")"#)
+            .exec()
+            .unwrap();
+        record_print(&lua, "hoge".to_string());
+        record_builtin(&lua, "exec", Duration::from_millis(2));
+        record_builtin(&lua, "exec", Duration::from_millis(3));
+
+        let records = take_records(&lua);
+        assert_eq!(vec!["hogefuga", "piyo"], records.warnings);
+        assert_eq!(vec!["hoge"], records.prints);
+        assert_eq!(
+            BuiltinTiming {
+                calls: 2,
+                total: Duration::from_millis(5)
+            },
+            records.builtins["exec"]
+        );
+
+        assert!(take_records(&lua).prints.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+
+/// Error found in a template, with its location
+///
+/// `Display` shows the message with the location and a caret snippet:
+///
+/// ```text
+/// error: unclosed section: items
+///  --> template:2:1
+///   |
+/// 2 | {{#items}}
+///   | ^^^^^^^^^^
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    template_name: String,
+    line: usize,
+    column: usize,
+    length: usize,
+    source_line: String,
+    message: String,
+}
+
+impl Diagnostic {
+    /// Create diagnostic from a byte range of the template
+    ///
+    /// # Arguments
+    ///
+    /// `template_name: &str` - Name of the template (e.g. file path)
+    ///
+    /// `template: &str` - Template string
+    ///
+    /// `start: usize` - Byte offset where the error starts
+    ///
+    /// `end: usize` - Byte offset where the error ends
+    ///
+    /// `message: String` - Error message
+    pub(crate) fn new(
+        template_name: &str,
+        template: &str,
+        start: usize,
+        end: usize,
+        message: String,
+    ) -> Self {
+        let line_start = template[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = template[start..]
+            .find('\n')
+            .map_or(template.len(), |i| start + i);
+        let source_line = template[line_start..line_end].trim_end_matches('\r');
+
+        let (line, column) = location(template, start);
+        let length = template[start..end.clamp(start, line_end)].chars().count();
+
+        Self {
+            template_name: template_name.to_string(),
+            line,
+            column,
+            length: length.max(1),
+            source_line: source_line.to_string(),
+            message,
+        }
+    }
+
+    /// Get name of the template
+    pub fn template_name(&self) -> &str {
+        &self.template_name
+    }
+
+    /// Get 1-based line number
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Get 1-based column number (in characters)
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Get the source line with a caret under the error
+    pub fn snippet(&self) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        let indent = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        format!(
+            "{gutter} |\n{line} | {source}\n{gutter} | {indent}{caret}",
+            line = self.line,
+            source = self.source_line,
+            caret = "^".repeat(self.length),
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.template_name, self.line, self.column
+        )?;
+        write!(f, "{}", self.snippet())
+    }
+}
+
+/// Get 1-based line and column (in characters) of a byte offset
+///
+/// # Arguments
+///
+/// `template: &str` - Template string
+///
+/// `offset: usize` - Byte offset in the template
+///
+/// # Returns
+///
+/// `(usize, usize)` - Line and column
+pub(crate) fn location(template: &str, offset: usize) -> (usize, usize) {
+    let line_start = template[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = template[..offset].matches('\n').count() + 1;
+    let column = template[line_start..offset].chars().count() + 1;
+
+    (line, column)
+}
+
+/// Error of parsing a template, with all diagnostics found in the template
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseError {
+    pub(crate) fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        Self { diagnostics }
+    }
+
+    /// Get diagnostics, ordered by their location
+    pub fn diagnostics(&self) -> &Vec<Diagnostic> {
+        &self.diagnostics
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let messages = self
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", messages.join("\n\n"))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic() {
+        let template = "Hello,\n  {{#items}} world";
+        let start = template.find("{{").unwrap();
+        let diagnostic = Diagnostic::new(
+            "hello.txt",
+            template,
+            start,
+            start + 10,
+            "unclosed section: items".to_string(),
+        );
+
+        assert_eq!(2, diagnostic.line());
+        assert_eq!(3, diagnostic.column());
+        assert_eq!(
+            "error: unclosed section: items\n --> hello.txt:2:3\n  |\n2 |   {{#items}} world\n  |   ^^^^^^^^^^",
+            diagnostic.to_string()
+        );
+    }
+}
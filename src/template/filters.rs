@@ -0,0 +1,267 @@
+//! Standard filters for variable tags
+//!
+//! # Examples
+//!
+//! ```text
+//! {{ name | upper }}                  -- HOGE
+//! {{ name | lower }}                  -- hoge
+//! {{ name | trim }}                   -- strip whitespaces of both ends
+//! {{ name | default("n/a") }}         -- "n/a" if name is nil or missing
+//! {{ name | truncate(3, "...") }}     -- hog...
+//! {{ name | replace("o", "0") }}      -- h0ge
+//! {{ price | pad(6, "0") }}           -- 000100 (negative width pads the end)
+//! {{ items | json }}                  -- [1,2,3]
+//! {{ html | escape }}                 -- &lt;p&gt;
+//! {{ datetime | date("%Y/%m/%d") }}   -- 2020/01/02 (datetime table or UNIX time)
+//! ```
+
+use mlua::{IntoLua, Lua, MultiValue};
+
+use super::value::MllValue;
+use crate::utils::lua_to_json;
+
+/// Key of the Lua registry table where filters registered by Lua scripts are stored
+pub(crate) const FILTERS_REGISTRY_KEY: &str = "mll_filters";
+
+/// Filter function, takes the value and the evaluated arguments
+pub(crate) type FilterFunction = fn(&Lua, MllValue, MultiValue) -> mlua::Result<MllValue>;
+
+/// Get standard filter by name
+///
+/// # Arguments
+///
+/// `name: &str` - Name of the filter
+///
+/// # Returns
+///
+/// `Option<FilterFunction>` - Filter function, `None` if there is no such filter
+pub(crate) fn get_filter(name: &str) -> Option<FilterFunction> {
+    let filter: FilterFunction = match name {
+        "upper" => upper,
+        "lower" => lower,
+        "trim" => trim,
+        "default" => default,
+        "json" => json,
+        "escape" => escape,
+        "pad" => pad,
+        "replace" => replace,
+        "truncate" => truncate,
+        #[cfg(feature = "datetime")]
+        "date" => date,
+        _ => return None,
+    };
+
+    Some(filter)
+}
+
+fn to_string(value: &MllValue) -> mlua::Result<String> {
+    value.to_output().ok_or_else(|| {
+        mlua::Error::RuntimeError(format!("cannot convert {} to string", value.type_name()))
+    })
+}
+
+fn upper(_: &Lua, value: MllValue, _: MultiValue) -> mlua::Result<MllValue> {
+    Ok(to_string(&value)?.to_uppercase().into())
+}
+
+fn lower(_: &Lua, value: MllValue, _: MultiValue) -> mlua::Result<MllValue> {
+    Ok(to_string(&value)?.to_lowercase().into())
+}
+
+fn trim(_: &Lua, value: MllValue, _: MultiValue) -> mlua::Result<MllValue> {
+    Ok(to_string(&value)?.trim().into())
+}
+
+fn default(lua: &Lua, value: MllValue, args: MultiValue) -> mlua::Result<MllValue> {
+    let default = lua.unpack_multi::<MllValue>(args)?;
+
+    match value {
+        MllValue::Nil => Ok(default),
+        value => Ok(value),
+    }
+}
+
+fn json(lua: &Lua, value: MllValue, _: MultiValue) -> mlua::Result<MllValue> {
+    let json = lua_to_json(value.into_lua(lua)?)?;
+    let s = serde_json::to_string(&json).map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+
+    Ok(s.into())
+}
+
+fn escape(_: &Lua, value: MllValue, _: MultiValue) -> mlua::Result<MllValue> {
+    let s = to_string(&value)?;
+
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    Ok(escaped.into())
+}
+
+fn pad(lua: &Lua, value: MllValue, args: MultiValue) -> mlua::Result<MllValue> {
+    let (width, fill) = lua.unpack_multi::<(i64, Option<String>)>(args)?;
+    let fill = fill.and_then(|f| f.chars().next()).unwrap_or(' ');
+
+    let s = to_string(&value)?;
+    let length = s.chars().count();
+    let size = width.unsigned_abs() as usize;
+    if length >= size {
+        return Ok(s.into());
+    }
+
+    let padding = std::iter::repeat_n(fill, size - length).collect::<String>();
+    if width.is_negative() {
+        Ok(format!("{}{}", s, padding).into())
+    } else {
+        Ok(format!("{}{}", padding, s).into())
+    }
+}
+
+fn replace(lua: &Lua, value: MllValue, args: MultiValue) -> mlua::Result<MllValue> {
+    let (from, to) = lua.unpack_multi::<(String, String)>(args)?;
+
+    Ok(to_string(&value)?.replace(&from, &to).into())
+}
+
+fn truncate(lua: &Lua, value: MllValue, args: MultiValue) -> mlua::Result<MllValue> {
+    let (length, suffix) = lua.unpack_multi::<(usize, Option<String>)>(args)?;
+
+    let s = to_string(&value)?;
+    if s.chars().count() <= length {
+        return Ok(s.into());
+    }
+
+    let mut truncated = s.chars().take(length).collect::<String>();
+    truncated.push_str(&suffix.unwrap_or_default());
+
+    Ok(truncated.into())
+}
+
+#[cfg(feature = "datetime")]
+fn date(lua: &Lua, value: MllValue, args: MultiValue) -> mlua::Result<MllValue> {
+    use crate::builtins::datetime::{format_chrono, lua_datetime_to_chrono};
+
+    let format = lua.unpack_multi::<Option<String>>(args)?;
+    let format = format.unwrap_or_else(|| "%Y-%m-%d %H:%M:%S".to_string());
+
+    let datetime = match value {
+        MllValue::Integer(timestamp) => chrono::DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| mlua::Error::RuntimeError(format!("invalid time: {}", timestamp)))?
+            .naive_utc(),
+        value @ (MllValue::Table(_) | MllValue::Map(_)) => match value.into_lua(lua)? {
+            mlua::Value::Table(table) => {
+                lua_datetime_to_chrono(&table).map_err(mlua::Error::RuntimeError)?
+            }
+            _ => unreachable!(),
+        },
+        value => {
+            return Err(mlua::Error::RuntimeError(format!(
+                "cannot convert {} to date",
+                value.type_name()
+            )));
+        }
+    };
+
+    let formatted = format_chrono(&datetime, &format).map_err(mlua::Error::RuntimeError)?;
+    Ok(formatted.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(lua: &Lua, name: &str, value: MllValue, args: &str) -> mlua::Result<String> {
+        let args = lua.load(format!("return {}", args)).eval::<MultiValue>()?;
+        let filtered = get_filter(name).unwrap()(lua, value, args)?;
+        Ok(filtered.to_output().unwrap_or_default())
+    }
+
+    #[test]
+    fn test_string_filters() {
+        let lua = Lua::new();
+
+        assert_eq!("HOGE", apply(&lua, "upper", "hoge".into(), "").unwrap());
+        assert_eq!("hoge", apply(&lua, "lower", "HoGe".into(), "").unwrap());
+        assert_eq!("hoge", apply(&lua, "trim", " hoge\n".into(), "").unwrap());
+        assert_eq!(
+            "h0ge",
+            apply(&lua, "replace", "hoge".into(), "'o', '0'").unwrap()
+        );
+        assert_eq!(
+            "&lt;a href=&quot;#&quot;&gt;",
+            apply(&lua, "escape", r##"<a href="#">"##.into(), "").unwrap()
+        );
+        assert!(apply(&lua, "upper", MllValue::Nil, "").is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        let lua = Lua::new();
+
+        assert_eq!(
+            "n/a",
+            apply(&lua, "default", MllValue::Nil, "'n/a'").unwrap()
+        );
+        assert_eq!(
+            "hoge",
+            apply(&lua, "default", "hoge".into(), "'n/a'").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pad_and_truncate() {
+        let lua = Lua::new();
+
+        assert_eq!(
+            "000100",
+            apply(&lua, "pad", 100i64.into(), "6, '0'").unwrap()
+        );
+        assert_eq!("ab  ", apply(&lua, "pad", "ab".into(), "-4").unwrap());
+        assert_eq!("abc", apply(&lua, "pad", "abc".into(), "2").unwrap());
+
+        assert_eq!(
+            "あいう...",
+            apply(&lua, "truncate", "あいうえお".into(), "3, '...'").unwrap()
+        );
+        assert_eq!(
+            "あい",
+            apply(&lua, "truncate", "あい".into(), "3, '...'").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let lua = Lua::new();
+        let table = lua.load("return {1, 2, 3}").eval::<mlua::Table>().unwrap();
+
+        assert_eq!("[1,2,3]", apply(&lua, "json", table.into(), "").unwrap());
+        assert_eq!(r#""hoge""#, apply(&lua, "json", "hoge".into(), "").unwrap());
+    }
+
+    #[cfg(feature = "datetime")]
+    #[test]
+    fn test_date() {
+        let lua = Lua::new();
+        let table = lua
+            .load("return {year = 2020, month = 1, day = 2}")
+            .eval::<mlua::Table>()
+            .unwrap();
+
+        assert_eq!(
+            "2020/01/02",
+            apply(&lua, "date", table.into(), "'%Y/%m/%d'").unwrap()
+        );
+        assert_eq!(
+            "1970-01-01 00:01:00",
+            apply(&lua, "date", 60i64.into(), "").unwrap()
+        );
+    }
+}
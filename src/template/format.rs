@@ -0,0 +1,173 @@
+use mlua::{Function, IntoLua, Lua};
+
+use super::value::MllValue;
+use crate::utils::lua_to_json;
+
+/// How to render values which are not strings
+///
+/// Integers are rendered without `.0`, and so are floats with an integral value unless the
+/// precision is set. Booleans are rendered as `true` and `false`, and `nil` is a missing value.
+/// Tables are rendered by their `__tostring` metamethod, or as JSON.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::{Mll, ValueFormat};
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("{{count}} {{ratio}} {{ok}} {{user}}".to_string());
+/// mll.set_pre_process_script(
+///     "count = 20; ratio = 2 / 3; ok = true; user = {name = 'hoge'}".to_string(),
+/// );
+/// mll.set_value_format(ValueFormat::new().float_precision(2));
+///
+/// assert_eq!(r#"20 0.67 true {"name":"hoge"}"#, mll.render_lua_globals().unwrap());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ValueFormat {
+    float_precision: Option<usize>,
+}
+
+impl ValueFormat {
+    /// Create format which renders floats in the shortest form
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render floats with a fixed number of decimal places
+    ///
+    /// # Arguments
+    ///
+    /// `precision: usize` - Number of decimal places (e.g. `2` for `0.67`)
+    pub fn float_precision(mut self, precision: usize) -> Self {
+        self.float_precision = Some(precision);
+        self
+    }
+
+    /// Get string to be written into the rendered output
+    ///
+    /// # Arguments
+    ///
+    /// `lua: &Lua` - Lua state, which converts tables
+    ///
+    /// `value: &MllValue` - Value
+    ///
+    /// # Returns
+    ///
+    /// `mlua::Result<Option<String>>` - Rendered string, `None` for `nil`, error if `__tostring`
+    /// fails or the table cannot be converted into JSON
+    pub(crate) fn format(&self, lua: &Lua, value: &MllValue) -> mlua::Result<Option<String>> {
+        match value {
+            MllValue::Table(table) => {
+                let tostring = table
+                    .metatable()
+                    .map(|metatable| metatable.get::<Option<Function>>("__tostring"))
+                    .transpose()?
+                    .flatten();
+
+                match tostring {
+                    Some(tostring) => tostring.call::<String>(table.clone()).map(Some),
+                    None => to_json(lua, value).map(Some),
+                }
+            }
+            MllValue::List(_) | MllValue::Map(_) => to_json(lua, value).map(Some),
+            _ => Ok(self.format_scalar(value)),
+        }
+    }
+
+    /// Get string of a value which is not a table
+    ///
+    /// # Arguments
+    ///
+    /// `value: &MllValue` - Value
+    ///
+    /// # Returns
+    ///
+    /// `Option<String>` - Rendered string, `None` for `nil` and tables
+    pub(crate) fn format_scalar(&self, value: &MllValue) -> Option<String> {
+        match value {
+            MllValue::Boolean(b) => Some(b.to_string()),
+            MllValue::Integer(i) => Some(i.to_string()),
+            MllValue::Number(n) => Some(self.format_number(*n)),
+            MllValue::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    fn format_number(&self, n: f64) -> String {
+        match self.float_precision {
+            Some(precision) => format!("{:.*}", precision, n),
+            None if n.fract() == 0.0 && n.abs() < 1e15 => format!("{:.0}", n),
+            None => n.to_string(),
+        }
+    }
+}
+
+/// Convert a table, a list or a map into a JSON string
+fn to_json(lua: &Lua, value: &MllValue) -> mlua::Result<String> {
+    let json = lua_to_json(value.clone().into_lua(lua)?)?;
+
+    serde_json::to_string(&json).map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(lua: &Lua, format: ValueFormat, script: &str) -> Option<String> {
+        let value = lua.load(script).eval::<MllValue>().unwrap();
+        format.format(lua, &value).unwrap()
+    }
+
+    #[test]
+    fn test_format() {
+        let lua = Lua::new();
+        let default = ValueFormat::new();
+        let fixed = ValueFormat::new().float_precision(2);
+
+        assert_eq!(Some("20".to_string()), format(&lua, default, "return 20"));
+        assert_eq!(
+            Some("20".to_string()),
+            format(&lua, default, "return 40 / 2")
+        );
+        assert_eq!(
+            Some("0.5".to_string()),
+            format(&lua, default, "return 1 / 2")
+        );
+        assert_eq!(
+            Some("0.50".to_string()),
+            format(&lua, fixed, "return 1 / 2")
+        );
+        assert_eq!(Some("20".to_string()), format(&lua, fixed, "return 20"));
+        assert_eq!(
+            Some("false".to_string()),
+            format(&lua, default, "return false")
+        );
+        assert_eq!(None, format(&lua, default, "return nil"));
+
+        assert_eq!(
+            Some(r#"{"items":[1,2.5],"name":"hoge"}"#.to_string()),
+            format(&lua, default, "return {name = 'hoge', items = {1, 2.5}}")
+        );
+        assert_eq!(
+            Some("<hoge>".to_string()),
+            format(
+                &lua,
+                default,
+                r#"
+                    return setmetatable({name = 'hoge'}, {
+                        __tostring = function(t) return '<' .. t.name .. '>' end
+                    })
+                "#
+            )
+        );
+        assert!(
+            ValueFormat::new()
+                .format(
+                    &lua,
+                    &MllValue::Table(lua.load("return {print}").eval().unwrap())
+                )
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// How to render a variable which is not found in the context
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use libmll::{MissingVariablePolicy, Mll};
+///
+/// let mut mll = Mll::new();
+/// mll.set_template("Hello, {{name}}! {{ greeting }}".to_string());
+/// mll.set_missing_variable_policy(MissingVariablePolicy::Keep);
+///
+/// let mut table = HashMap::new();
+/// table.insert("name", "hoge".to_string());
+///
+/// assert_eq!("Hello, hoge! {{ greeting }}", mll.render(&table).unwrap());
+/// ```
+#[derive(Clone, Default)]
+pub enum MissingVariablePolicy {
+    /// Render an empty string and fail with `MllError::MissingVariables` (default)
+    #[default]
+    Strict,
+    /// Render an empty string
+    Empty,
+    /// Keep the original tag (e.g. `{{ name }}`) to render the output again later
+    Keep,
+    /// Render the given string
+    Default(String),
+    /// Render the string returned by the function called with the name of the variable,
+    /// `None` is handled as `Strict`
+    Callback(Arc<dyn Fn(&str) -> Option<String> + Send + Sync>),
+    /// Render the string returned by the Lua global function of the given name, called with the
+    /// name of the variable, `nil` is handled as `Strict`
+    Lua(String),
+}
+
+impl MissingVariablePolicy {
+    /// Make a policy which calls the function
+    ///
+    /// # Arguments
+    ///
+    /// `callback: F` - Function called with the name of the missing variable
+    ///
+    /// # Returns
+    ///
+    /// `MissingVariablePolicy` - `MissingVariablePolicy::Callback`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libmll::MissingVariablePolicy;
+    ///
+    /// let policy = MissingVariablePolicy::callback(|name| Some(format!("<{}>", name)));
+    /// ```
+    pub fn callback<F>(callback: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        MissingVariablePolicy::Callback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for MissingVariablePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingVariablePolicy::Strict => write!(f, "Strict"),
+            MissingVariablePolicy::Empty => write!(f, "Empty"),
+            MissingVariablePolicy::Keep => write!(f, "Keep"),
+            MissingVariablePolicy::Default(s) => f.debug_tuple("Default").field(s).finish(),
+            MissingVariablePolicy::Callback(_) => write!(f, "Callback(..)"),
+            MissingVariablePolicy::Lua(name) => f.debug_tuple("Lua").field(name).finish(),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+pub(crate) mod diagnostic;
+pub(crate) mod filters;
+pub(crate) mod format;
+pub(crate) mod missing;
+pub(crate) mod parser;
+pub(crate) mod renderer;
+pub(crate) mod value;
+
+use std::cell::OnceCell;
+use std::time::Instant;
+
+use mlua::Lua;
+
+use crate::builtins::render::Session;
+use crate::limits::LimitGuard;
+use crate::report::{RenderReport, ValueSource, take_records};
+use crate::utils::do_blocking;
+use crate::{BuiltinRegistry, Capabilities, Deterministic, Engine, Limits, MllError};
+use diagnostic::ParseError;
+use format::ValueFormat;
+use missing::MissingVariablePolicy;
+use parser::{Node, parse};
+use renderer::{RenderResult, Renderer};
+use value::RenderContext;
+
+/// Template parsed into nodes, which can be rendered many times without parsing again
+///
+/// The Lua engine used to evaluate `{{= expr }}`, `{{#if expr}}` and filters is created on the
+/// first render, and reused by the following renders. `Mll` renders its compiled template in
+/// its own session instead. Each expression is compiled into a Lua function once per Lua state,
+/// and the following evaluations call the function.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use libmll::CompiledTemplate;
+///
+/// let compiled = CompiledTemplate::compile("Hello, {{name}}!").unwrap();
+///
+/// for name in ["hoge", "fuga"] {
+///     let mut table = HashMap::new();
+///     table.insert("name", name.to_string());
+///
+///     let rendered = compiled.render(&table);
+///     assert_eq!(format!("Hello, {}!", name), rendered.unwrap());
+/// }
+/// ```
+pub struct CompiledTemplate {
+    nodes: Vec<Node>,
+    missing_variable_policy: MissingVariablePolicy,
+    value_format: ValueFormat,
+    capabilities: Capabilities,
+    limits: Limits,
+    builtins: BuiltinRegistry,
+    deterministic: Option<Deterministic>,
+    engine: OnceCell<Engine>,
+}
+
+impl CompiledTemplate {
+    /// Parse template
+    ///
+    /// # Arguments
+    ///
+    /// `template: &str` - Template string
+    ///
+    /// # Returns
+    ///
+    /// `Result<CompiledTemplate, ParseError>` - Compiled template, or diagnostics of the errors
+    pub fn compile(template: &str) -> Result<Self, ParseError> {
+        Self::compile_named("template", template)
+    }
+
+    /// Parse template with a name shown in diagnostics (e.g. file path)
+    ///
+    /// # Arguments
+    ///
+    /// `name: &str` - Name of the template
+    ///
+    /// `template: &str` - Template string
+    ///
+    /// # Returns
+    ///
+    /// `Result<CompiledTemplate, ParseError>` - Compiled template, or diagnostics of the errors
+    pub fn compile_named(name: &str, template: &str) -> Result<Self, ParseError> {
+        Ok(Self {
+            nodes: parse(name, template)?,
+            missing_variable_policy: MissingVariablePolicy::default(),
+            value_format: ValueFormat::default(),
+            capabilities: Capabilities::default(),
+            limits: Limits::default(),
+            builtins: BuiltinRegistry::new(),
+            deterministic: None,
+            engine: OnceCell::new(),
+        })
+    }
+
+    /// Get how to render a variable which is not found
+    pub fn missing_variable_policy(&self) -> &MissingVariablePolicy {
+        &self.missing_variable_policy
+    }
+
+    /// Set how to render a variable which is not found
+    ///
+    /// # Arguments
+    ///
+    /// `policy: MissingVariablePolicy` - Policy for missing variables
+    pub fn set_missing_variable_policy(&mut self, policy: MissingVariablePolicy) {
+        self.missing_variable_policy = policy;
+        if let Some(engine) = self.engine.get() {
+            self.share_session(engine.lua());
+        }
+    }
+
+    /// Get how to render values which are not strings
+    pub fn value_format(&self) -> &ValueFormat {
+        &self.value_format
+    }
+
+    /// Set how to render values which are not strings
+    ///
+    /// # Arguments
+    ///
+    /// `format: ValueFormat` - Format of values
+    pub fn set_value_format(&mut self, format: ValueFormat) {
+        self.value_format = format;
+        if let Some(engine) = self.engine.get() {
+            self.share_session(engine.lua());
+        }
+    }
+
+    /// Set what expressions and filters are allowed to do, the Lua state is created again
+    ///
+    /// # Arguments
+    ///
+    /// `capabilities: Capabilities` - Capabilities of the template
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+        self.engine = OnceCell::new();
+    }
+
+    /// Set limits of each render
+    ///
+    /// # Arguments
+    ///
+    /// `limits: Limits` - Limits of a render
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Set builtins added, replaced or removed on top of the standard builtins, the Lua state
+    /// is created again
+    ///
+    /// # Arguments
+    ///
+    /// `builtins: BuiltinRegistry` - Builtins of the template
+    pub fn set_builtins(&mut self, builtins: BuiltinRegistry) {
+        self.builtins = builtins;
+        self.engine = OnceCell::new();
+    }
+
+    /// Turn on or off the deterministic mode, the Lua state is created again
+    ///
+    /// # Arguments
+    ///
+    /// `deterministic: Option<Deterministic>` - Seed and time, `None` to turn it off
+    pub fn set_deterministic(&mut self, deterministic: Option<Deterministic>) {
+        self.deterministic = deterministic;
+        self.engine = OnceCell::new();
+    }
+
+    /// Render template with map like object
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template, or error with the partially rendered
+    /// template if any variable is missing (with `MissingVariablePolicy::Strict`) or any
+    /// expression failed
+    pub fn render<T>(&self, context: &T) -> Result<String, MllError>
+    where
+        T: RenderContext,
+    {
+        do_blocking(self.render_async(context))?
+    }
+
+    /// Render template with map like object on the runtime of the caller
+    ///
+    /// See `Mll::render_async`.
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `Result<String, MllError>` - Rendered template
+    pub async fn render_async<T>(&self, context: &T) -> Result<String, MllError>
+    where
+        T: RenderContext,
+    {
+        let lua = self.lua();
+
+        let guard = LimitGuard::new(lua, &self.limits);
+        let result = self.render_in(lua, context, ValueSource::Context).await;
+        drop(guard);
+
+        result.into_result()
+    }
+
+    /// Render template with map like object, and report how it is rendered
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render
+    pub fn render_with_report<T>(&self, context: &T) -> (Result<String, MllError>, RenderReport)
+    where
+        T: RenderContext,
+    {
+        do_blocking(self.render_with_report_async(context))
+            .unwrap_or_else(|e| (Err(e), RenderReport::default()))
+    }
+
+    /// Render template with map like object on the runtime of the caller, and report how it is
+    /// rendered
+    ///
+    /// # Arguments
+    ///
+    /// `context: &T` - Map like object
+    ///
+    /// # Returns
+    ///
+    /// `(Result<String, MllError>, RenderReport)` - Rendered template and report of the render
+    pub async fn render_with_report_async<T>(
+        &self,
+        context: &T,
+    ) -> (Result<String, MllError>, RenderReport)
+    where
+        T: RenderContext,
+    {
+        let lua = self.lua();
+        take_records(lua);
+
+        let start = Instant::now();
+        let guard = LimitGuard::new(lua, &self.limits);
+        let result = self.render_in(lua, context, ValueSource::Context).await;
+        drop(guard);
+        let report = RenderReport::new(&result, take_records(lua), start.elapsed());
+
+        (result.into_result(), report)
+    }
+
+    /// Get the Lua state owned by the template
+    pub(crate) fn lua(&self) -> &Lua {
+        self.engine
+            .get_or_init(|| {
+                let mut engine = Engine::with_capabilities(self.capabilities.clone());
+                // the builtins can not be set only if the state runs out of memory
+                engine.set_builtins(self.builtins.clone()).unwrap();
+                if self.deterministic.is_some() {
+                    engine.set_deterministic(self.deterministic);
+                }
+                self.share_session(engine.lua());
+                engine
+            })
+            .lua()
+    }
+
+    /// Share the settings with the templates rendered by `mll.template.render`
+    fn share_session(&self, lua: &Lua) {
+        lua.set_app_data(Session {
+            builtins: self.builtins.clone(),
+            missing_variable_policy: self.missing_variable_policy.clone(),
+            value_format: self.value_format,
+        });
+    }
+
+    /// Render template, evaluating expressions in `lua`
+    pub(crate) async fn render_in(
+        &self,
+        lua: &Lua,
+        context: &dyn RenderContext,
+        context_source: ValueSource,
+    ) -> RenderResult {
+        Renderer::new(lua, &self.missing_variable_policy)
+            .context_source(context_source)
+            .value_format(self.value_format)
+            .render(&self.nodes, context)
+            .await
+    }
+}
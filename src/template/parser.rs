@@ -0,0 +1,831 @@
+use std::cell::OnceCell;
+
+use mlua::Lua;
+
+use super::diagnostic::{Diagnostic, ParseError, location};
+
+/// Segment of a path
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Segment {
+    /// Current scope (`this` or `.`)
+    This,
+    /// Key of a table (e.g. `args` of `response.args`)
+    Key(String),
+    /// 1-based index of a list (e.g. `[2]` of `items[2]`), same as Lua
+    Index(usize),
+}
+
+/// Path to a value (e.g. `response.args.foo`, `items[2].name`, `this`)
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Path {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    /// Parse path
+    ///
+    /// # Arguments
+    ///
+    /// `s: &str` - Path string
+    ///
+    /// # Returns
+    ///
+    /// `Option<Path>` - Path, `None` if `s` is not a valid path
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "." {
+            return Some(Self {
+                raw: s.to_string(),
+                segments: vec![Segment::This],
+            });
+        }
+
+        let mut segments = Vec::new();
+        let mut rest = s;
+        loop {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            let name = &rest[..end];
+            if !is_name(name) {
+                return None;
+            }
+
+            if segments.is_empty() && name == "this" {
+                segments.push(Segment::This);
+            } else {
+                segments.push(Segment::Key(name.to_string()));
+            }
+            rest = &rest[end..];
+
+            while let Some(index) = rest.strip_prefix('[') {
+                let close = index.find(']')?;
+                segments.push(Segment::Index(index[..close].trim().parse().ok()?));
+                rest = &index[close + 1..];
+            }
+
+            if rest.is_empty() {
+                break;
+            }
+            rest = rest.strip_prefix('.')?;
+        }
+
+        Some(Self {
+            raw: s.to_string(),
+            segments,
+        })
+    }
+
+    /// Get the path as written in the template
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+/// Filter applied to the value of a variable tag (e.g. `upper` or `truncate(20)`)
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Filter {
+    name: String,
+    arguments: Option<String>,
+}
+
+impl Filter {
+    /// Parse filter
+    ///
+    /// # Arguments
+    ///
+    /// `s: &str` - Filter string, a name optionally followed by Lua arguments in parentheses
+    ///
+    /// # Returns
+    ///
+    /// `Option<Filter>` - Filter, `None` if `s` is not a valid filter
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+
+        let (name, arguments) = match s.find('(') {
+            Some(open) => {
+                let arguments = s[open + 1..].strip_suffix(')')?.trim();
+                (s[..open].trim(), Some(arguments.to_string()))
+            }
+            None => (s, None),
+        };
+
+        is_name(name).then(|| Self {
+            name: name.to_string(),
+            arguments: arguments.filter(|a| !a.is_empty()),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get arguments as a Lua expression list (e.g. `"a", "b"`)
+    pub fn arguments(&self) -> Option<&str> {
+        self.arguments.as_deref()
+    }
+}
+
+/// Split `s` by `|`, except in strings and parentheses
+fn split_filters(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '|' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+/// Text and location of a tag in the template
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Source {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Source {
+    pub fn new(text: &str, line: usize, column: usize) -> Self {
+        Self {
+            text: text.to_string(),
+            line,
+            column,
+        }
+    }
+
+    /// Original text of the tag (e.g. `{{ name | upper }}`)
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// Node of a parsed template
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Node {
+    /// Plain text
+    Text(String),
+    /// Variable tag with filters (e.g. `{{ name }}`, `{{ name | upper | truncate(20) }}`)
+    Variable {
+        path: Path,
+        filters: Vec<Filter>,
+        source: Source,
+    },
+    /// Section (e.g. `{{#items}}...{{/items}}`), or inverted section (e.g. `{{^items}}...{{/items}}`)
+    Section {
+        path: Path,
+        inverted: bool,
+        children: Vec<Node>,
+    },
+    /// Lua expression (e.g. `{{= mll.random.int(1, 10) }}`)
+    Expression(String),
+    /// Conditional block (e.g. `{{#if expr}}...{{else}}...{{/if}}`)
+    If {
+        condition: String,
+        then_branch: Vec<Node>,
+        else_branch: Vec<Node>,
+    },
+}
+
+/// Kind of a tag between `{{` and `}}`
+enum Tag<'a> {
+    Variable(Path, Vec<Filter>),
+    Open(Path),
+    OpenInverted(Path),
+    Expression(&'a str),
+    If(&'a str),
+    Else,
+    Close(&'a str),
+}
+
+impl<'a> Tag<'a> {
+    fn parse(content: &'a str) -> Result<Self, String> {
+        let content = content.trim();
+
+        if let Some(name) = content.strip_prefix('#') {
+            let name = name.trim();
+            match name.strip_prefix("if") {
+                Some("") => Err("empty condition".to_string()),
+                Some(condition) if condition.starts_with(char::is_whitespace) => {
+                    Ok(Tag::If(condition.trim()))
+                }
+                _ => parse_path(name).map(Tag::Open),
+            }
+        } else if let Some(name) = content.strip_prefix('^') {
+            parse_path(name.trim()).map(Tag::OpenInverted)
+        } else if let Some(expression) = content.strip_prefix('=') {
+            match expression.trim() {
+                "" => Err("empty expression".to_string()),
+                expression => Ok(Tag::Expression(expression)),
+            }
+        } else if content == "else" {
+            Ok(Tag::Else)
+        } else if let Some(name) = content.strip_prefix('/') {
+            let name = name.trim();
+            parse_path(name).map(|_| Tag::Close(name))
+        } else {
+            let mut parts = split_filters(content).into_iter();
+            let path = parse_path(parts.next().unwrap_or_default().trim())?;
+            let filters = parts
+                .map(|filter| {
+                    Filter::parse(filter)
+                        .ok_or_else(|| format!("invalid filter: '{}'", filter.trim()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Tag::Variable(path, filters))
+        }
+    }
+}
+
+fn parse_path(s: &str) -> Result<Path, String> {
+    match s {
+        "" => Err("empty tag".to_string()),
+        s => Path::parse(s).ok_or_else(|| format!("invalid tag: '{}'", s)),
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Kind of a block which is not closed yet
+enum Block {
+    Root,
+    Section {
+        path: Path,
+        inverted: bool,
+    },
+    If {
+        condition: String,
+        then_branch: Option<Vec<Node>>,
+    },
+}
+
+impl Block {
+    fn name(&self) -> &str {
+        match self {
+            Block::Root => "",
+            Block::Section { path, .. } => path.raw(),
+            Block::If { .. } => "if",
+        }
+    }
+}
+
+/// Block which is not closed yet
+struct Frame {
+    block: Block,
+    nodes: Vec<Node>,
+    /// Byte range of the opening tag
+    start: usize,
+    end: usize,
+}
+
+impl Frame {
+    fn new(block: Block, start: usize, end: usize) -> Self {
+        Self {
+            block,
+            nodes: Vec::new(),
+            start,
+            end,
+        }
+    }
+
+    fn into_node(self) -> Node {
+        match self.block {
+            Block::Root => unreachable!("root block cannot be closed"),
+            Block::Section { path, inverted } => Node::Section {
+                path,
+                inverted,
+                children: self.nodes,
+            },
+            Block::If {
+                condition,
+                then_branch: Some(then_branch),
+            } => Node::If {
+                condition,
+                then_branch,
+                else_branch: self.nodes,
+            },
+            Block::If {
+                condition,
+                then_branch: None,
+            } => Node::If {
+                condition,
+                then_branch: self.nodes,
+                else_branch: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Parser which collects all diagnostics instead of stopping at the first error
+struct Parser<'a> {
+    name: &'a str,
+    template: &'a str,
+    frames: Vec<Frame>,
+    diagnostics: Vec<Diagnostic>,
+    /// Lua state to check syntax of expressions, created when needed
+    lua: OnceCell<Lua>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(name: &'a str, template: &'a str) -> Self {
+        Self {
+            name,
+            template,
+            frames: vec![Frame::new(Block::Root, 0, 0)],
+            diagnostics: Vec::new(),
+            lua: OnceCell::new(),
+        }
+    }
+
+    fn error(&mut self, start: usize, end: usize, message: String) {
+        self.diagnostics.push(Diagnostic::new(
+            self.name,
+            self.template,
+            start,
+            end,
+            message,
+        ));
+    }
+
+    /// Check syntax of a Lua expression (list)
+    fn check_expression(&mut self, expression: &str, start: usize, end: usize) {
+        let lua = self.lua.get_or_init(Lua::new);
+        let result = lua
+            .load(format!("return {}", expression))
+            .set_name("=expression")
+            .into_function();
+
+        if let Err(e) = result {
+            let message = match e {
+                mlua::Error::SyntaxError { message, .. } => message,
+                e => e.to_string(),
+            };
+            self.error(start, end, format!("invalid expression: {}", message));
+        }
+    }
+
+    fn current(&mut self) -> &mut Vec<Node> {
+        &mut self.frames.last_mut().unwrap().nodes
+    }
+
+    fn parse(mut self) -> Result<Vec<Node>, ParseError> {
+        let template = self.template;
+
+        let mut position = 0;
+        while let Some(found) = template[position..].find("{{") {
+            let mut start = position + found;
+
+            // `\\{{` is a literal `\` followed by a tag, and `\{{` is a literal `{{`
+            if template[..start].ends_with(r"\\") {
+                push_text(self.current(), &template[position..start - 1]);
+                position = start;
+            } else if template[..start].ends_with('\\') {
+                push_text(self.current(), &template[position..start - 1]);
+                push_text(self.current(), "{{");
+                position = start + 2;
+                continue;
+            }
+
+            // use the innermost `{{` of a run of braces (e.g. `{{{name}}}`)
+            while template[start + 2..].starts_with('{') {
+                start += 1;
+            }
+
+            push_text(self.current(), &template[position..start]);
+
+            let Some(length) = find_tag_end(&template[start + 2..]) else {
+                self.error(start, start + 2, "unclosed tag".to_string());
+                position = template.len();
+                break;
+            };
+            let end = start + 2 + length + 2;
+            position = end;
+
+            match Tag::parse(&template[start + 2..end - 2]) {
+                Ok(tag) => self.push_tag(tag, start, end),
+                Err(message) => self.error(start, end, message),
+            }
+        }
+        push_text(self.current(), &template[position..]);
+
+        while self.frames.len() > 1 {
+            let frame = self.frames.pop().unwrap();
+            let message = format!("unclosed section: {}", frame.block.name());
+            self.error(frame.start, frame.end, message);
+        }
+
+        if self.diagnostics.is_empty() {
+            Ok(self.frames.pop().unwrap().nodes)
+        } else {
+            self.diagnostics
+                .sort_by_key(|diagnostic| (diagnostic.line(), diagnostic.column()));
+            Err(ParseError::new(self.diagnostics))
+        }
+    }
+
+    fn push_tag(&mut self, tag: Tag, start: usize, end: usize) {
+        match tag {
+            Tag::Variable(path, filters) => {
+                for filter in &filters {
+                    if let Some(arguments) = filter.arguments() {
+                        self.check_expression(arguments, start, end);
+                    }
+                }
+
+                let (line, column) = location(self.template, start);
+                let source = Source::new(&self.template[start..end], line, column);
+
+                self.current().push(Node::Variable {
+                    path,
+                    filters,
+                    source,
+                });
+            }
+            Tag::Open(path) => self.frames.push(Frame::new(
+                Block::Section {
+                    path,
+                    inverted: false,
+                },
+                start,
+                end,
+            )),
+            Tag::OpenInverted(path) => self.frames.push(Frame::new(
+                Block::Section {
+                    path,
+                    inverted: true,
+                },
+                start,
+                end,
+            )),
+            Tag::Expression(expression) => {
+                self.check_expression(expression, start, end);
+                self.current()
+                    .push(Node::Expression(expression.to_string()));
+            }
+            Tag::If(condition) => {
+                self.check_expression(condition, start, end);
+                self.frames.push(Frame::new(
+                    Block::If {
+                        condition: condition.to_string(),
+                        then_branch: None,
+                    },
+                    start,
+                    end,
+                ));
+            }
+            Tag::Else => {
+                let frame = self.frames.last_mut().unwrap();
+                let message = match &mut frame.block {
+                    Block::If { then_branch, .. } if then_branch.is_none() => {
+                        *then_branch = Some(std::mem::take(&mut frame.nodes));
+                        return;
+                    }
+                    Block::If { .. } => "duplicated else",
+                    _ => "else outside of if",
+                };
+                self.error(start, end, message.to_string());
+            }
+            Tag::Close(name) => self.close(name, start, end),
+        }
+    }
+
+    fn close(&mut self, name: &str, start: usize, end: usize) {
+        let Some(depth) = self
+            .frames
+            .iter()
+            .skip(1)
+            .rposition(|frame| frame.block.name() == name)
+        else {
+            self.error(start, end, format!("unexpected closing tag: {}", name));
+            return;
+        };
+
+        // close inner blocks which are not closed, as errors
+        while self.frames.len() > depth + 2 {
+            let frame = self.frames.pop().unwrap();
+            let message = format!(
+                "unclosed section: {} (closed by {{{{/{}}}}})",
+                frame.block.name(),
+                name
+            );
+            self.error(frame.start, frame.end, message);
+        }
+
+        let frame = self.frames.pop().unwrap();
+        self.current().push(frame.into_node());
+    }
+}
+
+/// Parse template into nodes
+///
+/// # Arguments
+///
+/// `name: &str` - Name of the template, used in diagnostics
+///
+/// `template: &str` - Template string
+///
+/// # Returns
+///
+/// `Result<Vec<Node>, ParseError>` - Nodes, or all diagnostics found in the template
+pub(crate) fn parse(name: &str, template: &str) -> Result<Vec<Node>, ParseError> {
+    Parser::new(name, template).parse()
+}
+
+/// Find `}}` which closes a tag, skipping string literals of expressions (e.g. `{{= "}}" }}`)
+///
+/// If a quote is not closed in the line (e.g. `{{ don't }}`), it is not a string literal.
+fn find_tag_end(content: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in content.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) if c == '\n' => break,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if content[i..].starts_with("}}") => return Some(i),
+            None => {}
+        }
+    }
+
+    if quote.is_some() {
+        content.find("}}")
+    } else {
+        None
+    }
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+
+    match nodes.last_mut() {
+        Some(Node::Text(last)) => last.push_str(text),
+        _ => nodes.push(Node::Text(text.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(path: &str, text: &str, line: usize, column: usize) -> Node {
+        Node::Variable {
+            path: Path::parse(path).unwrap(),
+            filters: Vec::new(),
+            source: Source::new(text, line, column),
+        }
+    }
+
+    #[test]
+    fn test_parse_variable() {
+        let nodes = parse("test", "Hello, {{ name }}!").unwrap();
+
+        assert_eq!(
+            vec![
+                Node::Text("Hello, ".to_string()),
+                variable("name", "{{ name }}", 1, 8),
+                Node::Text("!".to_string()),
+            ],
+            nodes
+        );
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let path = Path::parse("response.items[2][1].name").unwrap();
+        assert_eq!("response.items[2][1].name", path.raw());
+        assert_eq!(
+            &[
+                Segment::Key("response".to_string()),
+                Segment::Key("items".to_string()),
+                Segment::Index(2),
+                Segment::Index(1),
+                Segment::Key("name".to_string()),
+            ],
+            path.segments()
+        );
+
+        assert_eq!(&[Segment::This], Path::parse("this").unwrap().segments());
+        assert_eq!(&[Segment::This], Path::parse(".").unwrap().segments());
+
+        assert!(Path::parse("items.").is_none());
+        assert!(Path::parse("items[a]").is_none());
+        assert!(Path::parse("items[1]name").is_none());
+        assert!(Path::parse("not a path").is_none());
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        let nodes = parse(
+            "test",
+            r#"{{ name | upper | replace("|", "(") | truncate( 20 ) }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![Node::Variable {
+                path: Path::parse("name").unwrap(),
+                filters: vec![
+                    Filter::parse("upper").unwrap(),
+                    Filter::parse(r#"replace("|", "(")"#).unwrap(),
+                    Filter::parse("truncate(20)").unwrap(),
+                ],
+                source: Source::new(
+                    r#"{{ name | upper | replace("|", "(") | truncate( 20 ) }}"#,
+                    1,
+                    1
+                ),
+            }],
+            nodes
+        );
+
+        let filter = Filter::parse(r#" replace("|", "(") "#).unwrap();
+        assert_eq!("replace", filter.name());
+        assert_eq!(Some(r#""|", "(""#), filter.arguments());
+        assert_eq!(None, Filter::parse("upper()").unwrap().arguments());
+
+        assert!(Filter::parse("truncate(20").is_none());
+        assert!(Filter::parse("not a filter").is_none());
+        assert!(parse("test", "{{ name | }}").is_err());
+        assert!(parse("test", "{{ name | truncate(20, ) }}").is_err());
+    }
+
+    #[test]
+    fn test_parse_section() {
+        let nodes = parse("test", "{{#items}}[{{.}}]{{/items}}").unwrap();
+
+        assert_eq!(
+            vec![Node::Section {
+                path: Path::parse("items").unwrap(),
+                inverted: false,
+                children: vec![
+                    Node::Text("[".to_string()),
+                    variable(".", "{{.}}", 1, 12),
+                    Node::Text("]".to_string()),
+                ],
+            }],
+            nodes
+        );
+    }
+
+    #[test]
+    fn test_parse_inverted_section() {
+        let nodes = parse("test", "{{^items}}empty{{/items}}").unwrap();
+
+        assert_eq!(
+            vec![Node::Section {
+                path: Path::parse("items").unwrap(),
+                inverted: true,
+                children: vec![Node::Text("empty".to_string())],
+            }],
+            nodes
+        );
+    }
+
+    #[test]
+    fn test_parse_if() {
+        let nodes = parse("test", "{{#if count > 0}}some{{else}}none{{/if}}").unwrap();
+
+        assert_eq!(
+            vec![Node::If {
+                condition: "count > 0".to_string(),
+                then_branch: vec![Node::Text("some".to_string())],
+                else_branch: vec![Node::Text("none".to_string())],
+            }],
+            nodes
+        );
+
+        assert!(parse("test", "{{#if a}}{{else}}{{else}}{{/if}}").is_err());
+        assert!(parse("test", "{{#if a}}").is_err());
+
+        let e = parse("test", "{{#if}}{{/if}}").unwrap_err();
+        assert_eq!("empty condition", e.diagnostics()[0].message());
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let nodes = parse("test", r#"{{= datetime_format(now, "%Y") }}"#).unwrap();
+
+        assert_eq!(
+            vec![Node::Expression(
+                r#"datetime_format(now, "%Y")"#.to_string()
+            )],
+            nodes
+        );
+
+        let nodes = parse("test", r#"{{= "}}" .. '\'}}' }}{{ name | default("}}") }}"#).unwrap();
+        assert_eq!(Node::Expression(r#""}}" .. '\'}}'"#.to_string()), nodes[0]);
+        assert_eq!(2, nodes.len());
+
+        assert!(parse("test", "{{=}}").is_err());
+        assert!(parse("test", "{{= 1 + }}").is_err());
+    }
+
+    #[test]
+    fn test_parse_not_a_tag() {
+        let nodes = parse("test", "{{{name}}}").unwrap();
+
+        assert_eq!(
+            vec![
+                Node::Text("{".to_string()),
+                variable("name", "{{name}}", 1, 2),
+                Node::Text("}".to_string()),
+            ],
+            nodes
+        );
+
+        let nodes = parse("test", r"\{{name}} \{{{ x }}} {{name}}").unwrap();
+        assert_eq!(
+            vec![
+                Node::Text("{{name}} {{{ x }}} ".to_string()),
+                variable("name", "{{name}}", 1, 22),
+            ],
+            nodes
+        );
+
+        let nodes = parse("test", r"C:\{{dir}} C:\\{{dir}}").unwrap();
+        assert_eq!(
+            vec![
+                Node::Text(r"C:{{dir}} C:\".to_string()),
+                variable("dir", "{{dir}}", 1, 16),
+            ],
+            nodes
+        );
+
+        let e = parse("test", "Hello,\n  {{ not a tag }}").unwrap_err();
+        let diagnostic = &e.diagnostics()[0];
+        assert_eq!(2, diagnostic.line());
+        assert_eq!(3, diagnostic.column());
+        assert_eq!("invalid tag: 'not a tag'", diagnostic.message());
+    }
+
+    #[test]
+    fn test_parse_unbalanced() {
+        assert!(parse("test", "{{#items}}").is_err());
+        assert!(parse("test", "{{/items}}").is_err());
+        assert!(parse("test", "{{#items}}{{/name}}").is_err());
+        assert!(parse("test", "{{#a.b}}{{/a}}").is_err());
+        assert!(parse("test", "{{else}}").is_err());
+        assert!(parse("test", "{{ name ").is_err());
+    }
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let template = "a\n{{ x y }}\n{{#items}}\n{{= 1 + }}";
+        let e = parse("hoge.txt", template).unwrap_err();
+
+        let locations = e
+            .diagnostics()
+            .iter()
+            .map(|d| (d.template_name(), d.line(), d.column()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![("hoge.txt", 2, 1), ("hoge.txt", 3, 1), ("hoge.txt", 4, 1)],
+            locations
+        );
+        assert_eq!("unclosed section: items", e.diagnostics()[1].message());
+        assert!(e.to_string().contains(" --> hoge.txt:3:1"));
+    }
+}
@@ -0,0 +1,455 @@
+use mlua::{FromLua, FromLuaMulti, Function, IntoLua, Lua, MultiValue, Table};
+
+use crate::MllError;
+use crate::error::find_limit;
+use crate::limits::call_limited;
+use crate::report::{TagReport, ValueSource};
+
+use super::filters::{FILTERS_REGISTRY_KEY, get_filter};
+use super::format::ValueFormat;
+use super::missing::MissingVariablePolicy;
+use super::parser::{Filter, Node, Path, Segment, Source};
+use super::value::{MllValue, RenderContext};
+
+/// Name of the registry value of the compiled expressions
+const EXPRESSIONS_REGISTRY_KEY: &str = "mll_expressions";
+
+/// Error raised by a tag while rendering
+pub(crate) struct TagError {
+    pub tag: String,
+    pub error: mlua::Error,
+}
+
+/// Result of rendering nodes
+pub(crate) struct RenderResult {
+    pub output: String,
+    pub tags: Vec<TagReport>,
+    pub errors: Vec<TagError>,
+}
+
+impl RenderResult {
+    /// Get names of the variables which are not rendered, without duplicates
+    pub fn missing_variables(&self) -> Vec<String> {
+        let mut variables: Vec<String> = Vec::new();
+
+        for tag in &self.tags {
+            if tag.source() == ValueSource::Missing && !variables.iter().any(|v| v == tag.name()) {
+                variables.push(tag.name().to_string());
+            }
+        }
+
+        variables
+    }
+
+    /// Get the output, or an error with the partial output
+    ///
+    /// An exceeded limit takes precedence over errors raised by tags, and the first error raised
+    /// by a tag takes precedence over missing variables.
+    pub fn into_result(mut self) -> Result<String, MllError> {
+        let limit = self
+            .errors
+            .iter()
+            .find_map(|e| find_limit(&e.error).map(|limit| (limit, e.tag.clone())));
+        if let Some((limit, tag)) = limit {
+            return Err(MllError::LimitExceeded {
+                limit,
+                tag: Some(tag),
+                output: Some(self.output),
+            });
+        }
+
+        if !self.errors.is_empty() {
+            let TagError { tag, error } = self.errors.remove(0);
+            return Err(MllError::LuaRuntime {
+                tag: Some(tag),
+                output: Some(self.output),
+                source: error,
+                location: None,
+            });
+        }
+
+        let variables = self.missing_variables();
+        if variables.is_empty() {
+            Ok(self.output)
+        } else {
+            Err(MllError::MissingVariables {
+                output: self.output,
+                variables,
+            })
+        }
+    }
+}
+
+/// Render parsed nodes with a stack of lookup scopes
+///
+/// Names are resolved from the innermost scope (e.g. the current item of a section) to the
+/// outermost scope (the context passed to `render`).
+/// Expressions of `{{= expr }}` and conditions of `{{#if expr}}` are evaluated in `lua`, where
+/// names are resolved from the scopes first and then from the Lua globals.
+pub(crate) struct Renderer<'a> {
+    lua: &'a Lua,
+    missing_variable_policy: &'a MissingVariablePolicy,
+    /// Where the values of the outermost scope came from
+    context_source: ValueSource,
+    value_format: ValueFormat,
+    tags: Vec<TagReport>,
+    errors: Vec<TagError>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(lua: &'a Lua, missing_variable_policy: &'a MissingVariablePolicy) -> Self {
+        Self {
+            lua,
+            missing_variable_policy,
+            context_source: ValueSource::Context,
+            value_format: ValueFormat::default(),
+            tags: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Set where the values of the context came from (e.g. the Lua globals), for the report
+    pub fn context_source(mut self, source: ValueSource) -> Self {
+        self.context_source = source;
+        self
+    }
+
+    /// Set how to render values which are not strings
+    pub fn value_format(mut self, format: ValueFormat) -> Self {
+        self.value_format = format;
+        self
+    }
+
+    pub async fn render(mut self, nodes: &[Node], context: &dyn RenderContext) -> RenderResult {
+        let mut output = String::new();
+        self.render_nodes(nodes, &[context], &mut output).await;
+
+        RenderResult {
+            output,
+            tags: self.tags,
+            errors: self.errors,
+        }
+    }
+
+    async fn render_nodes(
+        &mut self,
+        nodes: &[Node],
+        scopes: &[&dyn RenderContext],
+        output: &mut String,
+    ) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Variable {
+                    path,
+                    filters,
+                    source,
+                } => {
+                    let (value, value_source) = match lookup_scope(scopes, path) {
+                        Some((value, 0)) => (Some(value), self.context_source),
+                        Some((value, _)) => (Some(value), ValueSource::Section),
+                        None => (None, ValueSource::Filter),
+                    };
+
+                    // filters receive `nil` for a missing value (e.g. for `default`)
+                    let value = if filters.is_empty() {
+                        value
+                    } else {
+                        let value = value.unwrap_or(MllValue::Nil);
+                        match self.apply_filters(value, filters, scopes).await {
+                            Ok(value) => Some(value),
+                            Err(e) => {
+                                self.error(source.text().to_string(), e);
+                                continue;
+                            }
+                        }
+                    };
+
+                    let value = value.map(|value| self.value_format.format(self.lua, &value));
+                    let value = match value.transpose() {
+                        Ok(value) => value.flatten(),
+                        Err(e) => {
+                            self.error(source.text().to_string(), e);
+                            continue;
+                        }
+                    };
+
+                    let (value, value_source) = match value {
+                        Some(value) => (Some(value), value_source),
+                        None => match self.substitute(path, source).await {
+                            Ok(Some(value)) => (Some(value), ValueSource::Default),
+                            Ok(None) => (None, ValueSource::Missing),
+                            Err(e) => {
+                                self.error(source.text().to_string(), e);
+                                (None, ValueSource::Missing)
+                            }
+                        },
+                    };
+
+                    if let Some(value) = &value {
+                        output.push_str(value);
+                    }
+                    self.tags.push(TagReport::new(
+                        path.raw(),
+                        source.text(),
+                        (source.line(), source.column()),
+                        value_source,
+                        value,
+                    ));
+                }
+                Node::Section {
+                    path,
+                    inverted: false,
+                    children,
+                } => {
+                    let value = lookup(scopes, path).unwrap_or(MllValue::Nil);
+
+                    if let Some(items) = value.as_list() {
+                        // repeat once per item of the list
+                        for item in &items {
+                            let mut inner = scopes.to_vec();
+                            inner.push(item);
+                            Box::pin(self.render_nodes(children, &inner, output)).await;
+                        }
+                    } else if value.is_truthy() {
+                        let mut inner = scopes.to_vec();
+                        inner.push(&value);
+                        Box::pin(self.render_nodes(children, &inner, output)).await;
+                    }
+                }
+                Node::Section {
+                    path,
+                    inverted: true,
+                    children,
+                } => {
+                    let value = lookup(scopes, path).unwrap_or(MllValue::Nil);
+
+                    let empty = match value.as_list() {
+                        Some(items) => items.is_empty(),
+                        None => !value.is_truthy(),
+                    };
+                    if empty {
+                        Box::pin(self.render_nodes(children, scopes, output)).await;
+                    }
+                }
+                Node::Expression(expression) => {
+                    let result = self
+                        .evaluate::<mlua::Value>(expression, scopes)
+                        .await
+                        .and_then(|value| self.format_expression(value));
+
+                    match result {
+                        Ok(value) => output.push_str(&value),
+                        Err(e) => self.error(format!("{{{{= {} }}}}", expression), e),
+                    }
+                }
+                Node::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                } => match self.evaluate::<mlua::Value>(condition, scopes).await {
+                    Ok(value) => {
+                        let truthy =
+                            !matches!(value, mlua::Value::Nil | mlua::Value::Boolean(false));
+                        let branch = if truthy { then_branch } else { else_branch };
+                        Box::pin(self.render_nodes(branch, scopes, output)).await;
+                    }
+                    Err(e) => self.error(format!("{{{{#if {}}}}}", condition), e),
+                },
+            }
+        }
+    }
+
+    /// Get string of the value of an expression, `nil` is rendered as an empty string
+    fn format_expression(&self, value: mlua::Value) -> mlua::Result<String> {
+        match value {
+            // not data, rendered in the same way as Lua's `tostring`
+            mlua::Value::Function(_) | mlua::Value::Thread(_) | mlua::Value::UserData(_) => {
+                value.to_string()
+            }
+            value => {
+                let value = MllValue::from_lua(value, self.lua)?;
+                Ok(self
+                    .value_format
+                    .format(self.lua, &value)?
+                    .unwrap_or_default())
+            }
+        }
+    }
+
+    fn error(&mut self, tag: String, error: mlua::Error) {
+        self.errors.push(TagError { tag, error });
+    }
+
+    /// Get text rendered instead of a missing variable by the policy
+    async fn substitute(&self, path: &Path, source: &Source) -> mlua::Result<Option<String>> {
+        Ok(match self.missing_variable_policy {
+            MissingVariablePolicy::Strict => None,
+            MissingVariablePolicy::Empty => Some(String::new()),
+            MissingVariablePolicy::Keep => Some(source.text().to_string()),
+            MissingVariablePolicy::Default(value) => Some(value.clone()),
+            MissingVariablePolicy::Callback(callback) => callback(path.raw()),
+            MissingVariablePolicy::Lua(name) => {
+                let function = self.lua.globals().get::<Function>(name.as_str())?;
+                call_limited::<Option<String>>(self.lua, function, path.raw()).await?
+            }
+        })
+    }
+
+    /// Apply filters from left to right
+    async fn apply_filters(
+        &self,
+        value: MllValue,
+        filters: &[Filter],
+        scopes: &[&dyn RenderContext],
+    ) -> mlua::Result<MllValue> {
+        let mut value = value;
+
+        for filter in filters {
+            let arguments = match filter.arguments() {
+                Some(arguments) => self.evaluate::<MultiValue>(arguments, scopes).await?,
+                None => MultiValue::new(),
+            };
+
+            value = self.apply_filter(filter.name(), value, arguments).await?;
+        }
+
+        Ok(value)
+    }
+
+    /// Apply filter registered by Lua, or standard filter
+    async fn apply_filter(
+        &self,
+        name: &str,
+        value: MllValue,
+        arguments: MultiValue,
+    ) -> mlua::Result<MllValue> {
+        let lua = self.lua;
+
+        let registered = lua
+            .named_registry_value::<Option<Table>>(FILTERS_REGISTRY_KEY)?
+            .map(|filters| filters.get::<Option<Function>>(name))
+            .transpose()?
+            .flatten();
+
+        if let Some(function) = registered {
+            let mut arguments = arguments;
+            arguments.push_front(value.into_lua(lua)?);
+            return call_limited(lua, function, arguments).await;
+        }
+
+        match get_filter(name) {
+            Some(filter) => filter(lua, value, arguments),
+            None => Err(mlua::Error::RuntimeError(format!(
+                "unknown filter: {}",
+                name
+            ))),
+        }
+    }
+
+    /// Evaluate Lua expression with the scopes
+    ///
+    /// Names of the expression found in the scopes are set into the environment of the
+    /// expression, and the other names are resolved from the globals.
+    async fn evaluate<R>(&self, expression: &str, scopes: &[&dyn RenderContext]) -> mlua::Result<R>
+    where
+        R: FromLuaMulti,
+    {
+        let lua = self.lua;
+
+        let environment = lua.create_table()?;
+        for name in identifiers(expression) {
+            if let Some(value) = lookup_name(scopes, name) {
+                environment.raw_set(name, value)?;
+            }
+        }
+
+        let metatable = lua.create_table()?;
+        metatable.set("__index", lua.globals())?;
+        environment.set_metatable(Some(metatable));
+
+        call_limited(lua, compile(lua, expression)?, environment).await
+    }
+}
+
+/// Get the function of Lua expression, which takes its environment as the argument
+///
+/// Expressions are compiled once per Lua state, and cached in the registry by their source.
+fn compile(lua: &Lua, expression: &str) -> mlua::Result<Function> {
+    let cache = match lua.named_registry_value::<Option<Table>>(EXPRESSIONS_REGISTRY_KEY)? {
+        Some(cache) => cache,
+        None => {
+            let cache = lua.create_table()?;
+            lua.set_named_registry_value(EXPRESSIONS_REGISTRY_KEY, &cache)?;
+            cache
+        }
+    };
+
+    if let Some(function) = cache.raw_get::<Option<Function>>(expression)? {
+        return Ok(function);
+    }
+
+    // on a single line, so that the line numbers of errors are not changed
+    let function = lua
+        .load(format!("local _ENV = ...; return {}", expression))
+        .into_function()?;
+    cache.raw_set(expression, &function)?;
+
+    Ok(function)
+}
+
+/// Get identifiers of Lua expression, which may be names of the scopes (keys of fields and words
+/// in strings are also included)
+fn identifiers(expression: &str) -> impl Iterator<Item = &str> {
+    expression
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+/// Resolve path, the first segment from the scopes and the rest from the resolved value
+fn lookup(scopes: &[&dyn RenderContext], path: &Path) -> Option<MllValue> {
+    lookup_scope(scopes, path).map(|(value, _)| value)
+}
+
+/// Resolve path, with the index of the scope where the first segment is found
+fn lookup_scope(scopes: &[&dyn RenderContext], path: &Path) -> Option<(MllValue, usize)> {
+    let (first, rest) = path.segments().split_first()?;
+
+    let (mut value, scope) = match first {
+        Segment::This => lookup_name_scope(scopes, "this")?,
+        Segment::Key(name) => lookup_name_scope(scopes, name)?,
+        Segment::Index(index) => {
+            let (value, scope) = lookup_name_scope(scopes, "this")?;
+            (value.get_index(*index)?, scope)
+        }
+    };
+
+    for segment in rest {
+        value = match segment {
+            Segment::This => value,
+            Segment::Key(key) => value.get_key(key)?,
+            Segment::Index(index) => value.get_index(*index)?,
+        };
+    }
+
+    Some((value, scope))
+}
+
+/// Resolve name from the innermost scope, `this` is the innermost scope itself
+fn lookup_name(scopes: &[&dyn RenderContext], name: &str) -> Option<MllValue> {
+    lookup_name_scope(scopes, name).map(|(value, _)| value)
+}
+
+/// Resolve name from the innermost scope, with the index of the scope where it is found
+fn lookup_name_scope(scopes: &[&dyn RenderContext], name: &str) -> Option<(MllValue, usize)> {
+    if name == "this" {
+        let scope = scopes.len().checked_sub(1)?;
+        return scopes[scope].get_this().map(|value| (value, scope));
+    }
+
+    scopes
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, scope)| scope.get_value(name).map(|value| (value, i)))
+}
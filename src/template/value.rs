@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use mlua::{FromLua, IntoLua, Lua, Table};
+use serde_json::Value as JsonValue;
+
+use super::format::ValueFormat;
+use crate::GetValueByName;
+use crate::utils::is_array;
+
+/// Value resolved from a render context
+///
+/// # Examples
+///
+/// ```
+/// use libmll::MllValue;
+///
+/// let value: MllValue = "hoge".into();
+/// assert!(value.is_truthy());
+/// assert!(!MllValue::Nil.is_truthy());
+/// ```
+#[derive(Clone, Debug)]
+pub enum MllValue {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Table(Table),
+    List(Vec<MllValue>),
+    Map(HashMap<String, MllValue>),
+}
+
+impl MllValue {
+    /// Truthiness of the value, same as Lua (only `nil` and `false` are falsy)
+    ///
+    /// # Returns
+    ///
+    /// `bool` - `true` if the value is truthy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, MllValue::Nil | MllValue::Boolean(false))
+    }
+
+    /// Get name of the type for messages
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            MllValue::Nil => "nil",
+            MllValue::Boolean(_) => "boolean",
+            MllValue::Integer(_) => "integer",
+            MllValue::Number(_) => "number",
+            MllValue::String(_) => "string",
+            MllValue::Table(_) => "table",
+            MllValue::List(_) => "list",
+            MllValue::Map(_) => "map",
+        }
+    }
+
+    /// Get items if the value is a list (Lua sequence table)
+    ///
+    /// # Returns
+    ///
+    /// `Option<Vec<MllValue>>` - Items of the list, `None` if the value is not a list
+    pub(crate) fn as_list(&self) -> Option<Vec<MllValue>> {
+        match self {
+            MllValue::Table(table) => match is_array(table) {
+                Ok(true) => table
+                    .sequence_values::<MllValue>()
+                    .collect::<mlua::Result<Vec<_>>>()
+                    .ok(),
+                _ => None,
+            },
+            MllValue::List(list) => Some(list.clone()),
+            _ => None,
+        }
+    }
+
+    /// Get value of a key if the value is a table or a map
+    ///
+    /// # Arguments
+    ///
+    /// `key: &str` - Key
+    ///
+    /// # Returns
+    ///
+    /// `Option<MllValue>` - Value of the key, `None` if it is missing or `nil`
+    pub(crate) fn get_key(&self, key: &str) -> Option<MllValue> {
+        let value = match self {
+            MllValue::Table(table) => table.get::<MllValue>(key).ok(),
+            MllValue::Map(map) => map.get(key).cloned(),
+            _ => None,
+        };
+
+        value.filter(|v| !matches!(v, MllValue::Nil))
+    }
+
+    /// Get item at a 1-based index (same as Lua) if the value is a table or a list
+    ///
+    /// # Arguments
+    ///
+    /// `index: usize` - 1-based index
+    ///
+    /// # Returns
+    ///
+    /// `Option<MllValue>` - Item at the index, `None` if it is missing or `nil`
+    pub(crate) fn get_index(&self, index: usize) -> Option<MllValue> {
+        let value = match self {
+            MllValue::Table(table) => table.get::<MllValue>(index).ok(),
+            MllValue::List(list) => index.checked_sub(1).and_then(|i| list.get(i)).cloned(),
+            _ => None,
+        };
+
+        value.filter(|v| !matches!(v, MllValue::Nil))
+    }
+
+    /// Get string of the value in the default format (see `ValueFormat`), except for tables
+    ///
+    /// # Returns
+    ///
+    /// `Option<String>` - Rendered string, `None` for `nil` and tables
+    pub(crate) fn to_output(&self) -> Option<String> {
+        ValueFormat::default().format_scalar(self)
+    }
+}
+
+impl FromLua for MllValue {
+    fn from_lua(value: mlua::Value, _: &Lua) -> mlua::Result<Self> {
+        Ok(match value {
+            mlua::Value::Boolean(b) => MllValue::Boolean(b),
+            mlua::Value::Integer(i) => MllValue::Integer(i),
+            mlua::Value::Number(n) => MllValue::Number(n),
+            mlua::Value::String(s) => MllValue::String(s.to_string_lossy()),
+            mlua::Value::Table(t) => MllValue::Table(t),
+            _ => MllValue::Nil,
+        })
+    }
+}
+
+impl IntoLua for MllValue {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+        Ok(match self {
+            MllValue::Nil => mlua::Value::Nil,
+            MllValue::Boolean(b) => mlua::Value::Boolean(b),
+            MllValue::Integer(i) => mlua::Value::Integer(i),
+            MllValue::Number(n) => mlua::Value::Number(n),
+            MllValue::String(s) => mlua::Value::String(lua.create_string(&s)?),
+            MllValue::Table(t) => mlua::Value::Table(t),
+            MllValue::List(list) => mlua::Value::Table(lua.create_sequence_from(list)?),
+            MllValue::Map(map) => mlua::Value::Table(lua.create_table_from(map)?),
+        })
+    }
+}
+
+impl From<String> for MllValue {
+    fn from(value: String) -> Self {
+        MllValue::String(value)
+    }
+}
+
+impl From<&str> for MllValue {
+    fn from(value: &str) -> Self {
+        MllValue::String(value.to_string())
+    }
+}
+
+impl From<i64> for MllValue {
+    fn from(value: i64) -> Self {
+        MllValue::Integer(value)
+    }
+}
+
+impl From<f64> for MllValue {
+    fn from(value: f64) -> Self {
+        MllValue::Number(value)
+    }
+}
+
+impl From<bool> for MllValue {
+    fn from(value: bool) -> Self {
+        MllValue::Boolean(value)
+    }
+}
+
+impl From<Table> for MllValue {
+    fn from(value: Table) -> Self {
+        MllValue::Table(value)
+    }
+}
+
+impl<T> From<Vec<T>> for MllValue
+where
+    T: Into<MllValue>,
+{
+    fn from(value: Vec<T>) -> Self {
+        MllValue::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K, T> From<HashMap<K, T>> for MllValue
+where
+    K: Into<String>,
+    T: Into<MllValue>,
+{
+    fn from(value: HashMap<K, T>) -> Self {
+        MllValue::Map(
+            value
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+impl From<JsonValue> for MllValue {
+    fn from(value: JsonValue) -> Self {
+        match value {
+            JsonValue::Null => MllValue::Nil,
+            JsonValue::Bool(b) => MllValue::Boolean(b),
+            JsonValue::Number(n) => match n.as_i64() {
+                Some(i) => MllValue::Integer(i),
+                None => MllValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            JsonValue::String(s) => MllValue::String(s),
+            JsonValue::Array(arr) => MllValue::List(arr.into_iter().map(Into::into).collect()),
+            JsonValue::Object(obj) => {
+                MllValue::Map(obj.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+/// Trait for contexts that a template can be rendered with
+///
+/// # Methods
+///
+/// `get_value(&self, name: &str) -> Option<MllValue>` - Get value by name
+///
+/// `get_this(&self) -> Option<MllValue>` - Get the context itself as a value (`{{this}}`)
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use libmll::RenderContext;
+///
+/// let mut table = HashMap::new();
+/// table.insert("name", "hogehoge".to_string());
+///
+/// assert!(table.get_value("name").is_some());
+/// assert!(table.get_value("age").is_none());
+/// ```
+pub trait RenderContext {
+    fn get_value(&self, name: &str) -> Option<MllValue>;
+
+    fn get_this(&self) -> Option<MllValue> {
+        None
+    }
+}
+
+impl<T> RenderContext for HashMap<&str, T>
+where
+    T: Clone + Into<MllValue>,
+{
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        self.get_by_name(name).map(Into::into)
+    }
+}
+
+impl<T> RenderContext for HashMap<String, T>
+where
+    T: Clone + Into<MllValue>,
+{
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        self.get(name).cloned().map(Into::into)
+    }
+}
+
+impl RenderContext for Table {
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        match GetValueByName::<MllValue>::get_by_name(self, name) {
+            Some(MllValue::Nil) | None => None,
+            Some(value) => Some(value),
+        }
+    }
+
+    fn get_this(&self) -> Option<MllValue> {
+        Some(MllValue::Table(self.clone()))
+    }
+}
+
+impl RenderContext for JsonValue {
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        match self.get(name) {
+            Some(JsonValue::Null) | None => None,
+            Some(value) => Some(value.clone().into()),
+        }
+    }
+
+    fn get_this(&self) -> Option<MllValue> {
+        Some(self.clone().into())
+    }
+}
+
+impl RenderContext for MllValue {
+    fn get_value(&self, name: &str) -> Option<MllValue> {
+        self.get_key(name)
+    }
+
+    fn get_this(&self) -> Option<MllValue> {
+        Some(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_as_list() {
+        let lua = Lua::new();
+        lua.load("list = {1, 2, 3}; object = {name = 'hoge'}; empty = {}")
+            .exec()
+            .unwrap();
+
+        let globals = lua.globals();
+
+        let list = globals.get_value("list").unwrap().as_list().unwrap();
+        assert_eq!(3, list.len());
+
+        assert!(globals.get_value("object").unwrap().as_list().is_none());
+        assert_eq!(
+            0,
+            globals.get_value("empty").unwrap().as_list().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_get_key_and_index() {
+        let lua = Lua::new();
+        lua.load("items = {{name = 'hoge'}, {name = 'fuga'}}")
+            .exec()
+            .unwrap();
+
+        let items = lua.globals().get_value("items").unwrap();
+        let item = items.get_index(2).unwrap();
+        assert_eq!(
+            Some("fuga".to_string()),
+            item.get_key("name").unwrap().to_output()
+        );
+        assert!(items.get_index(3).is_none());
+        assert!(item.get_key("age").is_none());
+
+        let json: JsonValue = serde_json::json!({"items": [{"name": "hoge"}, null]});
+        let items = json.get_value("items").unwrap();
+        let item = items.get_index(1).unwrap();
+        assert_eq!(
+            Some("hoge".to_string()),
+            item.get_key("name").unwrap().to_output()
+        );
+        assert!(items.get_index(0).is_none());
+        assert!(items.get_index(2).is_none());
+    }
+
+    #[test]
+    fn test_to_output() {
+        assert_eq!(Some("20".to_string()), MllValue::Integer(20).to_output());
+        assert_eq!(Some("1.5".to_string()), MllValue::Number(1.5).to_output());
+        assert_eq!(Some("2".to_string()), MllValue::Number(2.0).to_output());
+        assert_eq!(
+            Some("true".to_string()),
+            MllValue::Boolean(true).to_output()
+        );
+        assert_eq!(None, MllValue::Nil.to_output());
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use mlua::{FromLuaMulti, Function, IntoLuaMulti, Lua};
+
+use crate::MllError;
+use crate::limits::call_limited;
+
+/// Name of the registry value of the function which calls a chunk with the message handler
+const TRACED: &str = "mll_traced";
+
+/// Maximum number of frames kept in a traceback
+const MAX_FRAMES: usize = 32;
+
+/// Calls a function by `xpcall` with the message handler, and raises the error again as it is
+const TRACED_SOURCE: &str = r#"
+local xpcall, error, handler = ...
+
+local function rethrow(ok, ...)
+    if ok then
+        return ...
+    end
+    error((...), 0)
+end
+
+return function(f, ...)
+    return rethrow(xpcall(f, handler, ...))
+end
+"#;
+
+/// Frame of the Lua call stack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    chunk: String,
+    line: usize,
+    function: Option<String>,
+}
+
+impl StackFrame {
+    /// Get name of the chunk (e.g. `pre_process` or the path of the script)
+    pub fn chunk(&self) -> &str {
+        &self.chunk
+    }
+
+    /// Get 1-based line number in the chunk
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Get name of the function (e.g. `f`, or `<pre_process:3>` for an anonymous function
+    /// defined at line 3), `None` for the main chunk
+    pub fn function(&self) -> Option<&str> {
+        self.function.as_deref()
+    }
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(function) if function.starts_with('<') => {
+                write!(f, "{}:{}: in function {}", self.chunk, self.line, function)
+            }
+            Some(function) => write!(
+                f,
+                "{}:{}: in function '{}'",
+                self.chunk, self.line, function
+            ),
+            None => write!(f, "{}:{}: in main chunk", self.chunk, self.line),
+        }
+    }
+}
+
+/// Position in a Lua script where an error is raised, with the traceback
+///
+/// Errors raised inside builtins are located at the line of the script which called the
+/// builtin.
+///
+/// # Examples
+///
+/// ```
+/// use libmll::Mll;
+///
+/// let mut mll = Mll::new();
+/// mll.set_pre_process_script("x = 1\nlocal function f() error('hoge') end\nf()".to_string());
+///
+/// let e = mll.render_lua_globals().unwrap_err();
+/// let location = e.location().unwrap();
+/// assert_eq!(("pre_process", 2), (location.chunk(), location.line()));
+/// assert_eq!(Some("local function f() error('hoge') end"), location.source_line());
+/// assert_eq!(2, location.traceback().len());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptLocation {
+    chunk: String,
+    line: usize,
+    source_line: Option<String>,
+    traceback: Vec<StackFrame>,
+}
+
+impl ScriptLocation {
+    /// Get name of the chunk (e.g. `pre_process` or the path of the script)
+    pub fn chunk(&self) -> &str {
+        &self.chunk
+    }
+
+    /// Get 1-based line number in the chunk
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Get the line of the script (without the surrounding whitespace), `None` if the source
+    /// of the chunk is unknown (e.g. an expression of the template)
+    pub fn source_line(&self) -> Option<&str> {
+        self.source_line.as_deref()
+    }
+
+    /// Get frames of the Lua call stack, innermost first, empty for syntax errors
+    pub fn traceback(&self) -> &[StackFrame] {
+        &self.traceback
+    }
+}
+
+impl fmt::Display for ScriptLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.chunk, self.line)?;
+        if let Some(source_line) = &self.source_line {
+            write!(f, "\n    {}", source_line)?;
+        }
+        if !self.traceback.is_empty() {
+            write!(f, "\nstack traceback:")?;
+            for frame in &self.traceback {
+                write!(f, "\n    {}", frame)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sources of the named chunks of a Lua state
+#[derive(Default)]
+struct Sources(HashMap<String, String>);
+
+/// Frames of the last error caught by the message handler
+struct Traceback(Vec<StackFrame>);
+
+/// Load Lua script as a named chunk, whose errors are located by `call_traced`
+///
+/// # Arguments
+///
+/// * `lua` - The Lua context
+/// * `name` - The name of the chunk (e.g. `pre_process` or the path of the script)
+/// * `script` - The Lua script
+pub(crate) fn load_named(lua: &Lua, name: &str, script: &str) -> Result<Function, MllError> {
+    if lua.app_data_ref::<Sources>().is_none() {
+        lua.set_app_data(Sources::default());
+    }
+    if let Some(mut sources) = lua.app_data_mut::<Sources>() {
+        sources.0.insert(name.to_string(), script.to_string());
+    }
+
+    lua.load(script)
+        .set_name(format!("={}", name))
+        .into_function()
+        .map_err(|e| {
+            let location = locate(lua, &e);
+            MllError::from(e).with_location(location)
+        })
+}
+
+/// Call a function with the limits (see `call_limited`), and locate its error in the scripts
+///
+/// # Arguments
+///
+/// * `lua` - The Lua context
+/// * `function` - The function, usually a chunk loaded by `load_named`
+/// * `args` - The arguments of the function
+pub(crate) async fn call_traced<R>(
+    lua: &Lua,
+    function: Function,
+    args: impl IntoLuaMulti,
+) -> Result<R, MllError>
+where
+    R: FromLuaMulti,
+{
+    let mut args = args.into_lua_multi(lua)?;
+    args.push_front(mlua::Value::Function(function));
+
+    lua.remove_app_data::<Traceback>();
+    let result = call_limited(lua, traced(lua)?, args).await;
+
+    result.map_err(|e| {
+        let location = locate(lua, &e);
+        MllError::from(e).with_location(location)
+    })
+}
+
+/// Get the function which calls a chunk with the message handler
+fn traced(lua: &Lua) -> mlua::Result<Function> {
+    if let Some(traced) = lua.named_registry_value::<Option<Function>>(TRACED)? {
+        return Ok(traced);
+    }
+
+    let globals = lua.globals();
+    let handler = lua.create_function(|lua, error: mlua::Value| {
+        lua.set_app_data(Traceback(stack_frames(lua)));
+        Ok(error)
+    })?;
+    let traced = lua.load(TRACED_SOURCE).set_name(TRACED).call::<Function>((
+        globals.get::<Function>("xpcall")?,
+        globals.get::<Function>("error")?,
+        handler,
+    ))?;
+    lua.set_named_registry_value(TRACED, traced.clone())?;
+
+    Ok(traced)
+}
+
+/// Get frames of the scripts in the call stack of the message handler
+fn stack_frames(lua: &Lua) -> Vec<StackFrame> {
+    (1..)
+        .map_while(|level| lua.inspect_stack(level))
+        .filter_map(|debug| {
+            let source = debug.source();
+            if source.what == "C" {
+                return None;
+            }
+
+            // chunks of mlua and `TRACED` are not named by `=` (or `@` for files)
+            let chunk = source.source?;
+            let chunk = chunk_name(&chunk)?.to_string();
+            let line = usize::try_from(debug.curr_line()).ok()?;
+            let function = match (source.what, debug.names().name) {
+                ("main", _) => None,
+                (_, Some(name)) => Some(name.into_owned()),
+                (_, None) => Some(format!(
+                    "<{}:{}>",
+                    chunk,
+                    source.line_defined.unwrap_or_default()
+                )),
+            };
+
+            Some(StackFrame {
+                chunk,
+                line,
+                function,
+            })
+        })
+        .take(MAX_FRAMES)
+        .collect()
+}
+
+/// Get name of a chunk from its Lua source name, `None` for unnamed chunks
+fn chunk_name(source: &str) -> Option<&str> {
+    source
+        .strip_prefix('=')
+        .or_else(|| source.strip_prefix('@'))
+}
+
+/// Locate an error in the scripts
+///
+/// Runtime errors are located by the traceback caught by the message handler, and syntax
+/// errors by their message (e.g. `pre_process:3: unexpected symbol near 'x'`).
+fn locate(lua: &Lua, e: &mlua::Error) -> Option<ScriptLocation> {
+    let traceback = lua
+        .remove_app_data::<Traceback>()
+        .map(|traceback| traceback.0)
+        .unwrap_or_default();
+    let sources = lua.app_data_ref::<Sources>();
+    let sources = sources.as_ref().map(|sources| &sources.0);
+
+    let (chunk, line) = match traceback
+        .iter()
+        .find(|frame| sources.is_some_and(|sources| sources.contains_key(&frame.chunk)))
+        .or(traceback.first())
+    {
+        Some(frame) => (frame.chunk.clone(), frame.line),
+        None => {
+            let message = match e {
+                mlua::Error::SyntaxError { message, .. } => message,
+                mlua::Error::RuntimeError(message) => message,
+                _ => return None,
+            };
+            let (chunk, line) = parse_position(message)?;
+            (chunk.to_string(), line)
+        }
+    };
+
+    let source_line = sources
+        .and_then(|sources| sources.get(&chunk))
+        .and_then(|source| source.lines().nth(line.checked_sub(1)?))
+        .map(|source_line| source_line.trim().to_string());
+
+    Some(ScriptLocation {
+        chunk,
+        line,
+        source_line,
+        traceback,
+    })
+}
+
+/// Get chunk name and line number at the head of a Lua error message (e.g. `pre_process:3:`)
+fn parse_position(message: &str) -> Option<(&str, usize)> {
+    message.match_indices(':').find_map(|(i, _)| {
+        let (line, _) = message[i + 1..].split_once(':')?;
+        let line = line.parse::<usize>().ok()?;
+
+        (i > 0).then_some((&message[..i], line))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::do_blocking;
+
+    fn run(lua: &Lua, name: &str, script: &str) -> Result<(), MllError> {
+        let function = load_named(lua, name, script)?;
+        do_blocking(call_traced(lua, function, ()))?
+    }
+
+    #[test]
+    fn test_locate() {
+        let lua = Lua::new();
+
+        let script = "local function f()\n  local x = nil\n  return x.y\nend\n\nf()";
+        let e = run(&lua, "pre_process", script).unwrap_err();
+        assert!(matches!(e, MllError::LuaRuntime { .. }));
+        let location = e.location().unwrap();
+        assert_eq!(("pre_process", 3), (location.chunk(), location.line()));
+        assert_eq!(Some("return x.y"), location.source_line());
+        assert_eq!(
+            vec![(3, Some("f")), (6, None)],
+            location
+                .traceback()
+                .iter()
+                .map(|frame| (frame.line(), frame.function()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "pre_process:3: in function 'f'",
+            location.traceback()[0].to_string()
+        );
+
+        let e = run(&lua, "scripts/hoge.lua", "x = 1\nx = = 2").unwrap_err();
+        assert!(matches!(e, MllError::LuaSyntax { .. }));
+        let location = e.location().unwrap();
+        assert_eq!(("scripts/hoge.lua", 2), (location.chunk(), location.line()));
+        assert_eq!(Some("x = = 2"), location.source_line());
+        assert!(location.traceback().is_empty());
+
+        // errors caught by the script are not located
+        assert!(run(&lua, "pre_process", "pcall(error, 'x')").is_ok());
+        let e = run(&lua, "pre_process", "\nerror({})").unwrap_err();
+        assert_eq!(2, e.location().unwrap().line());
+    }
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(
+            Some(("pre_process", 3)),
+            parse_position("pre_process:3: unexpected symbol near 'x'")
+        );
+        assert_eq!(
+            Some(("C:\\hoge.lua", 12)),
+            parse_position("C:\\hoge.lua:12: '=' expected near 'y'")
+        );
+        assert_eq!(None, parse_position("attempt to call a nil value"));
+    }
+}
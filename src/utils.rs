@@ -1,8 +1,14 @@
 use mlua::{Lua, Result, Table, Value};
 use serde_json::{Map, Value as JsonValue};
 
+use std::sync::{Arc, OnceLock};
+
 use encoding_rs;
 use encoding_rs::SHIFT_JIS;
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+use tokio::task;
+
+use crate::MllError;
 
 /// Convert a JSON string to a Lua table
 ///
@@ -76,6 +82,7 @@ pub fn json_to_lua(lua: &Lua, json: &JsonValue) -> Result<Value> {
 pub fn lua_to_json(value: Value) -> Result<JsonValue> {
     match value {
         Value::Nil => Ok(JsonValue::Null),
+        Value::LightUserData(ud) if ud.0.is_null() => Ok(JsonValue::Null),
         Value::Boolean(b) => Ok(JsonValue::Bool(b)),
         Value::Integer(i) => Ok(JsonValue::Number(i.into())),
         Value::Number(n) => Ok(JsonValue::Number(
@@ -104,7 +111,7 @@ pub fn lua_to_json(value: Value) -> Result<JsonValue> {
     }
 }
 
-fn is_array(table: &Table) -> Result<bool> {
+pub(crate) fn is_array(table: &Table) -> Result<bool> {
     let mut expected = 1;
     for pair in table.pairs::<Value, Value>() {
         let (k, _) = pair?;
@@ -129,17 +136,57 @@ fn is_array(table: &Table) -> Result<bool> {
 ///
 /// # Returns
 ///
-/// `String` - The Shift-JIS string
-pub fn lua_string_to_shift_jis(lua: &Lua, string: mlua::String) -> mlua::String {
-    let ls = string.to_str().unwrap();
+/// `Result<mlua::String>` - The Shift-JIS string, or `MllError::Encoding` if the string is
+/// not valid UTF-8
+pub fn lua_string_to_shift_jis(lua: &Lua, string: mlua::String) -> Result<mlua::String> {
+    let ls = string.to_str().map_err(|e| {
+        mlua::Error::external(MllError::Encoding {
+            encoding: SHIFT_JIS.name().to_string(),
+            message: e.to_string(),
+            source: Some(Arc::new(e)),
+            output: None,
+        })
+    })?;
     let (s, _, _) = SHIFT_JIS.encode(&ls);
-    lua.create_string(&s).unwrap()
+    lua.create_string(&s)
 }
 
-pub fn do_blocking<F>(future: F) -> F::Output
+/// Get the runtime shared by the blocking API, created on the first use
+pub(crate) fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create Tokio runtime")
+    })
+}
+
+/// Run a future to completion from blocking code
+///
+/// The future runs on the runtime of the caller if it is a multi-thread runtime (without
+/// nesting a runtime), otherwise on the shared runtime. A current-thread runtime of the caller
+/// cannot be blocked, and the future cannot be moved to another thread because the Lua state
+/// is not `Send`, so the async method has to be awaited on it instead.
+///
+/// # Arguments
+///
+/// * `future` - The future
+///
+/// # Returns
+///
+/// `Result<F::Output, MllError>` - The output of the future, or
+/// `MllError::CurrentThreadRuntime` if it is called on a current-thread runtime
+pub fn do_blocking<F>(future: F) -> std::result::Result<F::Output, MllError>
 where
     F: Future,
 {
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(future)
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Ok(task::block_in_place(|| handle.block_on(future)))
+        }
+        Ok(_) => Err(MllError::CurrentThreadRuntime),
+        Err(_) => Ok(runtime().block_on(future)),
+    }
 }